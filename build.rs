@@ -1,7 +1,7 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     tonic_build::configure()
         .protoc_arg("--experimental_allow_proto3_optional")
-        .compile(&["proto/dfs.proto"], &["proto"])?;
+        .compile(&["proto/dfs.proto", "proto/pubsub.proto"], &["proto"])?;
 
     Ok(())
 }
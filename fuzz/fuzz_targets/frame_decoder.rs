@@ -0,0 +1,50 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use libretto::pubsub::{Frame, FrameDecoder, FrameError};
+
+const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+fn decode(chunks: &[&[u8]]) -> (Vec<Frame>, Option<FrameError>, usize) {
+    let mut decoder = FrameDecoder::new().with_max_frame_size(MAX_FRAME_SIZE);
+    let mut frames = Vec::new();
+    for chunk in chunks {
+        decoder.extend(chunk);
+        loop {
+            match decoder.next_frame() {
+                Ok(Some(frame)) => frames.push(frame),
+                Ok(None) => break,
+                Err(e) if e.is_recoverable() => continue,
+                Err(e) => return (frames, Some(e), decoder.buffered()),
+            }
+        }
+    }
+    (frames, None, decoder.buffered())
+}
+
+// Arbitrary bytes fed in arbitrary pieces must never panic or hang, and must
+// decode the same way as when fed all at once.
+fuzz_target!(|input: (Vec<u8>, Vec<u16>)| {
+    let (bytes, splits) = input;
+
+    let mut chunks = Vec::new();
+    let mut rest = bytes.as_slice();
+    for split in splits {
+        if rest.is_empty() {
+            break;
+        }
+        let (chunk, tail) = rest.split_at(split as usize % (rest.len() + 1));
+        chunks.push(chunk);
+        rest = tail;
+    }
+    chunks.push(rest);
+
+    let (frames, error, buffered) = decode(&chunks);
+    let (whole_frames, whole_error, whole_buffered) = decode(&[bytes.as_slice()]);
+    assert_eq!(frames, whole_frames);
+    assert_eq!(error, whole_error);
+    if error.is_none() {
+        assert_eq!(buffered, whole_buffered);
+    }
+    assert!(frames.iter().all(|f| f.topic.len() + f.payload.len() + 16 <= MAX_FRAME_SIZE));
+});
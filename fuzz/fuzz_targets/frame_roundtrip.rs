@@ -0,0 +1,33 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use libretto::pubsub::{encode_frame, Frame, FrameDecoder};
+
+// Any sequence of frames, split at arbitrary byte boundaries, decodes back to
+// exactly the frames that were encoded.
+fuzz_target!(|input: (Vec<(String, Vec<u8>)>, Vec<u16>)| {
+    let (messages, splits) = input;
+
+    let mut wire = Vec::new();
+    for (topic, payload) in &messages {
+        wire.extend(encode_frame(topic, payload));
+    }
+
+    let mut decoder = FrameDecoder::new();
+    let mut decoded: Vec<Frame> = Vec::new();
+    let mut rest = wire.as_slice();
+    let mut splits = splits.into_iter();
+    while !rest.is_empty() {
+        let at = splits.next().map(|s| s as usize % (rest.len() + 1)).unwrap_or(rest.len());
+        let (chunk, tail) = rest.split_at(at.max(1));
+        decoder.extend(chunk);
+        decoded.extend(decoder.frames().expect("encoded frames are valid"));
+        rest = tail;
+    }
+
+    let expected: Vec<Frame> = messages.into_iter()
+        .map(|(topic, payload)| Frame { topic, payload })
+        .collect();
+    assert_eq!(decoded, expected);
+    assert_eq!(decoder.buffered(), 0);
+});
@@ -0,0 +1,342 @@
+use notify::event::{EventKind, ModifyKind};
+use notify::Event;
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeSet, HashMap};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::pubsub::VmmAction;
+use crate::watcher::resolver::InstancePath;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct BatchConfig {
+    /// How long changes to an instance are collected before they are sent.
+    /// Zero sends every event on its own.
+    pub window_ms: u64,
+    /// A change set is sent early once it touches this many paths.
+    pub max_paths: usize,
+}
+
+impl BatchConfig {
+    pub fn disabled() -> Self {
+        Self { window_ms: 0, max_paths: 0 }
+    }
+
+    pub fn window(&self) -> Duration {
+        Duration::from_millis(self.window_ms)
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        self.window_ms == 0
+    }
+
+    pub fn tick(&self) -> Duration {
+        Duration::from_millis((self.window_ms / 4).clamp(10, 250))
+    }
+}
+
+impl Default for BatchConfig {
+    fn default() -> Self {
+        Self { window_ms: 1_000, max_paths: 1_000 }
+    }
+}
+
+/// Every path touched in one instance over a batching window, by the net
+/// effect on it.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeSet {
+    /// The instance the paths belong to, with `path` set to `/`.
+    pub instance: Option<InstancePath>,
+    pub added: BTreeSet<PathBuf>,
+    pub modified: BTreeSet<PathBuf>,
+    pub removed: BTreeSet<PathBuf>,
+    /// Paths whose contents are unchanged but whose metadata is not.
+    pub metadata: BTreeSet<PathBuf>,
+}
+
+impl ChangeSet {
+    pub fn new(instance: Option<InstancePath>) -> Self {
+        Self { instance, ..Default::default() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.added.len() + self.modified.len() + self.removed.len() + self.metadata.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn paths(&self) -> impl Iterator<Item = &PathBuf> {
+        self.added.iter()
+            .chain(self.modified.iter())
+            .chain(self.removed.iter())
+            .chain(self.metadata.iter())
+    }
+
+    /// Folds an event into the set. A path created and removed within the
+    /// same set drops out entirely, and one removed then created again is
+    /// reported as modified.
+    pub fn record(&mut self, event: &Event) {
+        for path in &event.paths {
+            match event.kind {
+                EventKind::Create(_) => {
+                    self.metadata.remove(path);
+                    if self.removed.remove(path) {
+                        self.modified.insert(path.clone());
+                    } else {
+                        self.added.insert(path.clone());
+                    }
+                }
+                EventKind::Remove(_) => {
+                    self.modified.remove(path);
+                    self.metadata.remove(path);
+                    if !self.added.remove(path) {
+                        self.removed.insert(path.clone());
+                    }
+                }
+                EventKind::Modify(ModifyKind::Metadata(_)) => {
+                    if !self.added.contains(path) && !self.modified.contains(path) {
+                        self.metadata.insert(path.clone());
+                    }
+                }
+                _ => {
+                    self.metadata.remove(path);
+                    if !self.added.contains(path) {
+                        self.modified.insert(path.clone());
+                    }
+                }
+            }
+        }
+    }
+}
+
+struct Pending {
+    changes: ChangeSet,
+    action: VmmAction,
+    first_seen: Instant,
+}
+
+/// Collects `Copy` and `Rollup` events into one change set per instance.
+pub struct Batcher {
+    config: BatchConfig,
+    pending: HashMap<Option<InstancePath>, Pending>,
+}
+
+/// Key for grouping changes by instance, independent of the path inside it.
+pub(crate) fn instance_root(instance: Option<&InstancePath>) -> Option<InstancePath> {
+    instance.map(|instance| InstancePath {
+        path: PathBuf::from("/"),
+        in_rootfs: true,
+        ..instance.clone()
+    })
+}
+
+impl Batcher {
+    pub fn new(config: BatchConfig) -> Self {
+        Self { config, pending: HashMap::new() }
+    }
+
+    pub fn config(&self) -> &BatchConfig {
+        &self.config
+    }
+
+    /// Whether events with this action are collected rather than sent alone.
+    pub fn batches(&self, action: &VmmAction) -> bool {
+        !self.config.is_disabled() && matches!(action, VmmAction::Copy | VmmAction::Rollup)
+    }
+
+    /// Adds the event to its instance's change set, returning the set if it
+    /// has reached the size limit.
+    pub fn push(
+        &mut self,
+        instance: Option<&InstancePath>,
+        event: &Event,
+        action: VmmAction,
+        now: Instant
+    ) -> Option<(VmmAction, ChangeSet)> {
+        let key = instance_root(instance);
+        let pending = self.pending.entry(key.clone()).or_insert_with(|| Pending {
+            changes: ChangeSet::new(key.clone()),
+            action: VmmAction::Rollup,
+            first_seen: now,
+        });
+        // A single copy means the set needs data moved, not just metadata.
+        if let VmmAction::Copy = action {
+            pending.action = VmmAction::Copy;
+        }
+        pending.changes.record(event);
+
+        if pending.changes.len() >= self.config.max_paths {
+            return self.take(instance)
+        }
+
+        None
+    }
+
+    /// Removes the pending change set for an instance, so that an event that
+    /// cannot be batched is not sent ahead of earlier changes.
+    pub fn take(&mut self, instance: Option<&InstancePath>) -> Option<(VmmAction, ChangeSet)> {
+        self.pending.remove(&instance_root(instance))
+            .filter(|pending| !pending.changes.is_empty())
+            .map(|pending| (pending.action, pending.changes))
+    }
+
+    pub fn drain_ready(&mut self, now: Instant) -> Vec<(VmmAction, ChangeSet)> {
+        let window = self.config.window();
+        let mut expired: Vec<Option<InstancePath>> = self.pending.iter()
+            .filter(|(_, pending)| now.duration_since(pending.first_seen) >= window)
+            .map(|(key, _)| key.clone())
+            .collect();
+        expired.sort_by_key(|key| self.pending[key].first_seen);
+
+        expired.into_iter()
+            .filter_map(|key| self.pending.remove(&key))
+            .filter(|pending| !pending.changes.is_empty())
+            .map(|pending| (pending.action, pending.changes))
+            .collect()
+    }
+
+    pub fn drain_all(&mut self) -> Vec<(VmmAction, ChangeSet)> {
+        let mut pending: Vec<Pending> = self.pending.drain().map(|(_, p)| p).collect();
+        pending.sort_by_key(|p| p.first_seen);
+
+        pending.into_iter()
+            .filter(|pending| !pending.changes.is_empty())
+            .map(|pending| (pending.action, pending.changes))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{CreateKind, DataChange, MetadataKind, RemoveKind};
+
+    use crate::watcher::resolver::InstanceKind;
+
+    fn instance(name: &str, path: &str) -> InstancePath {
+        InstancePath {
+            name: name.to_string(),
+            project: None,
+            kind: InstanceKind::Container,
+            snapshot: None,
+            path: PathBuf::from(path),
+            in_rootfs: true,
+        }
+    }
+
+    fn event(kind: EventKind, path: &str) -> Event {
+        Event::new(kind).add_path(PathBuf::from(path))
+    }
+
+    fn create(path: &str) -> Event {
+        event(EventKind::Create(CreateKind::File), path)
+    }
+
+    fn write(path: &str) -> Event {
+        event(EventKind::Modify(ModifyKind::Data(DataChange::Content)), path)
+    }
+
+    fn remove(path: &str) -> Event {
+        event(EventKind::Remove(RemoveKind::File), path)
+    }
+
+    fn chmod(path: &str) -> Event {
+        event(EventKind::Modify(ModifyKind::Metadata(MetadataKind::Permissions)), path)
+    }
+
+    fn set(paths: &[&str]) -> BTreeSet<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn change_sets_keep_the_net_effect() {
+        let mut changes = ChangeSet::default();
+        for event in [
+            create("/tmp/scratch"), write("/tmp/scratch"), remove("/tmp/scratch"),
+            remove("/etc/hosts"), create("/etc/hosts"),
+            create("/etc/new"), write("/etc/new"), chmod("/etc/new"),
+            chmod("/etc/passwd"), write("/etc/passwd"),
+            chmod("/etc/shadow"),
+            write("/etc/gone"), remove("/etc/gone"),
+        ] {
+            changes.record(&event);
+        }
+
+        assert_eq!(changes.added, set(&["/etc/new"]));
+        assert_eq!(changes.modified, set(&["/etc/hosts", "/etc/passwd"]));
+        assert_eq!(changes.removed, set(&["/etc/gone"]));
+        assert_eq!(changes.metadata, set(&["/etc/shadow"]));
+        assert_eq!(changes.len(), 5);
+    }
+
+    #[test]
+    fn changes_are_grouped_per_instance_until_the_window_passes() {
+        let mut batcher = Batcher::new(BatchConfig { window_ms: 100, max_paths: 100 });
+        let start = Instant::now();
+        let c1 = instance("c1", "/etc/hosts");
+        let c2 = instance("c2", "/etc/hosts");
+        batcher.push(Some(&c1), &chmod("/etc/hosts"), VmmAction::Rollup, start);
+        batcher.push(Some(&c2), &write("/etc/hosts"), VmmAction::Copy, start + Duration::from_millis(50));
+        batcher.push(Some(&c1), &write("/etc/motd"), VmmAction::Copy, start + Duration::from_millis(60));
+
+        assert!(batcher.drain_ready(start + Duration::from_millis(99)).is_empty());
+
+        let ready = batcher.drain_ready(start + Duration::from_millis(100));
+        assert_eq!(ready.len(), 1);
+        let (action, changes) = &ready[0];
+        assert!(matches!(action, VmmAction::Copy));
+        assert_eq!(changes.instance, Some(instance("c1", "/")));
+        assert_eq!(changes.metadata, set(&["/etc/hosts"]));
+        assert_eq!(changes.modified, set(&["/etc/motd"]));
+
+        let ready = batcher.drain_ready(start + Duration::from_millis(150));
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].1.instance, Some(instance("c2", "/")));
+    }
+
+    #[test]
+    fn metadata_only_sets_are_rolled_up() {
+        let mut batcher = Batcher::new(BatchConfig::default());
+        let start = Instant::now();
+        batcher.push(None, &chmod("/etc/hosts"), VmmAction::Rollup, start);
+        let ready = batcher.drain_all();
+        assert!(matches!(ready[..], [(VmmAction::Rollup, _)]));
+    }
+
+    #[test]
+    fn full_sets_are_sent_early() {
+        let mut batcher = Batcher::new(BatchConfig { window_ms: 1_000, max_paths: 2 });
+        let start = Instant::now();
+        assert!(batcher.push(None, &write("/a"), VmmAction::Copy, start).is_none());
+        assert!(batcher.push(None, &write("/a"), VmmAction::Copy, start).is_none());
+        let (_, changes) = batcher.push(None, &write("/b"), VmmAction::Copy, start).unwrap();
+        assert_eq!(changes.modified, set(&["/a", "/b"]));
+        assert!(batcher.drain_all().is_empty());
+    }
+
+    #[test]
+    fn sets_that_cancel_out_are_not_sent() {
+        let mut batcher = Batcher::new(BatchConfig::default());
+        let start = Instant::now();
+        let c1 = instance("c1", "/tmp/x");
+        batcher.push(Some(&c1), &create("/tmp/x"), VmmAction::Copy, start);
+        batcher.push(Some(&c1), &remove("/tmp/x"), VmmAction::Copy, start);
+        assert!(batcher.take(Some(&c1)).is_none());
+
+        batcher.push(Some(&c1), &create("/tmp/x"), VmmAction::Copy, start);
+        batcher.push(Some(&c1), &remove("/tmp/x"), VmmAction::Copy, start);
+        assert!(batcher.drain_ready(start + Duration::from_secs(1)).is_empty());
+    }
+
+    #[test]
+    fn only_copies_and_rollups_are_batched() {
+        let batcher = Batcher::new(BatchConfig::default());
+        assert!(batcher.batches(&VmmAction::Copy));
+        assert!(batcher.batches(&VmmAction::Rollup));
+        assert!(!batcher.batches(&VmmAction::Snapshot));
+        assert!(!batcher.batches(&VmmAction::Move { from: PathBuf::from("/a"), to: PathBuf::from("/b") }));
+        assert!(!Batcher::new(BatchConfig::disabled()).batches(&VmmAction::Copy));
+    }
+}
@@ -1,41 +1,111 @@
 use conductor::{publisher::PubStream, subscriber::SubStream};
-use notify::{
-    Event,
-    EventKind,
-    event::{
-        AccessKind, 
-        AccessMode, 
-        CreateKind, 
-        ModifyKind, 
-        RemoveKind, 
-        DataChange, 
-        RenameMode, 
-        MetadataKind
-    }
-};
-
-use serde::{Serialize, Deserialize};
-use crate::pubsub::{FilesystemSubscriber, LibrettoPublisher, LibrettoTopic, LibrettoEvent, VmmAction};
-
-#[derive(Serialize, Deserialize)]
-pub struct LxdOperation {
-    pub id: String,
-    pub class: String,
-    pub description: String,
-    pub status: String,
-    pub status_code: u32,
-    pub resources: Option<LxdResources>,
+use notify::{Event, EventKind, event::{MetadataKind, ModifyKind}};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use crate::batch::{BatchConfig, Batcher, ChangeSet};
+use crate::handler::{HandlerRegistry, VmmActionHandler};
+use crate::lxd::LxdClient;
+use crate::migration::{MigrationConfig, MigrationCoordinator};
+pub use crate::lxd::{LxdOperation, LxdResources};
+use crate::policy::{Outcome, Policy};
+use crate::report::{Decision, Report, ReportEntry};
+use crate::rollup::{Rollup, RollupAggregator, RollupConfig};
+use crate::pubsub::{kind_name, ConnectionEvent, ConnectionState, Encoding, FilesystemEvent, FilesystemSubscriber, FilesystemTopic, LibrettoPublisher, LibrettoTopic, LibrettoEvent, TlsConfig, VmmAction};
+use crate::snapshot::{SnapshotConfig, SnapshotExecutor};
+use crate::watcher::resolver::InstancePath;
+
+/// Where the client's `LibrettoEvent`s go: to every registered handler, then
+/// to the broker. In a dry run they only go to the report.
+pub struct EventSink {
+    publisher: LibrettoPublisher,
+    handlers: HandlerRegistry,
+    outcomes: UnboundedSender<LibrettoEvent>,
+    report: Option<Report>,
+    /// Outbox drops receivers have already been told about.
+    reported_drops: u64
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct LxdResources {
-    pub containers: Option<Vec<String>>,
-    pub instances: Option<Vec<String>>
+impl EventSink {
+    pub fn new(publisher: LibrettoPublisher, outcomes: UnboundedSender<LibrettoEvent>) -> Self {
+        Self { publisher, handlers: HandlerRegistry::new(), outcomes, report: None, reported_drops: 0 }
+    }
+
+    /// Writes every decision to `report` instead of acting on it.
+    pub fn with_report(mut self, report: Report) -> Self {
+        self.report = Some(report);
+        self
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.report.is_some()
+    }
+
+    pub async fn send(&mut self, event: LibrettoEvent) -> std::io::Result<()> {
+        if let Some(report) = self.report.as_mut() {
+            return report.record(&ReportEntry::published(Decision::Publish, event))
+        }
+        self.handlers.dispatch(&event, &self.outcomes);
+        self.publisher.publish(LibrettoTopic, event).await
+    }
+
+    /// Publishes a result event. These are not handed to the handlers, which
+    /// produced them in the first place.
+    pub async fn send_outcome(&mut self, event: LibrettoEvent) -> std::io::Result<()> {
+        if let Some(report) = self.report.as_mut() {
+            return report.record(&ReportEntry::published(Decision::Outcome, event))
+        }
+        self.publisher.publish(LibrettoTopic, event).await
+    }
+
+    /// Sends events held while the broker was unreachable, if it is back.
+    pub async fn retry_buffered(&mut self) {
+        if self.publisher.buffered() == 0 {
+            return
+        }
+        if let Err(e) = self.publisher.try_flush().await {
+            log::debug!("still holding {} events: {e}", self.publisher.buffered());
+        }
+    }
+
+    /// Tells receivers how many events the outbox discarded while the broker
+    /// was unreachable. Sent once the broker is back, so the notice is not
+    /// itself dropped.
+    pub async fn report_dropped(&mut self) {
+        let dropped = self.publisher.dropped() - self.reported_drops;
+        if dropped == 0 {
+            return
+        }
+        self.reported_drops = self.publisher.dropped();
+        let reason = format!("{dropped} events were dropped while the broker was unreachable");
+        let event = LibrettoEvent::new(Event::new(EventKind::Other), VmmAction::Degraded(reason), None);
+        if let Err(e) = self.send(event).await {
+            log::info!("ERROR: attempting to notify nodes of dropped events: {e}");
+        }
+    }
+
+    /// Records an event the policy logged or dropped. Only dry runs report
+    /// these.
+    pub fn skip(&mut self, decision: Decision, event: &Event, instance: Option<&InstancePath>) {
+        if let Some(report) = self.report.as_mut() {
+            if let Err(e) = report.record(&ReportEntry::skipped(decision, event, instance)) {
+                log::error!("unable to write dry-run report: {e}");
+            }
+        }
+    }
 }
 
 pub struct LibrettoClient {
     subscriber: FilesystemSubscriber,
-    publisher: LibrettoPublisher
+    sink: EventSink,
+    policy: Policy,
+    batcher: Batcher,
+    rollups: RollupAggregator,
+    snapshots: Option<SnapshotExecutor>,
+    migrations: Option<MigrationCoordinator>,
+    outcomes: UnboundedReceiver<LibrettoEvent>,
+    connections: UnboundedReceiver<ConnectionEvent>
 }
 
 impl LibrettoClient {
@@ -43,349 +113,422 @@ impl LibrettoClient {
         subscriber_uri: &str,
         publisher_uri: &str,
     ) -> std::io::Result<Self> {
-        let subscriber = FilesystemSubscriber::new(subscriber_uri).await?;
-        let publisher = LibrettoPublisher::new(publisher_uri).await?;
-        Ok(Self { subscriber, publisher })
+        Self::connect(subscriber_uri, publisher_uri, None).await
+    }
+
+    /// Connects both broker connections over TLS when `tls` is set.
+    pub async fn connect(
+        subscriber_uri: &str,
+        publisher_uri: &str,
+        tls: Option<&TlsConfig>,
+    ) -> std::io::Result<Self> {
+        let (connection_sender, connections) = tokio::sync::mpsc::unbounded_channel();
+        let subscriber = FilesystemSubscriber::connect(subscriber_uri, FilesystemTopic, tls).await?
+            .with_state_events(connection_sender.clone());
+        let publisher = LibrettoPublisher::connect(publisher_uri, tls).await?
+            .with_state_events(connection_sender);
+        let (outcome_sender, outcomes) = tokio::sync::mpsc::unbounded_channel();
+        Ok(Self {
+            subscriber,
+            sink: EventSink::new(publisher, outcome_sender),
+            policy: Policy::default(),
+            batcher: Batcher::new(BatchConfig::default()),
+            rollups: RollupAggregator::new(RollupConfig::default()),
+            snapshots: None,
+            migrations: None,
+            outcomes,
+            connections
+        })
+    }
+
+    pub fn with_policy(mut self, policy: Policy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn with_batching(mut self, config: BatchConfig) -> Self {
+        self.batcher = Batcher::new(config);
+        self
+    }
+
+    pub fn with_rollups(mut self, config: RollupConfig) -> Self {
+        self.rollups = RollupAggregator::new(config);
+        self
+    }
+
+    /// Creates an LXD snapshot of the instance for every `Snapshot` action
+    /// and publishes the result, instead of only passing the action on.
+    pub fn with_snapshots(mut self, config: SnapshotConfig, lxd: LxdClient) -> Self {
+        self.snapshots = Some(SnapshotExecutor::new(config, lxd, self.sink.outcomes.clone()));
+        self
     }
+
+    /// Lets the client move instances to other nodes. Unfinished migrations
+    /// are resumed when the client starts running.
+    pub fn with_migrations(mut self, config: MigrationConfig, lxd: LxdClient) -> Self {
+        let coordinator = MigrationCoordinator::new(config, lxd, self.sink.outcomes.clone());
+        self.sink.handlers.register(Arc::new(coordinator.clone()));
+        self.migrations = Some(coordinator);
+        self
+    }
+
+    /// Publishes events in `encoding`. The client decodes every encoding it
+    /// receives whatever this is set to.
+    pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.sink.publisher = self.sink.publisher.with_encoding(encoding);
+        self
+    }
+
+    /// Runs the whole pipeline but writes each decided action to `report`
+    /// instead of handling or publishing it. Snapshots are not taken and
+    /// migrations are not resumed.
+    pub fn with_dry_run(mut self, report: Report) -> Self {
+        self.sink.report = Some(report);
+        self
+    }
+
+    /// Handle for starting migrations, if they are enabled.
+    pub fn migrations(&self) -> Option<MigrationCoordinator> {
+        self.migrations.clone()
+    }
+
+    /// Calls the handler for every action the client emits, before the
+    /// action is published.
+    pub fn with_handler(mut self, handler: impl VmmActionHandler + 'static) -> Self {
+        self.sink.handlers.register(Arc::new(handler));
+        self
+    }
+
     pub async fn run(
         mut self,
     ) -> std::io::Result<()> {
+        let mut batch_interval = tokio::time::interval(self.batcher.config().tick());
+        let mut rollup_interval = tokio::time::interval(self.rollups.config().tick());
+        if let (Some(migrations), false) = (&self.migrations, self.sink.is_dry_run()) {
+            let resumed = migrations.resume()?;
+            if resumed > 0 {
+                log::info!("resumed {resumed} unfinished migrations");
+            }
+        }
         loop {
             tokio::select! {
                 Ok(messages) = self.subscriber.receive() => {
                     log::info!("Received Libretto Message");
                     for message in messages {
-                        handle_events(message, &mut self.publisher).await;
+                        handle_events(
+                            message,
+                            &self.policy,
+                            &mut self.batcher,
+                            &mut self.rollups,
+                            self.snapshots.as_ref(),
+                            &mut self.sink
+                        ).await;
                     }
                 }
-                _ = tokio::signal::ctrl_c() => {
-                    break;
+                Some(connection) = self.connections.recv() => {
+                    handle_connection_event(connection, &mut self.batcher, &mut self.sink).await;
                 }
-            }
-        }
-
-        Ok(())
-    }
-}
-
-pub async fn handle_events(event: Event, publisher: &mut LibrettoPublisher) {
-    let kind = event.kind.clone();
-    match kind {
-        EventKind::Any => {}
-        EventKind::Access(access) => {
-            match access {
-                AccessKind::Any => {
-                    log::info!("AccessKind::Any: {:?}", event);
-                }
-                AccessKind::Read => {
-                    log::info!("AccessKind::Read: {:?}", event);
-                }
-                AccessKind::Open(mode) => {
-                    match mode {
-                        AccessMode::Any => {
-                            log::info!("AccessKind::Open(AccessMode::Any): {:?}", event);
-                        }
-                        AccessMode::Read => {
-                            log::info!("AccessKind::Open(AccessMode::Read): {:?}", event);
-                        }
-                        AccessMode::Execute => {
-                            log::info!("AccessKind::Open(AccessMode::Execute): {:?}", event);
-                        }
-                        AccessMode::Write => {
-                            log::info!("AccessKind::Open(AccessMode::Write): {:?}", event);
-                        }
-                        AccessMode::Other => {
-                            log::info!("AccessKind::Open(AccessMode::Other): {:?}", event);
-                        }
+                Some(outcome) = self.outcomes.recv() => {
+                    if let Err(e) = self.sink.send_outcome(outcome).await {
+                        log::info!("ERROR: attempting to publish action outcome: {e}");
                     }
                 }
-                AccessKind::Close(mode) => {
-                    match mode {
-                        AccessMode::Any => {
-                            log::info!("AccessKind::Close(AccessMode::Any): {:?}", event);
-                        }
-                        AccessMode::Read => {
-                            log::info!("AccessKind::Close(AccessMode::Read): {:?}", event);
-                        }
-                        AccessMode::Execute => {
-                            log::info!("AccessKind::Close(AccessMode::Execute): {:?}", event);
-                            if let Some(path) = event.clone().paths.get(0) {
-                                if let Err(e) = notify_vmm(path.to_str(), publisher, event, VmmAction::Copy).await {
-                                    log::info!("ERROR: attempting to notify nodes of AccessMode::Execute: {e}");
-                                }
-                            }
-                        }
-                        AccessMode::Write => {
-                            log::info!("AccessKind::Close(AccessMode::Write): {:?}", event);
-                            if let Some(path) = event.clone().paths.get(0) {
-                                if let Err(e) = notify_vmm(path.to_str(), publisher, event, VmmAction::Copy).await {
-                                    log::info!("ERROR: attempting to notify nodes of AccessMode::Write: {e}");
-                                }
-                            }
-                        }
-                        AccessMode::Other => {
-                            log::info!("AccessKind::Close(AccessMode::Other): {:?}", event);
-                        }
-                    }
-                }
-                AccessKind::Other => {
-                    log::info!("AccessKind::Other: {:?}", event);
-                }
-            }
-        }
-        EventKind::Create(create) => {
-            match create {
-                CreateKind::Any => {
-                    log::info!("AccessKind::Create(CreateKind::Any): {:?}", event);
-                    if let Some(path) = event.clone().paths.get(0) {
-                        if let Err(e) = notify_vmm(path.to_str(), publisher, event, VmmAction::Copy).await {
-                            log::info!("ERROR: attempting to notify nodes of CreateKind::Any: {e}");
-                        }
+                _ = batch_interval.tick() => {
+                    self.sink.retry_buffered().await;
+                    for (action, changes) in self.batcher.drain_ready(Instant::now()) {
+                        notify_change_set(&mut self.sink, action, changes).await;
                     }
                 }
-                CreateKind::File => {
-                    log::info!("AccessKind::Create(CreateKind::File): {:?}", event);
-                    if let Some(path) = event.clone().paths.get(0) {
-                        if let Err(e) = notify_vmm(path.to_str(), publisher, event, VmmAction::Copy).await {
-                            log::info!("ERROR: attempting to notify nodes of CreateKind::File: {e}");
-                        }
+                _ = rollup_interval.tick() => {
+                    for rollup in self.rollups.drain_ready(Instant::now()) {
+                        notify_rollup(&mut self.sink, rollup).await;
                     }
                 }
-                CreateKind::Folder => {
-                    log::info!("AccessKind::Create(CreateKind::Folder): {:?}", event);
-                    if let Some(path) = event.clone().paths.get(0) {
-                        if let Err(e) = notify_vmm(path.to_str(), publisher, event, VmmAction::Copy).await {
-                            log::info!("ERROR: attempting to notify nodes of CreateKind::Folder: {e}");
-                        }
+                _ = tokio::signal::ctrl_c() => {
+                    for (action, changes) in self.batcher.drain_all() {
+                        notify_change_set(&mut self.sink, action, changes).await;
                     }
-                }
-                CreateKind::Other => {
-                    log::info!("AccessKind::Create(CreateKind::Other): {:?}", event);
-                    if let Some(path) = event.clone().paths.get(0) {
-                        if let Err(e) = notify_vmm(path.to_str(), publisher, event, VmmAction::Copy).await {
-                            log::info!("ERROR: attempting to notify nodes of CreateKind::Other: {e}");
-                        }
+                    for rollup in self.rollups.drain_all() {
+                        notify_rollup(&mut self.sink, rollup).await;
                     }
+                    break;
                 }
             }
         }
-        EventKind::Modify(modify) => {
-            match modify {
-                ModifyKind::Any => {
-                    log::info!("ModifyKind::Any: {:?}", event);
-                    if let Some(path) = event.clone().paths.get(0) {
-                        if let Err(e) = notify_vmm(path.to_str(), publisher, event, VmmAction::Copy).await {
-                            log::info!("ERROR: attempting to notify nodes of ModifyKind::Any: {e}");
-                        }
-                    }
-                }
-                ModifyKind::Data(data_change) => {
-                    match data_change {
-                        DataChange::Any => {
-                            log::info!("ModifyKind::Data(DataChange::Any): {:?}", event);
-                            if let Some(path) = event.clone().paths.get(0) {
-                                if let Err(e) = notify_vmm(path.to_str(), publisher, event, VmmAction::Copy).await {
-                                    log::info!("ERROR: attempting to notify nodes of ModifyKind::Data(DataChange::Any): {e}");
-                                }
-                            }
-                        }
-                        DataChange::Size => {
-                            log::info!("ModifyKind::Data(DataChange::Size): {:?}", event);
-                            if let Some(path) = event.clone().paths.get(0) {
-                                if let Err(e) = notify_vmm(path.to_str(), publisher, event, VmmAction::Copy).await {
-                                    log::info!("ERROR: attempting to notify nodes of ModifyKind::Data(DataChange::Size): {e}");
-                                }
-                            }
-                        }
-                        DataChange::Content => {
-                            log::info!("ModifyKind::Data(DataChange::Content): {:?}", event);
-                            if let Some(path) = event.clone().paths.get(0) {
-                                if let Err(e) = notify_vmm(path.to_str(), publisher, event, VmmAction::Copy).await {
-                                    log::info!("ERROR: attempting to notify nodes of ModifyKind::Data(DataChange::Content): {e}");
-                                }
-                            }
-                        }
-                        DataChange::Other => {
-                            log::info!("ModifyKind::Data(DataChange::Other): {:?}", event);
-                            if let Some(path) = event.clone().paths.get(0) {
-                                if let Err(e) = notify_vmm(path.to_str(), publisher, event, VmmAction::Copy).await {
-                                    log::info!("ERROR: attempting to notify nodes of ModifyKind::Data(DataChange::Other): {e}");
-                                }
-                            }
-                        }
-                    }
-                }
-                ModifyKind::Name(rename) => {
-                    match rename {
-                        RenameMode::Any => {
-                            log::info!("ModifyKind::Name(RenameMode::Any): {:?}", event);
-                            if let Some(path) = event.clone().paths.get(0) {
-                                if let Err(e) = notify_vmm(path.to_str(), publisher, event, VmmAction::Copy).await {
-                                    log::info!("ERROR: attempting to notify nodes of ModifyKind::Name(RenameMode::Any): {e}");
-                                }
-                            }
-                        }
-                        RenameMode::To => {
-                            log::info!("ModifyKind::Name(RenameMode::To): {:?}", event);
-                            if let Some(path) = event.clone().paths.get(0) {
-                                if let Err(e) = notify_vmm(path.to_str(), publisher, event, VmmAction::Copy).await {
-                                    log::info!("ERROR: attempting to notify nodes of ModifyKind::Name(RenameMode::To): {e}");
-                                }
-                            }
-                        }
-                        RenameMode::From => {
-                            log::info!("ModifyKind::Name(RenameMode::From): {:?}", event);
-                            if let Some(path) = event.clone().paths.get(0) {
-                                if let Err(e) = notify_vmm(path.to_str(), publisher, event, VmmAction::Copy).await {
-                                    log::info!("ERROR: attempting to notify nodes of ModifyKind::Name(RenameMode::From): {e}");
-                                }
-                            }
-                        }
-                        RenameMode::Both => {
-                            log::info!("ModifyKind::Name(RenameMode::Both): {:?}", event);
-                            if let Some(path) = event.clone().paths.get(0) {
-                                if let Err(e) = notify_vmm(path.to_str(), publisher, event, VmmAction::Copy).await {
-                                    log::info!("ERROR: attempting to notify nodes of ModifyKind::Name(RenameMode::Both): {e}");
-                                }
-                            }
-                        }
-                        RenameMode::Other => {
-                            log::info!("ModifyKind::Name(RenameMode::Other): {:?}", event);
-                            if let Some(path) = event.clone().paths.get(0) {
-                                if let Err(e) = notify_vmm(path.to_str(), publisher, event, VmmAction::Copy).await {
-                                    log::info!("ERROR: attempting to notify nodes of ModifyKind::Name(RenameMode::Other): {e}");
-                                }
-                            }
-                        }
-                    }
-                }
-                ModifyKind::Metadata(metadata) => {
-                    match metadata {
-                        MetadataKind::Any => {
-                            log::info!("ModifyKind::Metadata(MetadataKind::Any): {:?}", event);
-                            if let Some(path) = event.clone().paths.get(0) {
-                                if let Err(e) = notify_vmm(path.to_str(), publisher, event, VmmAction::Rollup).await {
-                                    log::info!("ERROR: attempting to notify nodes of ModifyKind::Metadata(MetdataKind::Any): {e}");
-                                }
-                            }
-                        }
-                        MetadataKind::Other => {
-                            log::info!("ModifyKind::Metadata(MetadataKind::Other): {:?}", event);
-                            if let Some(path) = event.clone().paths.get(0) {
-                                if let Err(e) = notify_vmm(path.to_str(), publisher, event, VmmAction::Rollup).await {
-                                    log::info!("ERROR: attempting to notify nodes of ModifyKind::Metadata(MetdataKind::Other): {e}");
-                                }
-                            }
-                        }
-                        MetadataKind::Extended => {
-                            log::info!("ModifyKind::Metadata(MetadataKind::Extended): {:?}", event);
-                            if let Some(path) = event.clone().paths.get(0) {
-                                if let Err(e) = notify_vmm(path.to_str(), publisher, event, VmmAction::Rollup).await {
-                                    log::info!("ERROR: attempting to notify nodes of ModifyKind::Metadata(MetdataKind::Other): {e}");
-                                }
-                            }
-                        }
-                        MetadataKind::WriteTime => {
-                            log::info!("ModifyKind::Metadata(MetadataKind::WriteTime): {:?}", event);
-                            if let Some(path) = event.clone().paths.get(0) {
-                                if let Err(e) = notify_vmm(path.to_str(), publisher, event, VmmAction::Rollup).await {
-                                    log::info!("ERROR: attempting to notify nodes of ModifyKind::Metadata(MetdataKind::WriteTime): {e}");
-                                }
-                            }
-                        }
-                        MetadataKind::Ownership => {
-                            log::info!("ModifyKind::Metadata(MetadataKind::Ownership): {:?}", event);
-                            if let Some(path) = event.clone().paths.get(0) {
-                                if let Err(e) = notify_vmm(path.to_str(), publisher, event, VmmAction::Copy).await {
-                                    log::info!("ERROR: attempting to notify nodes of ModifyKind::Metadata(MetdataKind::Ownership): {e}");
-                                }
-                            }
-                        }
-                        MetadataKind::AccessTime => {
-                            log::info!("ModifyKind::Metadata(MetadataKind::AccessTime): {:?}", event);
-                            if let Some(path) = event.clone().paths.get(0) {
-                                if let Err(e) = notify_vmm(path.to_str(), publisher, event, VmmAction::Rollup).await {
-                                    log::info!("ERROR: attempting to notify nodes of ModifyKind::Metadata(MetdataKind::AccessTime): {e}");
-                                }
-                            }
-                        }
-                        MetadataKind::Permissions => {
-                            log::info!("ModifyKind::Metadata(MetadataKind::Permissions): {:?}", event);
-                            if let Some(path) = event.clone().paths.get(0) {
-                                if let Err(e) = notify_vmm(path.to_str(), publisher, event, VmmAction::Copy).await {
-                                    log::info!("ERROR: attempting to notify nodes of ModifyKind::Metadata(MetdataKind::Permissions): {e}");
-                                }
-                            }
-                        }
-                    }
-                }
-                ModifyKind::Other => {
-                    log::info!("ModifyKind::Metadata(MetadataKind::Other): {:?}", event);
-                    if let Some(path) = event.clone().paths.get(0) {
-                        if let Err(e) = notify_vmm(path.to_str(), publisher, event, VmmAction::Copy).await {
-                            log::info!("ERROR: attempting to notify nodes of ModifyKind::Metadata(MetdataKind::Other): {e}");
-                        }
-                    }
-                }
-            }
+
+        Ok(())
+    }
+}
+
+pub async fn handle_events(
+    event: FilesystemEvent,
+    policy: &Policy,
+    batcher: &mut Batcher,
+    rollups: &mut RollupAggregator,
+    snapshots: Option<&SnapshotExecutor>,
+    sink: &mut EventSink
+) {
+    let destination = event.destination().clone();
+    let (event, instance) = event.into_parts();
+    if event.need_rescan() {
+        if let Some((action, changes)) = batcher.take(instance.as_ref()) {
+            notify_change_set(sink, action, changes).await;
         }
-        EventKind::Remove(remove) => {
-            match remove {
-                RemoveKind::Any => {
-                    log::info!("RemoveKind::Any: {:?}", event);
-                    if let Some(path) = event.clone().paths.get(0) {
-                        if let Err(e) = notify_vmm(path.to_str(), publisher, event, VmmAction::Copy).await {
-                            log::info!("ERROR: attempting to notify nodes of RemoveKind::Any: {e}");
-                        }
-                    }
-                }
-                RemoveKind::File => {
-                    log::info!("RemoveKind::File: {:?}", event);
-                    if let Some(path) = event.clone().paths.get(0) {
-                        if let Err(e) = notify_vmm(path.to_str(), publisher, event, VmmAction::Copy).await {
-                            log::info!("ERROR: attempting to notify nodes of RemoveKind::File: {e}");
-                        }
-                    }
+        log::warn!("watcher degraded: {:?}", event);
+        let reason = event.info().unwrap_or("rescan required").to_string();
+        if let Err(e) = notify_vmm(instance, sink, event, VmmAction::Degraded(reason)).await {
+            log::info!("ERROR: attempting to notify nodes of degraded watcher: {e}");
+        }
+        return
+    }
+
+    let kind = kind_name(&event.kind);
+    match policy.evaluate(&event, instance.as_ref()).clone() {
+        Outcome::Drop => {
+            sink.skip(Decision::Drop, &event, instance.as_ref());
+        }
+        Outcome::Log => {
+            log::info!("{kind}: {:?}", event);
+            sink.skip(Decision::Log, &event, instance.as_ref());
+        }
+        Outcome::Publish(action) => {
+            log::info!("{kind}: {:?}", event);
+            if event.paths.is_empty() {
+                return
+            }
+            if let (VmmAction::Rollup, true) = (&action, rollups.is_enabled()) {
+                if let Some(rollup) = rollups.push(instance.as_ref(), &event, Instant::now()) {
+                    notify_rollup(sink, rollup).await;
                 }
-                RemoveKind::Folder => {
-                    log::info!("RemoveKind::Folder: {:?}", event);
-                    if let Some(path) = event.clone().paths.get(0) {
-                        if let Err(e) = notify_vmm(path.to_str(), publisher, event, VmmAction::Copy).await {
-                            log::info!("ERROR: attempting to notify nodes of RemoveKind::Folder: {e}");
-                        }
-                    }
+                return
+            }
+            if batcher.batches(&action) {
+                let full = batcher.push(instance.as_ref(), &event, action, Instant::now());
+                if let Some((action, changes)) = full {
+                    notify_change_set(sink, action, changes).await;
                 }
-                RemoveKind::Other => {
-                    log::info!("RemoveKind::Other: {:?}", event);
-                    if let Some(path) = event.clone().paths.get(0) {
-                        if let Err(e) = notify_vmm(path.to_str(), publisher, event, VmmAction::Copy).await {
-                            log::info!("ERROR: attempting to notify nodes of RemoveKind::Other: {e}");
-                        }
-                    }
+                return
+            }
+            if let Some((action, changes)) = batcher.take(instance.as_ref()) {
+                notify_change_set(sink, action, changes).await;
+            }
+            if let (VmmAction::Snapshot, Some(snapshots), Some(instance)) = (&action, snapshots, &instance) {
+                if !sink.is_dry_run() {
+                    snapshots.spawn(instance.clone(), event.clone());
                 }
             }
+            if let Err(e) = notify_vmm(instance, sink, event, action).await {
+                log::info!("ERROR: attempting to notify nodes of {kind}: {e}");
+            }
         }
-        EventKind::Other => {
-            log::info!("EventKind::Other: {:?}", event);
-            if let Some(path) = event.clone().paths.get(0) {
-                if let Err(e) = notify_vmm(path.to_str(), publisher, event, VmmAction::Snapshot).await {
-                    log::info!("ERROR: attempting to notify nodes of EventKind::Other: {e}");
+        Outcome::Move => {
+            log::info!("{kind}: {:?}", event);
+            // Earlier changes may touch the source, so they go first.
+            if let Some((action, changes)) = batcher.take(instance.as_ref()) {
+                notify_change_set(sink, action, changes).await;
+            }
+            if let (Some(from), Some(to)) = (event.paths.first().cloned(), event.paths.get(1).cloned()) {
+                if let Err(e) = notify_vmm_move(instance, destination, sink, event, from, to).await {
+                    log::info!("ERROR: attempting to notify nodes of {kind}: {e}");
                 }
             }
         }
     }
 }
 
-async fn notify_vmm(instance_name: Option<&str>, publisher: &mut LibrettoPublisher, event: Event, action: VmmAction) -> std::io::Result<()> {
-    log::info!("received an event {:?}, inform vmm, time to copy {:?}", event, instance_name);
+/// Logs broker connection changes. When the subscription comes back, events
+/// published while it was down are gone, and when the publisher comes back,
+/// events its outbox could not hold are gone, so receivers are told their
+/// view may be stale.
+async fn handle_connection_event(connection: ConnectionEvent, batcher: &mut Batcher, sink: &mut EventSink) {
+    log::info!("connection to {} is now {:?}", connection.uri, connection.state);
+    let topic = match (&connection.topic, &connection.state) {
+        (Some(topic), ConnectionState::Connected) => topic,
+        (None, ConnectionState::Connected) => return sink.report_dropped().await,
+        _ => return,
+    };
+
+    for (action, changes) in batcher.drain_all() {
+        notify_change_set(sink, action, changes).await;
+    }
+    let reason = format!("resubscribed to {topic} on {}, events may have been missed", connection.uri);
+    if let Err(e) = notify_vmm(None, sink, Event::new(EventKind::Other), VmmAction::Degraded(reason)).await {
+        log::info!("ERROR: attempting to notify nodes of missed events: {e}");
+    }
+}
+
+async fn notify_vmm(instance: Option<InstancePath>, sink: &mut EventSink, event: Event, action: VmmAction) -> std::io::Result<()> {
+    log::info!("received an event {:?}, inform vmm, time to copy {:?}", event, instance);
 
     let event = LibrettoEvent::new(
         event,
         action,
-        instance_name.map(|s| s.to_string())
+        instance
     );
 
-    publisher.publish(LibrettoTopic, event).await?;
+    sink.send(event).await?;
+
+    Ok(())
+}
+
+async fn notify_vmm_move(
+    instance: Option<InstancePath>,
+    destination: Option<InstancePath>,
+    sink: &mut EventSink,
+    event: Event,
+    from: PathBuf,
+    to: PathBuf
+) -> std::io::Result<()> {
+    log::info!("received a move {:?} -> {:?}, inform vmm", from, to);
+
+    let event = LibrettoEvent::new(
+        event,
+        VmmAction::Move { from, to },
+        instance
+    ).with_destination(destination);
+
+    sink.send(event).await?;
 
     Ok(())
 }
+
+async fn notify_change_set(sink: &mut EventSink, action: VmmAction, changes: ChangeSet) {
+    log::info!(
+        "sending {} batched changes for {:?}",
+        changes.len(),
+        changes.instance.as_ref().map(|i| i.qualified_name())
+    );
+
+    let mut event = Event::new(EventKind::Any);
+    event.paths = changes.paths().cloned().collect();
+    let instance = changes.instance.clone();
+    let event = LibrettoEvent::new(event, action, instance).with_changes(changes);
+
+    if let Err(e) = sink.send(event).await {
+        log::info!("ERROR: attempting to notify nodes of batched changes: {e}");
+    }
+}
+
+async fn notify_rollup(sink: &mut EventSink, rollup: Rollup) {
+    log::info!(
+        "sending metadata rollup of {} paths for {:?}",
+        rollup.len(),
+        rollup.instance.as_ref().map(|i| i.qualified_name())
+    );
+
+    let mut event = Event::new(EventKind::Modify(ModifyKind::Metadata(MetadataKind::Any)));
+    event.paths = rollup.paths.keys().cloned().collect();
+    let instance = rollup.instance.clone();
+    let event = LibrettoEvent::new(event, VmmAction::Rollup, instance).with_rollup(rollup);
+
+    if let Err(e) = sink.send(event).await {
+        log::info!("ERROR: attempting to notify nodes of metadata rollup: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{AccessKind, CreateKind};
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+    use tonic::async_trait;
+
+    use crate::pubsub::ActionOutcome;
+    use crate::report::ReportSink;
+
+    /// Records every action it is handed.
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl Recorder {
+        fn record(&self, event: &LibrettoEvent) -> std::io::Result<Option<ActionOutcome>> {
+            self.0.lock().unwrap().push(format!("{:?}", event.action()));
+            Ok(None)
+        }
+    }
+
+    #[async_trait]
+    impl VmmActionHandler for Recorder {
+        async fn copy(&self, event: &LibrettoEvent) -> std::io::Result<Option<ActionOutcome>> {
+            self.record(event)
+        }
+
+        async fn snapshot(&self, event: &LibrettoEvent) -> std::io::Result<Option<ActionOutcome>> {
+            self.record(event)
+        }
+
+        async fn other(&self, event: &LibrettoEvent) -> std::io::Result<Option<ActionOutcome>> {
+            self.record(event)
+        }
+    }
+
+    #[tokio::test]
+    async fn dry_runs_report_decisions_without_acting_on_them() {
+        let subscriptions = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let publications = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let publisher_uri = publications.local_addr().unwrap().to_string();
+        let broker = tokio::spawn(async move {
+            let (mut socket, _) = publications.accept().await.unwrap();
+            let mut published = Vec::new();
+            socket.read_to_end(&mut published).await.unwrap();
+            published
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("report.jsonl");
+        let report = ReportSink::parse(path.to_str().unwrap()).open().unwrap();
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut client = LibrettoClient::new(&subscriptions.local_addr().unwrap().to_string(), &publisher_uri).await.unwrap()
+            .with_batching(BatchConfig { window_ms: 0, max_paths: 1 })
+            .with_handler(Recorder(calls.clone()))
+            .with_dry_run(report);
+
+        let events = [
+            Event::new(EventKind::Create(CreateKind::File)).add_path(PathBuf::from("/etc/hosts")),
+            Event::new(EventKind::Access(AccessKind::Read)).add_path(PathBuf::from("/etc/passwd")),
+            Event::new(EventKind::Any).add_path(PathBuf::from("/etc/group")),
+        ];
+        for event in events {
+            handle_events(
+                FilesystemEvent::new(event, None),
+                &client.policy,
+                &mut client.batcher,
+                &mut client.rollups,
+                client.snapshots.as_ref(),
+                &mut client.sink
+            ).await;
+        }
+        let outcome = LibrettoEvent::new(Event::new(EventKind::Other), VmmAction::Snapshot, None)
+            .with_outcome(ActionOutcome::failed("disk full".to_string(), None, None));
+        client.sink.send_outcome(outcome).await.unwrap();
+
+        let entries: Vec<ReportEntry> = std::fs::read_to_string(&path).unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let decisions: Vec<_> = entries.iter()
+            .map(|entry| (entry.decision, entry.kind.as_str(), entry.paths.clone()))
+            .collect();
+        assert_eq!(decisions, [
+            (Decision::Publish, "create.file", vec![PathBuf::from("/etc/hosts")]),
+            (Decision::Log, "access.read", vec![PathBuf::from("/etc/passwd")]),
+            (Decision::Drop, "any", vec![PathBuf::from("/etc/group")]),
+            (Decision::Outcome, "other", vec![]),
+        ]);
+        assert!(matches!(entries[0].event.as_ref().map(|e| e.action()), Some(VmmAction::Copy)));
+        assert!(entries[1].event.is_none());
+        assert!(entries[2].event.is_none());
+        assert!(entries[3].event.as_ref().unwrap().outcome().is_some());
+
+        // Handlers run on their own task, so give them time to be called.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(calls.lock().unwrap().is_empty());
+        assert!(client.outcomes.try_recv().is_err());
+
+        drop(client);
+        let published = tokio::time::timeout(Duration::from_secs(5), broker).await.unwrap().unwrap();
+        assert!(published.is_empty(), "published {} bytes", published.len());
+    }
+}
@@ -0,0 +1,273 @@
+use std::sync::{Arc, RwLock};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tonic::async_trait;
+
+use crate::pubsub::{ActionOutcome, LibrettoEvent, VmmAction};
+
+/// Carries out `VmmAction`s in-process. Every method defaults to doing
+/// nothing, so a handler only implements the actions it cares about.
+/// Returning an outcome, or an error, publishes a result event for the
+/// action.
+#[async_trait]
+pub trait VmmActionHandler: Send + Sync {
+    async fn copy(&self, event: &LibrettoEvent) -> std::io::Result<Option<ActionOutcome>> {
+        let _ = event;
+        Ok(None)
+    }
+
+    async fn migrate(&self, event: &LibrettoEvent) -> std::io::Result<Option<ActionOutcome>> {
+        let _ = event;
+        Ok(None)
+    }
+
+    async fn snapshot(&self, event: &LibrettoEvent) -> std::io::Result<Option<ActionOutcome>> {
+        let _ = event;
+        Ok(None)
+    }
+
+    async fn rollup(&self, event: &LibrettoEvent) -> std::io::Result<Option<ActionOutcome>> {
+        let _ = event;
+        Ok(None)
+    }
+
+    /// Moves, degraded notices and `VmmAction::Other`.
+    async fn other(&self, event: &LibrettoEvent) -> std::io::Result<Option<ActionOutcome>> {
+        let _ = event;
+        Ok(None)
+    }
+}
+
+async fn dispatch(handler: &dyn VmmActionHandler, event: &LibrettoEvent) -> std::io::Result<Option<ActionOutcome>> {
+    match event.action() {
+        VmmAction::Copy => handler.copy(event).await,
+        VmmAction::Migrate => handler.migrate(event).await,
+        VmmAction::Snapshot => handler.snapshot(event).await,
+        VmmAction::Rollup => handler.rollup(event).await,
+        VmmAction::Move { .. } | VmmAction::Degraded(_) | VmmAction::Other(_) => handler.other(event).await,
+    }
+}
+
+type Handlers = Arc<RwLock<Vec<Arc<dyn VmmActionHandler>>>>;
+
+/// Handlers registered with a `LibrettoClient`, called in registration order
+/// for every event the client emits. Events are handled one at a time, in
+/// the order they were emitted, on a single background task that sees
+/// handlers registered after it started.
+#[derive(Default)]
+pub struct HandlerRegistry {
+    handlers: Handlers,
+    worker: Option<UnboundedSender<LibrettoEvent>>,
+}
+
+impl HandlerRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(&mut self, handler: Arc<dyn VmmActionHandler>) {
+        match self.handlers.write() {
+            Ok(mut handlers) => handlers.push(handler),
+            Err(e) => log::error!("unable to acquire lock on action handlers: {e}"),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.handlers.read().map(|handlers| handlers.len()).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Queues the event for the handlers. Each one that reports an outcome
+    /// or fails sends a result event to `outcomes`.
+    pub fn dispatch(&mut self, event: &LibrettoEvent, outcomes: &UnboundedSender<LibrettoEvent>) {
+        if self.is_empty() {
+            return
+        }
+
+        let worker = self.worker.get_or_insert_with(|| {
+            let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+            tokio::spawn(run(self.handlers.clone(), receiver, outcomes.clone()));
+            sender
+        });
+        if worker.send(event.clone()).is_err() {
+            log::error!("action handler worker has stopped");
+            self.worker = None;
+        }
+    }
+}
+
+async fn run(
+    handlers: Handlers,
+    mut events: UnboundedReceiver<LibrettoEvent>,
+    outcomes: UnboundedSender<LibrettoEvent>
+) {
+    while let Some(event) = events.recv().await {
+        let handlers = match handlers.read() {
+            Ok(handlers) => handlers.clone(),
+            Err(e) => {
+                log::error!("unable to acquire lock on action handlers: {e}");
+                return
+            }
+        };
+        for handler in &handlers {
+            let outcome = match dispatch(handler.as_ref(), &event).await {
+                Ok(Some(outcome)) => outcome,
+                Ok(None) => continue,
+                Err(e) => {
+                    log::error!("action handler failed on {:?}: {e}", event.action());
+                    ActionOutcome::failed(e.to_string(), None, None)
+                }
+            };
+            if outcomes.send(event.clone().with_outcome(outcome)).is_err() {
+                log::error!("unable to report handler outcome, client has stopped");
+                return
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{CreateKind, EventKind};
+    use notify::Event;
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+
+    use crate::pubsub::OutcomeStatus;
+
+    #[derive(Default)]
+    struct Recorder {
+        calls: Mutex<Vec<&'static str>>,
+    }
+
+    impl Recorder {
+        fn record(&self, method: &'static str) -> std::io::Result<Option<ActionOutcome>> {
+            self.calls.lock().unwrap().push(method);
+            Ok(None)
+        }
+    }
+
+    #[async_trait]
+    impl VmmActionHandler for Recorder {
+        async fn copy(&self, _: &LibrettoEvent) -> std::io::Result<Option<ActionOutcome>> {
+            self.record("copy")
+        }
+
+        async fn migrate(&self, _: &LibrettoEvent) -> std::io::Result<Option<ActionOutcome>> {
+            self.record("migrate")
+        }
+
+        async fn snapshot(&self, _: &LibrettoEvent) -> std::io::Result<Option<ActionOutcome>> {
+            self.record("snapshot")
+        }
+
+        async fn rollup(&self, _: &LibrettoEvent) -> std::io::Result<Option<ActionOutcome>> {
+            self.record("rollup")
+        }
+
+        async fn other(&self, _: &LibrettoEvent) -> std::io::Result<Option<ActionOutcome>> {
+            self.record("other")
+        }
+    }
+
+    struct Failing;
+
+    #[async_trait]
+    impl VmmActionHandler for Failing {
+        async fn copy(&self, _: &LibrettoEvent) -> std::io::Result<Option<ActionOutcome>> {
+            Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "replica is read-only"))
+        }
+
+        async fn snapshot(&self, _: &LibrettoEvent) -> std::io::Result<Option<ActionOutcome>> {
+            Ok(Some(ActionOutcome::completed(Some("op".to_string()), Some("snap0".to_string()))))
+        }
+    }
+
+    fn event(action: VmmAction) -> LibrettoEvent {
+        let event = Event::new(EventKind::Create(CreateKind::File)).add_path(PathBuf::from("/etc/hosts"));
+        LibrettoEvent::new(event, action, None)
+    }
+
+    #[tokio::test]
+    async fn each_action_reaches_its_method() {
+        let recorder = Arc::new(Recorder::default());
+        let mut registry = HandlerRegistry::new();
+        registry.register(recorder.clone());
+        let (outcomes, mut results) = tokio::sync::mpsc::unbounded_channel();
+
+        let actions = [
+            VmmAction::Copy,
+            VmmAction::Migrate,
+            VmmAction::Snapshot,
+            VmmAction::Rollup,
+            VmmAction::Move { from: PathBuf::from("/a"), to: PathBuf::from("/b") },
+            VmmAction::Degraded("overflow".to_string()),
+            VmmAction::Other("unknown".to_string()),
+        ];
+        for action in actions {
+            registry.dispatch(&event(action), &outcomes);
+        }
+        drop(registry);
+        drop(outcomes);
+
+        // The worker exits once the registry is gone and the queue is empty.
+        assert!(results.recv().await.is_none());
+        assert_eq!(
+            *recorder.calls.lock().unwrap(),
+            vec!["copy", "migrate", "snapshot", "rollup", "other", "other", "other"]
+        );
+    }
+
+    #[tokio::test]
+    async fn failures_and_outcomes_are_reported() {
+        let mut registry = HandlerRegistry::new();
+        registry.register(Arc::new(Failing));
+        let (outcomes, mut results) = tokio::sync::mpsc::unbounded_channel();
+
+        registry.dispatch(&event(VmmAction::Copy), &outcomes);
+        registry.dispatch(&event(VmmAction::Rollup), &outcomes);
+        registry.dispatch(&event(VmmAction::Snapshot), &outcomes);
+
+        let failed: LibrettoEvent = results.recv().await.unwrap();
+        assert!(matches!(failed.action(), VmmAction::Copy));
+        match &failed.outcome().as_ref().unwrap().status {
+            OutcomeStatus::Failed(reason) => assert!(reason.contains("read-only")),
+            status => panic!("unexpected status {status:?}"),
+        }
+
+        let completed = results.recv().await.unwrap();
+        assert!(matches!(completed.action(), VmmAction::Snapshot));
+        let outcome = completed.outcome().clone().unwrap();
+        assert!(matches!(outcome.status, OutcomeStatus::Completed));
+        assert_eq!(outcome.resource.as_deref(), Some("snap0"));
+    }
+
+    #[tokio::test]
+    async fn handlers_registered_later_share_the_worker() {
+        let first = Arc::new(Recorder::default());
+        let second = Arc::new(Recorder::default());
+        let mut registry = HandlerRegistry::new();
+        let (outcomes, mut results) = tokio::sync::mpsc::unbounded_channel();
+
+        registry.register(first.clone());
+        registry.dispatch(&event(VmmAction::Copy), &outcomes);
+        let worker = registry.worker.clone().unwrap();
+        while first.calls.lock().unwrap().is_empty() {
+            tokio::task::yield_now().await;
+        }
+        registry.register(second.clone());
+        registry.dispatch(&event(VmmAction::Rollup), &outcomes);
+        assert!(worker.same_channel(registry.worker.as_ref().unwrap()));
+        assert_eq!(registry.len(), 2);
+
+        drop(worker);
+        drop(registry);
+        drop(outcomes);
+        assert!(results.recv().await.is_none());
+        assert_eq!(*first.calls.lock().unwrap(), vec!["copy", "rollup"]);
+        assert_eq!(*second.calls.lock().unwrap(), vec!["rollup"]);
+    }
+}
@@ -0,0 +1,356 @@
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+use crate::statics::STORAGE_PATH;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JournalConfig {
+    #[serde(default = "JournalConfig::default_enabled")]
+    pub enabled: bool,
+    /// Defaults to `$STORAGE_PATH/journal`.
+    #[serde(default)]
+    pub dir: Option<PathBuf>,
+    #[serde(default = "JournalConfig::default_segment_bytes")]
+    pub max_segment_bytes: u64,
+    /// fsync after every append. Survives power loss, not just crashes, at a
+    /// large cost in throughput.
+    #[serde(default)]
+    pub sync: bool,
+}
+
+impl JournalConfig {
+    fn default_enabled() -> bool {
+        true
+    }
+
+    fn default_segment_bytes() -> u64 {
+        64 * 1024 * 1024
+    }
+
+    pub fn dir(&self) -> PathBuf {
+        self.dir.clone().unwrap_or_else(|| {
+            Path::new(STORAGE_PATH.as_str()).join("journal")
+        })
+    }
+}
+
+impl Default for JournalConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            dir: None,
+            max_segment_bytes: Self::default_segment_bytes(),
+            sync: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JournalEntry<T> {
+    pub seq: u64,
+    pub item: T,
+}
+
+struct Segment {
+    first_seq: u64,
+    path: PathBuf,
+}
+
+/// Append-only, segmented log of accepted events. Every entry gets a sequence
+/// number; consumers commit the highest sequence they have delivered and
+/// anything above the last flushed commit is handed back by `pending` after
+/// a restart.
+pub struct Journal<T> {
+    config: JournalConfig,
+    dir: PathBuf,
+    segments: Vec<Segment>,
+    writer: Option<File>,
+    writer_len: u64,
+    next_seq: u64,
+    committed: u64,
+    /// The commit last written to disk.
+    flushed: u64,
+    _marker: PhantomData<T>,
+}
+
+impl<T: Serialize + DeserializeOwned> Journal<T> {
+    pub fn open(config: JournalConfig) -> std::io::Result<Self> {
+        let dir = config.dir();
+        std::fs::create_dir_all(&dir)?;
+
+        let mut segments: Vec<Segment> = std::fs::read_dir(&dir)?.flatten().filter_map(|entry| {
+            let path = entry.path();
+            let first_seq = path.file_name()?.to_str()?.strip_suffix(".log")?.parse().ok()?;
+            Some(Segment { first_seq, path })
+        }).collect();
+        segments.sort_by_key(|s| s.first_seq);
+
+        let committed = match std::fs::read_to_string(dir.join("committed")) {
+            Ok(contents) => contents.trim().parse().map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("unable to parse committed offset: {e}")
+                )
+            })?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+
+        let last_seq = match segments.last() {
+            Some(segment) => Self::read_segment(&segment.path)?
+                .last()
+                .map(|entry| entry.seq)
+                .unwrap_or(segment.first_seq.saturating_sub(1)),
+            None => committed,
+        };
+
+        let mut journal = Self {
+            config,
+            dir,
+            segments,
+            writer: None,
+            writer_len: 0,
+            next_seq: last_seq.max(committed) + 1,
+            committed,
+            flushed: committed,
+            _marker: PhantomData,
+        };
+        journal.open_writer()?;
+
+        Ok(journal)
+    }
+
+    fn segment_path(&self, first_seq: u64) -> PathBuf {
+        self.dir.join(format!("{first_seq:020}.log"))
+    }
+
+    fn open_writer(&mut self) -> std::io::Result<()> {
+        if self.segments.is_empty() {
+            let path = self.segment_path(self.next_seq);
+            self.segments.push(Segment { first_seq: self.next_seq, path });
+        }
+        if let Some(segment) = self.segments.last() {
+            truncate_torn_tail(&segment.path)?;
+            let writer = OpenOptions::new().create(true).append(true).open(&segment.path)?;
+            self.writer_len = writer.metadata()?.len();
+            self.writer = Some(writer);
+        }
+
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        let path = self.segment_path(self.next_seq);
+        self.segments.push(Segment { first_seq: self.next_seq, path });
+        self.open_writer()
+    }
+
+    fn read_segment(path: &Path) -> std::io::Result<Vec<JournalEntry<T>>> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut entries = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            // A crash mid-append can leave a torn final line behind.
+            match serde_json::from_str(&line) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => log::warn!("skipping unreadable journal entry in {}: {e}", path.display()),
+            }
+        }
+
+        Ok(entries)
+    }
+
+    pub fn committed(&self) -> u64 {
+        self.committed
+    }
+
+    pub fn last_seq(&self) -> u64 {
+        self.next_seq - 1
+    }
+
+    pub fn append(&mut self, item: &T) -> std::io::Result<u64> {
+        if self.writer_len >= self.config.max_segment_bytes {
+            self.rotate()?;
+        }
+
+        let seq = self.next_seq;
+        let mut line = serde_json::to_vec(&JournalEntry { seq, item }).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::Other,
+                e
+            )
+        })?;
+        line.push(b'\n');
+
+        let writer = self.writer.as_mut().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "journal has no open segment"
+            )
+        })?;
+        writer.write_all(&line)?;
+        if self.config.sync {
+            writer.sync_data()?;
+        }
+
+        self.writer_len += line.len() as u64;
+        self.next_seq += 1;
+        Ok(seq)
+    }
+
+    /// Records that everything up to and including `seq` has been delivered.
+    /// The offset only reaches disk on the next `flush`, so callers commit
+    /// every event and flush once per batch.
+    pub fn commit(&mut self, seq: u64) {
+        self.committed = self.committed.max(seq);
+    }
+
+    /// Durably stores the committed offset and removes segments that hold
+    /// nothing newer.
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        if self.flushed == self.committed {
+            return Ok(())
+        }
+
+        let tmp = self.dir.join("committed.tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(self.committed.to_string().as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, self.dir.join("committed"))?;
+        File::open(&self.dir)?.sync_all()?;
+        self.flushed = self.committed;
+
+        // A segment is done once the next one starts at or below the commit.
+        while self.segments.len() > 1 && self.segments[1].first_seq <= self.committed + 1 {
+            let segment = self.segments.remove(0);
+            if let Err(e) = std::fs::remove_file(&segment.path) {
+                log::warn!("unable to remove journal segment {}: {e}", segment.path.display());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Every entry above the committed offset, up to and including `up_to`
+    /// when given.
+    pub fn pending(&self, up_to: Option<u64>) -> std::io::Result<Vec<JournalEntry<T>>> {
+        let up_to = up_to.unwrap_or(u64::MAX);
+        let mut entries = Vec::new();
+        for (idx, segment) in self.segments.iter().enumerate() {
+            if segment.first_seq > up_to {
+                break;
+            }
+            let next_first = self.segments.get(idx + 1).map(|s| s.first_seq).unwrap_or(u64::MAX);
+            if next_first <= self.committed + 1 {
+                continue;
+            }
+            entries.extend(
+                Self::read_segment(&segment.path)?
+                    .into_iter()
+                    .filter(|entry| entry.seq > self.committed && entry.seq <= up_to)
+            );
+        }
+
+        Ok(entries)
+    }
+}
+
+/// Cuts a segment back to its last complete line, so an entry torn by a crash
+/// mid-append is not glued to the next one.
+fn truncate_torn_tail(path: &Path) -> std::io::Result<()> {
+    let contents = match std::fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let complete = contents.iter().rposition(|b| *b == b'\n').map_or(0, |idx| idx + 1);
+    if complete < contents.len() {
+        log::warn!("dropping {} bytes of a torn entry from {}", contents.len() - complete, path.display());
+        OpenOptions::new().write(true).open(path)?.set_len(complete as u64)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(dir: &Path, max_segment_bytes: u64) -> JournalConfig {
+        JournalConfig {
+            enabled: true,
+            dir: Some(dir.to_path_buf()),
+            max_segment_bytes,
+            sync: false,
+        }
+    }
+
+    fn items(entries: Vec<JournalEntry<String>>) -> Vec<(u64, String)> {
+        entries.into_iter().map(|entry| (entry.seq, entry.item)).collect()
+    }
+
+    #[test]
+    fn torn_entries_are_cut_before_appending() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut journal = Journal::<String>::open(config(dir.path(), 1 << 20)).unwrap();
+        journal.append(&"a".to_string()).unwrap();
+        let segment = journal.segments[0].path.clone();
+        drop(journal);
+
+        let mut file = OpenOptions::new().append(true).open(&segment).unwrap();
+        file.write_all(br#"{"seq":2,"item":"tor"#).unwrap();
+        drop(file);
+
+        let mut journal = Journal::<String>::open(config(dir.path(), 1 << 20)).unwrap();
+        assert_eq!(journal.append(&"b".to_string()).unwrap(), 2);
+        drop(journal);
+
+        let journal = Journal::<String>::open(config(dir.path(), 1 << 20)).unwrap();
+        assert_eq!(items(journal.pending(None).unwrap()), vec![(1, "a".to_string()), (2, "b".to_string())]);
+    }
+
+    #[test]
+    fn commits_survive_reopening_and_remove_old_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut journal = Journal::<String>::open(config(dir.path(), 1)).unwrap();
+        for item in ["a", "b", "c"] {
+            journal.append(&item.to_string()).unwrap();
+        }
+        assert_eq!(journal.segments.len(), 3);
+        assert_eq!(items(journal.pending(Some(2)).unwrap()), vec![(1, "a".to_string()), (2, "b".to_string())]);
+
+        journal.commit(2);
+        journal.commit(1);
+        assert_eq!(journal.committed(), 2);
+        assert_eq!(journal.segments.len(), 3);
+        journal.flush().unwrap();
+        assert_eq!(journal.segments.len(), 1);
+        drop(journal);
+
+        let journal = Journal::<String>::open(config(dir.path(), 1)).unwrap();
+        assert_eq!(journal.committed(), 2);
+        assert_eq!(journal.last_seq(), 3);
+        assert_eq!(items(journal.pending(None).unwrap()), vec![(3, "c".to_string())]);
+    }
+
+    #[test]
+    fn unflushed_commits_are_delivered_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut journal = Journal::<String>::open(config(dir.path(), 1 << 20)).unwrap();
+        for item in ["a", "b", "c"] {
+            journal.append(&item.to_string()).unwrap();
+        }
+        journal.commit(1);
+        journal.flush().unwrap();
+        journal.commit(2);
+        assert_eq!(items(journal.pending(None).unwrap()), vec![(3, "c".to_string())]);
+        drop(journal);
+
+        let journal = Journal::<String>::open(config(dir.path(), 1 << 20)).unwrap();
+        assert_eq!(journal.committed(), 1);
+        assert!(!dir.path().join("committed.tmp").exists());
+        assert_eq!(items(journal.pending(None).unwrap()), vec![(2, "b".to_string()), (3, "c".to_string())]);
+    }
+}
@@ -3,7 +3,22 @@ pub mod server;
 pub mod watcher;
 pub mod statics;
 pub mod pubsub;
+pub mod journal;
+pub mod batch;
+pub mod rollup;
+pub mod migration;
+pub mod report;
+pub mod lxd;
+pub mod snapshot;
+pub mod handler;
+pub mod sync;
+pub mod policy;
 
 pub mod dfs {
     tonic::include_proto!("dfs");
 }
+
+/// Protobuf forms of the pubsub messages, see `pubsub::Encoding`.
+pub mod wire {
+    tonic::include_proto!("pubsub");
+}
@@ -0,0 +1,467 @@
+use chrono::{DateTime, Utc};
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::header::{CONTENT_TYPE, HOST};
+use hyper::{Method, Request};
+use hyper_util::rt::TokioIo;
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::net::UnixStream;
+
+use crate::statics::LXD_SOCKET_PATH;
+
+// LXD status codes. Anything from `STATUS_SUCCESS` up is terminal.
+const STATUS_SUCCESS: u32 = 200;
+const STATUS_FAILURE: u32 = 400;
+const STATUS_CANCELLED: u32 = 401;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LxdOperation {
+    pub id: String,
+    pub class: String,
+    pub description: String,
+    pub status: String,
+    pub status_code: u32,
+    pub resources: Option<LxdResources>,
+    #[serde(default)]
+    pub err: String,
+}
+
+impl LxdOperation {
+    pub fn is_done(&self) -> bool {
+        self.status_code >= STATUS_SUCCESS
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.status_code == STATUS_SUCCESS
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LxdResources {
+    pub containers: Option<Vec<String>>,
+    pub instances: Option<Vec<String>>
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LxdInstance {
+    pub name: String,
+    pub status: String,
+    pub status_code: u32,
+    /// `container` or `virtual-machine`.
+    #[serde(rename = "type")]
+    pub instance_type: String,
+    #[serde(default)]
+    pub project: String,
+    #[serde(default)]
+    pub config: HashMap<String, String>,
+    #[serde(default)]
+    pub expanded_config: HashMap<String, String>,
+    #[serde(default)]
+    pub profiles: Vec<String>,
+    #[serde(default)]
+    pub stateful: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LxdInstanceState {
+    pub status: String,
+    pub status_code: u32,
+    #[serde(default)]
+    pub pid: i64,
+    #[serde(default)]
+    pub processes: i64,
+}
+
+/// Envelope every LXD API response comes in.
+#[derive(Debug, Deserialize)]
+struct LxdResponse {
+    #[serde(rename = "type")]
+    response_type: String,
+    #[serde(default)]
+    error: String,
+    #[serde(default)]
+    error_code: u32,
+    #[serde(default)]
+    metadata: serde_json::Value,
+}
+
+/// Client for the LXD REST API on its local unix socket.
+#[derive(Clone, Debug)]
+pub struct LxdClient {
+    socket: PathBuf,
+    project: Option<String>,
+}
+
+impl Default for LxdClient {
+    fn default() -> Self {
+        Self::new(LXD_SOCKET_PATH.as_str())
+    }
+}
+
+impl LxdClient {
+    pub fn new(socket: impl AsRef<Path>) -> Self {
+        Self { socket: socket.as_ref().to_path_buf(), project: None }
+    }
+
+    /// Scopes every request to an LXD project other than `default`.
+    pub fn with_project(mut self, project: &str) -> Self {
+        self.project = Some(project.to_string());
+        self
+    }
+
+    fn uri(&self, path: &str) -> String {
+        match &self.project {
+            Some(project) if path.contains('?') => format!("{path}&project={project}"),
+            Some(project) => format!("{path}?project={project}"),
+            None => path.to_string(),
+        }
+    }
+
+    async fn request<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<serde_json::Value>
+    ) -> std::io::Result<T> {
+        let stream = UnixStream::connect(&self.socket).await.map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!("unable to connect to LXD at {}: {e}", self.socket.display())
+            )
+        })?;
+        let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+            .await
+            .map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("LXD handshake failed: {e}")
+                )
+            })?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                log::error!("LXD connection error: {e}");
+            }
+        });
+
+        let body = match body {
+            Some(body) => Full::new(Bytes::from(serde_json::to_vec(&body).map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    e
+                )
+            })?)),
+            None => Full::new(Bytes::new()),
+        };
+        let request = Request::builder()
+            .method(method)
+            .uri(self.uri(path))
+            .header(HOST, "lxd")
+            .header(CONTENT_TYPE, "application/json")
+            .body(body)
+            .map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    e
+                )
+            })?;
+
+        let response = sender.send_request(request).await.map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("LXD request to {path} failed: {e}")
+            )
+        })?;
+        let bytes = response.into_body().collect().await.map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("unable to read LXD response from {path}: {e}")
+            )
+        })?.to_bytes();
+
+        let response: LxdResponse = serde_json::from_slice(&bytes).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unable to parse LXD response from {path}: {e}")
+            )
+        })?;
+        if response.response_type == "error" {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("LXD error {} from {path}: {}", response.error_code, response.error)
+            ))
+        }
+
+        serde_json::from_value(response.metadata).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unexpected LXD metadata from {path}: {e}")
+            )
+        })
+    }
+
+    /// Names of every instance in the project.
+    pub async fn list_instances(&self) -> std::io::Result<Vec<String>> {
+        let urls: Vec<String> = self.request(Method::GET, "/1.0/instances", None).await?;
+        Ok(urls.iter().filter_map(|url| {
+            let name = url.rsplit('/').next()?;
+            Some(name.split('?').next().unwrap_or(name).to_string())
+        }).collect())
+    }
+
+    /// The instance's definition, including its local and expanded config.
+    pub async fn instance(&self, name: &str) -> std::io::Result<LxdInstance> {
+        self.request(Method::GET, &format!("/1.0/instances/{name}"), None).await
+    }
+
+    pub async fn instance_state(&self, name: &str) -> std::io::Result<LxdInstanceState> {
+        self.request(Method::GET, &format!("/1.0/instances/{name}/state"), None).await
+    }
+
+    /// Starts a snapshot and returns the background operation; pass it to
+    /// `wait` to block until it is done.
+    pub async fn create_snapshot(
+        &self,
+        name: &str,
+        snapshot: &str,
+        stateful: bool,
+        expires_at: Option<DateTime<Utc>>
+    ) -> std::io::Result<LxdOperation> {
+        let mut body = serde_json::json!({ "name": snapshot, "stateful": stateful });
+        if let Some(expires_at) = expires_at {
+            body["expires_at"] = serde_json::Value::String(expires_at.to_rfc3339());
+        }
+        self.request(Method::POST, &format!("/1.0/instances/{name}/snapshots"), Some(body)).await
+    }
+
+    /// Starts a state change such as `freeze`, `unfreeze` or `stop` and
+    /// returns the background operation.
+    pub async fn update_state(&self, name: &str, action: &str, force: bool) -> std::io::Result<LxdOperation> {
+        let body = serde_json::json!({ "action": action, "force": force, "timeout": -1 });
+        self.request(Method::PUT, &format!("/1.0/instances/{name}/state"), Some(body)).await
+    }
+
+    pub async fn operation(&self, id: &str) -> std::io::Result<LxdOperation> {
+        self.request(Method::GET, &format!("/1.0/operations/{id}"), None).await
+    }
+
+    /// Blocks on LXD's `/wait` endpoint until the operation is done or the
+    /// timeout passes, returning an error if it failed or was cancelled.
+    pub async fn wait(&self, id: &str, timeout: Duration) -> std::io::Result<LxdOperation> {
+        let path = format!("/1.0/operations/{id}/wait?timeout={}", timeout.as_secs().max(1));
+        let operation: LxdOperation = self.request(Method::GET, &path, None).await?;
+        Self::finished(operation)
+    }
+
+    /// Polls the operation every `interval` until it is done, for LXD
+    /// versions or proxies where `/wait` is not usable.
+    pub async fn poll(&self, id: &str, interval: Duration, timeout: Duration) -> std::io::Result<LxdOperation> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let operation = self.operation(id).await?;
+            if operation.is_done() || tokio::time::Instant::now() >= deadline {
+                return Self::finished(operation)
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    fn finished(operation: LxdOperation) -> std::io::Result<LxdOperation> {
+        match operation.status_code {
+            STATUS_SUCCESS => Ok(operation),
+            STATUS_FAILURE | STATUS_CANCELLED => Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("LXD operation {} {}: {}", operation.id, operation.status.to_lowercase(), operation.err)
+            )),
+            _ if operation.is_done() => Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("LXD operation {} ended with status {}", operation.id, operation.status)
+            )),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("LXD operation {} still {}", operation.id, operation.status.to_lowercase())
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::UnixListener;
+
+    pub(crate) type Requests = Arc<Mutex<Vec<String>>>;
+
+    /// Answers every request on a socket in a tempdir with the envelope
+    /// `respond` returns for its method and target, and records what was
+    /// asked for.
+    pub(crate) fn serve<F>(respond: F) -> (tempfile::TempDir, LxdClient, Requests)
+    where
+        F: Fn(&str) -> serde_json::Value + Send + Sync + 'static
+    {
+        let (dir, client, requests, _bodies) = serve_with_bodies(respond);
+        (dir, client, requests)
+    }
+
+    /// Like `serve`, also recording every request body, empty for requests
+    /// without one.
+    pub(crate) fn serve_with_bodies<F>(respond: F) -> (tempfile::TempDir, LxdClient, Requests, Requests)
+    where
+        F: Fn(&str) -> serde_json::Value + Send + Sync + 'static
+    {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("unix.socket");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let requests = Requests::default();
+        let bodies = Requests::default();
+        let respond = Arc::new(respond);
+
+        let (recorded, recorded_bodies) = (requests.clone(), bodies.clone());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let (respond, recorded, recorded_bodies) = (respond.clone(), recorded.clone(), recorded_bodies.clone());
+                tokio::spawn(async move {
+                    let mut reader = BufReader::new(stream);
+                    let mut request_line = String::new();
+                    reader.read_line(&mut request_line).await.unwrap();
+                    let mut content_length = 0;
+                    loop {
+                        let mut header = String::new();
+                        reader.read_line(&mut header).await.unwrap();
+                        if header.trim().is_empty() {
+                            break;
+                        }
+                        if let Some((name, value)) = header.split_once(':') {
+                            if name.eq_ignore_ascii_case("content-length") {
+                                content_length = value.trim().parse().unwrap();
+                            }
+                        }
+                    }
+                    let mut body = vec![0; content_length];
+                    reader.read_exact(&mut body).await.unwrap();
+
+                    let request = request_line.rsplit_once(' ').map_or("", |(request, _)| request).to_string();
+                    let response = serde_json::to_vec(&respond(&request)).unwrap();
+                    recorded.lock().unwrap().push(request);
+                    recorded_bodies.lock().unwrap().push(String::from_utf8_lossy(&body).to_string());
+                    let mut stream = reader.into_inner();
+                    let head = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        response.len()
+                    );
+                    stream.write_all(head.as_bytes()).await.unwrap();
+                    stream.write_all(&response).await.unwrap();
+                });
+            }
+        });
+
+        let client = LxdClient::new(&socket);
+        (dir, client, requests, bodies)
+    }
+
+    pub(crate) fn sync(metadata: serde_json::Value) -> serde_json::Value {
+        serde_json::json!({ "type": "sync", "status": "Success", "status_code": 200, "metadata": metadata })
+    }
+
+    pub(crate) fn operation(status: &str, status_code: u32, err: &str) -> serde_json::Value {
+        serde_json::json!({
+            "id": "op1",
+            "class": "task",
+            "description": "Snapshotting instance",
+            "status": status,
+            "status_code": status_code,
+            "resources": null,
+            "err": err,
+        })
+    }
+
+    #[tokio::test]
+    async fn instance_names_are_taken_from_their_urls() {
+        let (_dir, client, requests) = serve(|_| sync(serde_json::json!([
+            "/1.0/instances/c1",
+            "/1.0/instances/vm1?project=staging",
+        ])));
+
+        assert_eq!(client.list_instances().await.unwrap(), vec!["c1".to_string(), "vm1".to_string()]);
+        assert_eq!(*requests.lock().unwrap(), vec!["GET /1.0/instances".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn error_envelopes_become_errors() {
+        let (_dir, client, _requests) = serve(|_| serde_json::json!({
+            "type": "error",
+            "error": "Instance not found",
+            "error_code": 404,
+        }));
+
+        let e = client.instance("missing").await.unwrap_err();
+        assert!(e.to_string().contains("LXD error 404"), "{e}");
+        assert!(e.to_string().contains("Instance not found"), "{e}");
+    }
+
+    #[tokio::test]
+    async fn requests_are_scoped_to_the_project() {
+        let (_dir, client, requests) = serve(|_| sync(operation("Success", 200, "")));
+        let client = client.with_project("staging");
+
+        client.operation("op1").await.unwrap();
+        client.wait("op1", Duration::from_secs(30)).await.unwrap();
+        assert_eq!(*requests.lock().unwrap(), vec![
+            "GET /1.0/operations/op1?project=staging".to_string(),
+            "GET /1.0/operations/op1/wait?timeout=30&project=staging".to_string(),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn wait_reports_how_the_operation_ended() {
+        let cases = [
+            ("Success", 200, None),
+            ("Failure", 400, Some(std::io::ErrorKind::Other)),
+            ("Cancelled", 401, Some(std::io::ErrorKind::Other)),
+            ("Running", 103, Some(std::io::ErrorKind::TimedOut)),
+        ];
+        for (status, status_code, expected) in cases {
+            let (_dir, client, _requests) = serve(move |_| sync(operation(status, status_code, "disk full")));
+            let result = client.wait("op1", Duration::from_millis(10)).await;
+            match expected {
+                None => assert!(result.unwrap().is_success()),
+                Some(kind) => assert_eq!(result.unwrap_err().kind(), kind, "{status}"),
+            }
+        }
+
+        let (_dir, client, _requests) = serve(|_| sync(operation("Failure", 400, "disk full")));
+        let e = client.wait("op1", Duration::from_secs(1)).await.unwrap_err();
+        assert!(e.to_string().contains("disk full"), "{e}");
+    }
+
+    #[tokio::test]
+    async fn poll_gives_up_at_the_deadline() {
+        let (_dir, client, requests) = serve(|_| sync(operation("Running", 103, "")));
+
+        let e = client.poll("op1", Duration::from_millis(10), Duration::from_millis(50)).await.unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::TimedOut);
+        assert!(requests.lock().unwrap().len() > 1);
+    }
+
+    #[tokio::test]
+    async fn poll_returns_once_the_operation_is_done() {
+        let polls = Arc::new(Mutex::new(0));
+        let counter = polls.clone();
+        let (_dir, client, _requests) = serve(move |_| {
+            let mut polls = counter.lock().unwrap();
+            *polls += 1;
+            match *polls {
+                1 | 2 => sync(operation("Running", 103, "")),
+                _ => sync(operation("Success", 200, "")),
+            }
+        });
+
+        let operation = client.poll("op1", Duration::from_millis(10), Duration::from_secs(5)).await.unwrap();
+        assert!(operation.is_success());
+        assert_eq!(*polls.lock().unwrap(), 3);
+    }
+}
@@ -1,24 +1,86 @@
 use libretto::client::LibrettoClient;
-use libretto::pubsub::FilesystemPublisher;
-use libretto::watcher;
+use libretto::lxd::LxdClient;
+use libretto::migration::MigrationConfig;
+use libretto::policy::Policy;
+use libretto::report::ReportSink;
+use libretto::snapshot::SnapshotConfig;
+use libretto::sync::{ReplicaSync, SyncConfig};
+use libretto::pubsub::{Encoding, FilesystemPublisher, TlsConfig};
+use libretto::statics::{
+    BROKER_PUBLISH_ADDR, BROKER_SUBSCRIBE_ADDR, DRY_RUN_REPORT, FILTER_CONFIG_PATH, MIGRATION_CONFIG_PATH, POLICY_CONFIG_PATH,
+    PUBSUB_ENCODING, REPLICA_PATH, SNAPSHOT_CONFIG_PATH, TLS_CA_PATH, TLS_CERT_PATH, TLS_KEY_PATH, TLS_SERVER_NAME,
+    WATCH_CONFIG_PATH, WATCH_PATH
+};
+use libretto::watcher::{self, EventQueue, RootConfig, WatchConfig, WatchRoots};
 
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
-    let libretto_client = LibrettoClient::new(
-        "127.0.0.1:5556",
-        "127.0.0.1:5555"
-    ).await?;
+    let watch_config = match (WATCH_CONFIG_PATH.as_ref(), WATCH_PATH.as_ref()) {
+        (Some(path), _) => WatchConfig::load(path)?,
+        (None, Some(path)) => {
+            let mut root = RootConfig::new(path);
+            root.filter_file = FILTER_CONFIG_PATH.as_ref().map(Into::into);
+            WatchConfig { roots: vec![root], ..Default::default() }
+        }
+        (None, None) => return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "nothing to watch, set LIBRETTO_WATCH_CONFIG or LIBRETTO_WATCH_PATH"
+        )),
+    };
+    let policy = match POLICY_CONFIG_PATH.as_ref() {
+        Some(path) => Policy::from_file(path)?,
+        None => Policy::default(),
+    };
+    let encoding = match PUBSUB_ENCODING.as_ref() {
+        Some(name) => name.parse::<Encoding>()?,
+        None => Encoding::default(),
+    };
+    let tls = TLS_CA_PATH.as_ref().map(|ca_path| TlsConfig {
+        ca_path: ca_path.into(),
+        cert_path: TLS_CERT_PATH.as_ref().map(Into::into),
+        key_path: TLS_KEY_PATH.as_ref().map(Into::into),
+        server_name: TLS_SERVER_NAME.clone(),
+    });
+    let mut libretto_client = LibrettoClient::connect(
+        &BROKER_SUBSCRIBE_ADDR,
+        &BROKER_PUBLISH_ADDR,
+        tls.as_ref()
+    ).await?.with_policy(policy).with_encoding(encoding);
+    if let Some(path) = SNAPSHOT_CONFIG_PATH.as_ref() {
+        libretto_client = libretto_client.with_snapshots(SnapshotConfig::load(path)?, LxdClient::default());
+    }
+    if let Some(path) = REPLICA_PATH.as_ref() {
+        let config = SyncConfig { replica_root: Some(path.into()), ..Default::default() };
+        libretto_client = libretto_client.with_handler(ReplicaSync::new(&config));
+    }
+    let migration_config = match MIGRATION_CONFIG_PATH.as_ref() {
+        Some(path) => MigrationConfig::load(path)?,
+        None => MigrationConfig::default(),
+    };
+    libretto_client = libretto_client.with_migrations(migration_config, LxdClient::default());
+    if let Some(target) = DRY_RUN_REPORT.as_ref() {
+        libretto_client = libretto_client.with_dry_run(ReportSink::parse(target).open()?);
+    }
     let event_handler = tokio::spawn(async move {
         libretto_client.run().await?;
 
         Ok::<(), std::io::Error>(())
     });
 
-    let filesystem_publisher = FilesystemPublisher::new("127.0.0.1:5555").await?;
+    let roots = WatchRoots::from_config(&watch_config)?;
+    let queue = EventQueue::new(watch_config.queue.clone())?;
+
+    let filesystem_publisher = FilesystemPublisher::connect(&BROKER_PUBLISH_ADDR, tls.as_ref()).await?
+        .with_encoding(encoding);
     let monitor = tokio::spawn(async move {
-        let _ = watcher::monitor_directory(
-            "/home/ans/projects/sandbox/test-dir/",
+        let _ = watcher::monitor_roots(
+            roots,
+            watch_config.debounce,
+            watch_config.baseline,
+            watch_config.journal,
+            watch_config.rename,
+            queue,
             filesystem_publisher
         ).await;
     });
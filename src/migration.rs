@@ -0,0 +1,1257 @@
+use chrono::{DateTime, Utc};
+use notify::{Event, EventKind};
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::async_trait;
+
+use crate::dfs::dfs_service_client::DfsServiceClient;
+use crate::dfs::{LaunchRequest, ReplicateRequest};
+use crate::handler::VmmActionHandler;
+use crate::lxd::LxdClient;
+use crate::pubsub::{ActionOutcome, LibrettoEvent, VmmAction};
+use crate::rollup::PathMetadata;
+use crate::statics::STORAGE_PATH;
+use crate::watcher::resolver::InstancePath;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MigrationConfig {
+    /// Where migration state is persisted. Defaults to
+    /// `$STORAGE_PATH/migrations`.
+    #[serde(default)]
+    pub state_dir: Option<PathBuf>,
+    /// Catch-up rounds before the instance is frozen regardless of how much
+    /// is still changing.
+    #[serde(default = "MigrationConfig::default_max_rounds")]
+    pub max_rounds: usize,
+    /// The instance is frozen once a catch-up round has at most this many
+    /// changed paths to send.
+    #[serde(default = "MigrationConfig::default_converged_paths")]
+    pub converged_paths: usize,
+    #[serde(default = "MigrationConfig::default_round_interval_ms")]
+    pub round_interval_ms: u64,
+    #[serde(default = "MigrationConfig::default_chunk_size")]
+    pub chunk_size: usize,
+    /// How long LXD gets to freeze or stop the source instance.
+    #[serde(default = "MigrationConfig::default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+impl MigrationConfig {
+    fn default_max_rounds() -> usize {
+        5
+    }
+
+    fn default_converged_paths() -> usize {
+        100
+    }
+
+    fn default_round_interval_ms() -> u64 {
+        2_000
+    }
+
+    fn default_chunk_size() -> usize {
+        1024 * 1024
+    }
+
+    fn default_timeout_secs() -> u64 {
+        60
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let contents = std::fs::read(path.as_ref())?;
+        serde_json::from_slice(&contents).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unable to parse migration config {}: {e}", path.as_ref().display())
+            )
+        })
+    }
+
+    pub fn state_dir(&self) -> PathBuf {
+        self.state_dir.clone().unwrap_or_else(|| {
+            Path::new(STORAGE_PATH.as_str()).join("migrations")
+        })
+    }
+
+    pub fn round_interval(&self) -> Duration {
+        Duration::from_millis(self.round_interval_ms)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+}
+
+impl Default for MigrationConfig {
+    fn default() -> Self {
+        Self {
+            state_dir: None,
+            max_rounds: Self::default_max_rounds(),
+            converged_paths: Self::default_converged_paths(),
+            round_interval_ms: Self::default_round_interval_ms(),
+            chunk_size: Self::default_chunk_size(),
+            timeout_secs: Self::default_timeout_secs(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MigrationPhase {
+    Prepare,
+    BulkTransfer,
+    CatchUp,
+    Freeze,
+    FinalDelta,
+    Launch,
+    Cleanup,
+    Completed,
+    Failed(String),
+}
+
+impl MigrationPhase {
+    pub fn is_finished(&self) -> bool {
+        matches!(self, MigrationPhase::Completed | MigrationPhase::Failed(_))
+    }
+
+    /// Whether the instance may already be running on the target, so the
+    /// source must not be brought back.
+    fn launched(&self) -> bool {
+        matches!(self, MigrationPhase::Launch | MigrationPhase::Cleanup | MigrationPhase::Completed)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MigrationRequest {
+    pub instance: InstancePath,
+    /// The instance's directory in the storage pool, everything below which
+    /// is transferred.
+    pub source: PathBuf,
+    pub target_node: String,
+    /// gRPC endpoint of the target's `DfsService`, e.g. `http://10.0.0.2:50051`.
+    pub target_addr: String,
+}
+
+/// Everything needed to pick a migration back up after a restart.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MigrationState {
+    pub id: String,
+    pub request: MigrationRequest,
+    pub phase: MigrationPhase,
+    pub rounds: usize,
+    /// Set once the source has been frozen by the migration, so that a
+    /// failure thaws it and cleanup stops it.
+    pub frozen: bool,
+    pub updated_at: DateTime<Utc>,
+    /// The source metadata of every path the target has, relative to the
+    /// source directory.
+    #[serde(default)]
+    pub manifest: BTreeMap<PathBuf, PathMetadata>,
+}
+
+/// Published as a `Migrate` event on every phase transition.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MigrationProgress {
+    pub id: String,
+    pub phase: MigrationPhase,
+    pub target_node: String,
+    pub rounds: usize,
+}
+
+/// One entry of a transfer, sent as the header of a `ReplicateRequest`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferEntry {
+    /// A chunk of file contents starting at `offset`. Every chunk also sets
+    /// the file's length to `metadata.size`, so chunks can be applied again,
+    /// in any order, without losing what other chunks wrote.
+    File { path: PathBuf, metadata: PathMetadata, offset: u64 },
+    Dir { path: PathBuf, metadata: PathMetadata },
+    Symlink { path: PathBuf, target: PathBuf, metadata: PathMetadata },
+    Remove { path: PathBuf },
+}
+
+impl TransferEntry {
+    pub fn path(&self) -> &Path {
+        match self {
+            TransferEntry::File { path, .. }
+            | TransferEntry::Dir { path, .. }
+            | TransferEntry::Symlink { path, .. }
+            | TransferEntry::Remove { path } => path,
+        }
+    }
+
+    /// `[header len u32 BE][header json][data]`
+    pub fn encode(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        let header = serde_json::to_vec(self).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                e
+            )
+        })?;
+        let mut bytes = Vec::with_capacity(4 + header.len() + data.len());
+        bytes.extend_from_slice(&(header.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&header);
+        bytes.extend_from_slice(data);
+        Ok(bytes)
+    }
+
+    pub fn decode(bytes: &[u8]) -> std::io::Result<(Self, &[u8])> {
+        let invalid = |msg: String| std::io::Error::new(std::io::ErrorKind::InvalidData, msg);
+        let len_bytes: [u8; 4] = bytes.get(..4)
+            .and_then(|b| b.try_into().ok())
+            .ok_or_else(|| invalid("transfer chunk too short".to_string()))?;
+        let header_len = u32::from_be_bytes(len_bytes) as usize;
+        let header = bytes.get(4..4 + header_len)
+            .ok_or_else(|| invalid("transfer chunk header truncated".to_string()))?;
+        let entry: Self = serde_json::from_slice(header)
+            .map_err(|e| invalid(format!("unable to parse transfer chunk header: {e}")))?;
+
+        Ok((entry, &bytes[4 + header_len..]))
+    }
+
+    /// Writes the entry below `root`. Paths that would leave `root`, either
+    /// through `..` or through a symlink already below `root`, are rejected.
+    /// The source's guest controls what gets transferred, so an earlier
+    /// entry may have turned a directory into a symlink to anywhere.
+    pub fn apply(&self, root: &Path, data: &[u8]) -> std::io::Result<()> {
+        let relative = self.path();
+        if relative.components().any(|c| !matches!(c, Component::Normal(_))) {
+            return Err(refused(relative))
+        }
+        let dest = root.join(relative);
+
+        if let TransferEntry::Remove { .. } = self {
+            // Below a symlink or a file nothing by this name exists in
+            // `root`, so there is nothing to remove.
+            if !parents_are_dirs(root, relative)? {
+                log::debug!("nothing to remove at {}", relative.display());
+                return Ok(())
+            }
+        } else {
+            std::fs::create_dir_all(root)?;
+            create_parents(root, relative)?;
+        }
+
+        match self {
+            TransferEntry::Remove { .. } => {
+                let result = match std::fs::symlink_metadata(&dest) {
+                    Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(&dest),
+                    Ok(_) => std::fs::remove_file(&dest),
+                    Err(e) => Err(e),
+                };
+                match result {
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                    result => result,
+                }
+            }
+            TransferEntry::Dir { metadata, .. } => {
+                if let Ok(existing) = std::fs::symlink_metadata(&dest) {
+                    if !existing.is_dir() {
+                        std::fs::remove_file(&dest)?;
+                    }
+                }
+                std::fs::create_dir_all(&dest)?;
+                set_metadata(&dest, metadata)
+            }
+            TransferEntry::Symlink { target, metadata, .. } => {
+                if let Ok(existing) = std::fs::symlink_metadata(&dest) {
+                    if existing.is_dir() {
+                        std::fs::remove_dir_all(&dest)?;
+                    } else {
+                        std::fs::remove_file(&dest)?;
+                    }
+                }
+                std::os::unix::fs::symlink(target, &dest)?;
+                set_metadata(&dest, metadata)
+            }
+            TransferEntry::File { metadata, offset, .. } => {
+                if let Ok(existing) = std::fs::symlink_metadata(&dest) {
+                    let replaceable = existing.is_dir() || existing.file_type().is_symlink();
+                    match (replaceable, *offset == 0) {
+                        (true, true) if existing.is_dir() => std::fs::remove_dir_all(&dest)?,
+                        (true, true) => std::fs::remove_file(&dest)?,
+                        // A later chunk must not be written through a
+                        // symlink that replaced the file.
+                        (true, false) => return Err(refused(relative)),
+                        (false, _) => {}
+                    }
+                }
+                let mut file = std::fs::OpenOptions::new()
+                    .create(true)
+                    .write(true)
+                    .truncate(false)
+                    .open(&dest)?;
+                file.seek(SeekFrom::Start(*offset))?;
+                file.write_all(data)?;
+                file.set_len(metadata.size)?;
+                set_metadata(&dest, metadata)
+            }
+        }
+    }
+}
+
+fn refused(relative: &Path) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("refusing transfer path {}", relative.display())
+    )
+}
+
+/// Creates the directories leading to `relative` below `root` one at a time,
+/// failing if any of them exists as a symlink or anything but a directory.
+fn create_parents(root: &Path, relative: &Path) -> std::io::Result<()> {
+    let Some(parent) = relative.parent() else {
+        return Ok(())
+    };
+    let mut dir = root.to_path_buf();
+    for component in parent.components() {
+        dir.push(component);
+        match std::fs::symlink_metadata(&dir) {
+            Ok(metadata) if metadata.is_dir() => {}
+            Ok(_) => return Err(refused(relative)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => std::fs::create_dir(&dir)?,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Whether every directory leading to `relative` below `root` exists as a
+/// real directory.
+fn parents_are_dirs(root: &Path, relative: &Path) -> std::io::Result<bool> {
+    let Some(parent) = relative.parent() else {
+        return Ok(true)
+    };
+    let mut dir = root.to_path_buf();
+    for component in parent.components() {
+        dir.push(component);
+        match std::fs::symlink_metadata(&dir) {
+            Ok(metadata) if metadata.is_dir() => {}
+            Ok(_) => return Ok(false),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+fn set_metadata(path: &Path, metadata: &PathMetadata) -> std::io::Result<()> {
+    if let Err(e) = std::os::unix::fs::lchown(path, Some(metadata.uid), Some(metadata.gid)) {
+        log::debug!("unable to set owner of {}: {e}", path.display());
+    }
+    let is_symlink = std::fs::symlink_metadata(path)?.file_type().is_symlink();
+    if !is_symlink {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(metadata.mode))?;
+    }
+    let time = |ns: i64| filetime::FileTime::from_unix_time(ns.div_euclid(1_000_000_000), ns.rem_euclid(1_000_000_000) as u32);
+    filetime::set_symlink_file_times(path, time(metadata.atime_ns), time(metadata.mtime_ns))
+}
+
+/// Whether the target's copy is still current. Access times are ignored,
+/// reading a file is not a change.
+fn unchanged(current: &PathMetadata, sent: &PathMetadata) -> bool {
+    current.mode == sent.mode
+        && current.uid == sent.uid
+        && current.gid == sent.gid
+        && current.size == sent.size
+        && current.mtime_ns == sent.mtime_ns
+}
+
+/// Stats every path below `source` without following symlinks.
+fn scan(source: &Path) -> std::io::Result<BTreeMap<PathBuf, PathMetadata>> {
+    let mut found = BTreeMap::new();
+    let mut dirs = vec![source.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let metadata = match PathMetadata::stat(&path) {
+                Ok(metadata) => metadata,
+                // Removed since the directory was read.
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            if std::fs::symlink_metadata(&path).map(|m| m.is_dir()).unwrap_or(false) {
+                dirs.push(path.clone());
+            }
+            if let Ok(relative) = path.strip_prefix(source) {
+                found.insert(relative.to_path_buf(), metadata);
+            }
+        }
+    }
+
+    Ok(found)
+}
+
+/// Paths whose source differs from what the target has, including ones
+/// removed from the source.
+fn diff(source: &Path, manifest: &BTreeMap<PathBuf, PathMetadata>) -> std::io::Result<BTreeSet<PathBuf>> {
+    let current = scan(source)?;
+    let mut changed: BTreeSet<PathBuf> = current.iter()
+        .filter(|(path, metadata)| !manifest.get(*path).is_some_and(|sent| unchanged(metadata, sent)))
+        .map(|(path, _)| path.clone())
+        .collect();
+    changed.extend(manifest.keys().filter(|path| !current.contains_key(*path)).cloned());
+
+    Ok(changed)
+}
+
+/// Reads the paths and queues their entries on `chunks`, returning what was
+/// sent for each so the manifest can be updated once the target has it.
+fn produce(
+    source: &Path,
+    paths: &BTreeSet<PathBuf>,
+    instance_name: &str,
+    chunk_size: usize,
+    chunks: &tokio::sync::mpsc::Sender<ReplicateRequest>
+) -> std::io::Result<Vec<(PathBuf, Option<PathMetadata>)>> {
+    let closed = || std::io::Error::new(std::io::ErrorKind::BrokenPipe, "transfer stream closed");
+    let send = |entry: TransferEntry, data: &[u8]| -> std::io::Result<()> {
+        let request = ReplicateRequest {
+            instance_name: instance_name.to_string(),
+            instance_data: entry.encode(data)?,
+        };
+        chunks.blocking_send(request).map_err(|_| closed())
+    };
+
+    let mut sent = Vec::with_capacity(paths.len());
+    for relative in paths {
+        let path = source.join(relative);
+        let metadata = match std::fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                send(TransferEntry::Remove { path: relative.clone() }, &[])?;
+                sent.push((relative.clone(), None));
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let stat = PathMetadata::stat(&path)?;
+
+        if metadata.is_dir() {
+            send(TransferEntry::Dir { path: relative.clone(), metadata: stat.clone() }, &[])?;
+        } else if metadata.file_type().is_symlink() {
+            let target = std::fs::read_link(&path)?;
+            send(TransferEntry::Symlink { path: relative.clone(), target, metadata: stat.clone() }, &[])?;
+        } else if metadata.is_file() {
+            let mut file = std::fs::File::open(&path)?;
+            let mut buffer = vec![0; chunk_size.max(1)];
+            let mut offset = 0u64;
+            loop {
+                let n = file.read(&mut buffer)?;
+                if n == 0 && offset > 0 {
+                    break;
+                }
+                let entry = TransferEntry::File { path: relative.clone(), metadata: stat.clone(), offset };
+                send(entry, &buffer[..n])?;
+                if n == 0 {
+                    break;
+                }
+                offset += n as u64;
+            }
+        } else {
+            log::info!("not migrating special file {}", path.display());
+            continue;
+        }
+        sent.push((relative.clone(), Some(stat)));
+    }
+
+    Ok(sent)
+}
+
+struct Tracked {
+    source: PathBuf,
+    dirty: BTreeSet<PathBuf>,
+}
+
+/// Moves instances to another node through persisted phases:
+///
+/// 1. `Prepare` checks the source and the target.
+/// 2. `BulkTransfer` sends everything below the instance directory.
+/// 3. `CatchUp` resends what the watcher reported as changed, in rounds,
+///    until little is left to send.
+/// 4. `Freeze` freezes the source instance in LXD.
+/// 5. `FinalDelta` sends the last changes, taken from both the watcher and a
+///    scan of the frozen instance, since events can still be in flight.
+/// 6. `Launch` asks the target to start the instance.
+/// 7. `Cleanup` stops the source.
+///
+/// State is written before each phase starts, so a restarted coordinator
+/// picks every unfinished migration back up with `resume`. Every phase is
+/// safe to repeat, and the target's `Launch` must be as well. The
+/// coordinator also has to be registered as a handler to see changes;
+/// `LibrettoClient::with_migrations` does both.
+#[derive(Clone)]
+pub struct MigrationCoordinator {
+    config: MigrationConfig,
+    lxd: LxdClient,
+    tracked: Arc<Mutex<HashMap<String, Tracked>>>,
+    outcomes: UnboundedSender<LibrettoEvent>,
+}
+
+impl MigrationCoordinator {
+    pub fn new(config: MigrationConfig, lxd: LxdClient, outcomes: UnboundedSender<LibrettoEvent>) -> Self {
+        Self { config, lxd, tracked: Arc::new(Mutex::new(HashMap::new())), outcomes }
+    }
+
+    pub fn config(&self) -> &MigrationConfig {
+        &self.config
+    }
+
+    fn state_path(&self, id: &str) -> PathBuf {
+        self.config.state_dir().join(format!("{id}.json"))
+    }
+
+    fn persist(&self, state: &MigrationState) -> std::io::Result<()> {
+        let dir = self.config.state_dir();
+        std::fs::create_dir_all(&dir)?;
+        let contents = serde_json::to_vec(state).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                e
+            )
+        })?;
+        let path = self.state_path(&state.id);
+        let tmp = path.with_extension("json.tmp");
+        let mut file = std::fs::File::create(&tmp)?;
+        file.write_all(&contents)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, &path)
+    }
+
+    /// Every migration with persisted state, finished or not.
+    pub fn list(&self) -> std::io::Result<Vec<MigrationState>> {
+        let dir = self.config.state_dir();
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut states = Vec::new();
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            let contents = std::fs::read(&path)?;
+            match serde_json::from_slice::<MigrationState>(&contents) {
+                Ok(state) => states.push(state),
+                Err(e) => log::error!("unable to parse migration state {}: {e}", path.display()),
+            }
+        }
+        states.sort_by_key(|state| state.updated_at);
+
+        Ok(states)
+    }
+
+    /// Persists a new migration and starts driving it in the background,
+    /// returning its id.
+    pub fn start(&self, request: MigrationRequest) -> std::io::Result<String> {
+        let name = request.instance.qualified_name();
+        let running = self.list()?.into_iter()
+            .any(|state| !state.phase.is_finished() && state.request.instance.qualified_name() == name);
+        if running {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{name} is already being migrated")
+            ))
+        }
+
+        let now = Utc::now();
+        let state = MigrationState {
+            id: format!("{}-{}", name, now.format("%Y%m%d%H%M%S")),
+            request,
+            phase: MigrationPhase::Prepare,
+            rounds: 0,
+            frozen: false,
+            updated_at: now,
+            manifest: BTreeMap::new(),
+        };
+        self.persist(&state)?;
+        let id = state.id.clone();
+        self.spawn(state, false);
+
+        Ok(id)
+    }
+
+    /// Restarts every unfinished migration from its last persisted phase.
+    pub fn resume(&self) -> std::io::Result<usize> {
+        let pending: Vec<MigrationState> = self.list()?.into_iter()
+            .filter(|state| !state.phase.is_finished())
+            .collect();
+        for state in &pending {
+            log::info!("resuming migration {} in phase {:?}", state.id, state.phase);
+        }
+        let count = pending.len();
+        for state in pending {
+            self.spawn(state, true);
+        }
+
+        Ok(count)
+    }
+
+    fn spawn(&self, state: MigrationState, resumed: bool) {
+        let coordinator = self.clone();
+        tokio::spawn(async move {
+            coordinator.drive(state, resumed).await;
+        });
+    }
+
+    async fn drive(&self, mut state: MigrationState, resumed: bool) {
+        // Changes made while no coordinator was running were not tracked, so
+        // the first round after a restart compares against the manifest.
+        let mut rescan = resumed;
+        self.track(&state);
+
+        while !state.phase.is_finished() {
+            if let Err(e) = self.step(&mut state, &mut rescan).await {
+                log::error!("migration {} failed in phase {:?}: {e}", state.id, state.phase);
+                self.fail(&mut state, e).await;
+            }
+        }
+
+        self.untrack(&state.id);
+    }
+
+    async fn step(&self, state: &mut MigrationState, rescan: &mut bool) -> std::io::Result<()> {
+        match state.phase {
+            MigrationPhase::Prepare => {
+                let source = state.request.source.clone();
+                if !tokio::fs::metadata(&source).await?.is_dir() {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        format!("{} is not an instance directory", source.display())
+                    ))
+                }
+                self.lxd(state).instance(&state.request.instance.name).await?;
+                self.connect(state).await?;
+                self.advance(state, MigrationPhase::BulkTransfer)
+            }
+            MigrationPhase::BulkTransfer => {
+                // Anything changed from here on is caught up on later.
+                self.take_dirty(&state.id);
+                let source = state.request.source.clone();
+                let all = blocking(move || scan(&source)).await?;
+                let paths = all.into_keys().collect();
+                self.transfer(state, paths).await?;
+                *rescan = false;
+                self.advance(state, MigrationPhase::CatchUp)
+            }
+            MigrationPhase::CatchUp => {
+                tokio::time::sleep(self.config.round_interval()).await;
+                let mut paths = self.take_dirty(&state.id);
+                if std::mem::take(rescan) {
+                    paths.extend(self.diff(state).await?);
+                }
+                let sent = paths.len();
+                self.transfer(state, paths).await?;
+                state.rounds += 1;
+
+                if sent <= self.config.converged_paths || state.rounds >= self.config.max_rounds {
+                    self.advance(state, MigrationPhase::Freeze)
+                } else {
+                    self.persist(state)
+                }
+            }
+            MigrationPhase::Freeze => {
+                let lxd = self.lxd(state);
+                let name = &state.request.instance.name;
+                let status = lxd.instance_state(name).await?.status;
+                if status == "Running" {
+                    let operation = lxd.update_state(name, "freeze", false).await?;
+                    lxd.wait(&operation.id, self.config.timeout()).await?;
+                    state.frozen = true;
+                } else if status == "Frozen" {
+                    state.frozen = true;
+                }
+                self.advance(state, MigrationPhase::FinalDelta)
+            }
+            MigrationPhase::FinalDelta => {
+                let mut paths = self.take_dirty(&state.id);
+                paths.extend(self.diff(state).await?);
+                self.transfer(state, paths).await?;
+                self.advance(state, MigrationPhase::Launch)
+            }
+            MigrationPhase::Launch => {
+                let mut client = self.connect(state).await?;
+                let request = LaunchRequest {
+                    instance_name: state.request.instance.qualified_name(),
+                    target_node: state.request.target_node.clone(),
+                };
+                let response = client.launch(request).await.map_err(|e| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        format!("launch on {} failed: {e}", state.request.target_node)
+                    )
+                })?;
+                if !response.into_inner().success {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        format!("{} refused to launch the instance", state.request.target_node)
+                    ))
+                }
+                self.advance(state, MigrationPhase::Cleanup)
+            }
+            MigrationPhase::Cleanup => {
+                if state.frozen {
+                    let lxd = self.lxd(state);
+                    let operation = lxd.update_state(&state.request.instance.name, "stop", true).await?;
+                    lxd.wait(&operation.id, self.config.timeout()).await?;
+                }
+                self.advance(state, MigrationPhase::Completed)
+            }
+            MigrationPhase::Completed | MigrationPhase::Failed(_) => Ok(()),
+        }
+    }
+
+    async fn fail(&self, state: &mut MigrationState, error: std::io::Error) {
+        // Once the target may be running the instance, bringing the source
+        // back would leave two copies running.
+        if state.frozen && !state.phase.launched() {
+            let lxd = self.lxd(state);
+            match lxd.update_state(&state.request.instance.name, "unfreeze", false).await {
+                Ok(operation) => {
+                    if let Err(e) = lxd.wait(&operation.id, self.config.timeout()).await {
+                        log::error!("unable to unfreeze {}: {e}", state.request.instance.qualified_name());
+                    }
+                }
+                Err(e) => log::error!("unable to unfreeze {}: {e}", state.request.instance.qualified_name()),
+            }
+            state.frozen = false;
+        }
+
+        let reason = format!("{:?}: {error}", state.phase);
+        if let Err(e) = self.advance(state, MigrationPhase::Failed(reason)) {
+            log::error!("unable to record failure of migration {}: {e}", state.id);
+        }
+    }
+
+    /// Records and publishes the next phase.
+    fn advance(&self, state: &mut MigrationState, phase: MigrationPhase) -> std::io::Result<()> {
+        log::info!("migration {} entering phase {:?}", state.id, phase);
+        state.phase = phase;
+        state.updated_at = Utc::now();
+        // Progress still goes out if the state cannot be written.
+        let persisted = self.persist(state);
+        self.publish(state);
+        persisted
+    }
+
+    fn publish(&self, state: &MigrationState) {
+        let mut event = Event::new(EventKind::Other);
+        event.paths.push(state.request.source.clone());
+        let progress = MigrationProgress {
+            id: state.id.clone(),
+            phase: state.phase.clone(),
+            target_node: state.request.target_node.clone(),
+            rounds: state.rounds,
+        };
+        let mut event = LibrettoEvent::new(event, VmmAction::Migrate, Some(state.request.instance.clone()))
+            .with_migration(progress);
+        match &state.phase {
+            MigrationPhase::Completed => {
+                event = event.with_outcome(ActionOutcome::completed(Some(state.id.clone()), Some(state.request.target_node.clone())));
+            }
+            MigrationPhase::Failed(reason) => {
+                event = event.with_outcome(ActionOutcome::failed(reason.clone(), Some(state.id.clone()), Some(state.request.target_node.clone())));
+            }
+            _ => {}
+        }
+        if self.outcomes.send(event).is_err() {
+            log::error!("unable to report migration progress, client has stopped");
+        }
+    }
+
+    fn lxd(&self, state: &MigrationState) -> LxdClient {
+        match &state.request.instance.project {
+            Some(project) => self.lxd.clone().with_project(project),
+            None => self.lxd.clone(),
+        }
+    }
+
+    async fn connect(&self, state: &MigrationState) -> std::io::Result<DfsServiceClient<tonic::transport::Channel>> {
+        DfsServiceClient::connect(state.request.target_addr.clone()).await.map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::ConnectionRefused,
+                format!("unable to connect to {}: {e}", state.request.target_addr)
+            )
+        })
+    }
+
+    async fn diff(&self, state: &MigrationState) -> std::io::Result<BTreeSet<PathBuf>> {
+        let source = state.request.source.clone();
+        let manifest = state.manifest.clone();
+        blocking(move || diff(&source, &manifest)).await
+    }
+
+    /// Sends the paths to the target and records what it now has.
+    async fn transfer(&self, state: &mut MigrationState, paths: BTreeSet<PathBuf>) -> std::io::Result<()> {
+        if paths.is_empty() {
+            return Ok(())
+        }
+        log::info!("migration {} sending {} paths", state.id, paths.len());
+
+        let mut client = self.connect(state).await?;
+        let (chunks, stream) = tokio::sync::mpsc::channel(16);
+        let source = state.request.source.clone();
+        let instance_name = state.request.instance.qualified_name();
+        let chunk_size = self.config.chunk_size;
+        let producer = tokio::task::spawn_blocking(move || {
+            produce(&source, &paths, &instance_name, chunk_size, &chunks)
+        });
+
+        let response = client.replicate(ReceiverStream::new(stream)).await;
+        let sent = producer.await.map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("transfer panicked: {e}")
+            )
+        })?;
+        let response = response.map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("transfer to {} failed: {e}", state.request.target_node)
+            )
+        })?;
+        if !response.into_inner().success {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("{} rejected the transfer", state.request.target_node)
+            ))
+        }
+
+        for (path, metadata) in sent? {
+            match metadata {
+                Some(metadata) => state.manifest.insert(path, metadata),
+                None => state.manifest.remove(&path),
+            };
+        }
+
+        Ok(())
+    }
+
+    fn track(&self, state: &MigrationState) {
+        if let Ok(mut tracked) = self.tracked.lock() {
+            tracked.entry(state.id.clone()).or_insert_with(|| Tracked {
+                source: state.request.source.clone(),
+                dirty: BTreeSet::new(),
+            });
+        }
+    }
+
+    fn untrack(&self, id: &str) {
+        if let Ok(mut tracked) = self.tracked.lock() {
+            tracked.remove(id);
+        }
+    }
+
+    fn take_dirty(&self, id: &str) -> BTreeSet<PathBuf> {
+        let Ok(mut tracked) = self.tracked.lock() else {
+            return BTreeSet::new()
+        };
+        tracked.get_mut(id).map(|t| std::mem::take(&mut t.dirty)).unwrap_or_default()
+    }
+
+    /// Marks every path of the event that lies in a migrating instance.
+    fn record(&self, event: &LibrettoEvent) {
+        let mut paths: Vec<&PathBuf> = event.event().paths.iter().collect();
+        if let Some(changes) = event.changes() {
+            paths.extend(changes.paths());
+        }
+        if let VmmAction::Move { from, to } = event.action() {
+            paths.push(from);
+            paths.push(to);
+        }
+
+        let Ok(mut tracked) = self.tracked.lock() else {
+            return
+        };
+        for tracked in tracked.values_mut() {
+            for path in &paths {
+                if let Ok(relative) = path.strip_prefix(&tracked.source) {
+                    if relative.as_os_str().is_empty() {
+                        continue;
+                    }
+                    tracked.dirty.insert(relative.to_path_buf());
+                }
+            }
+        }
+    }
+}
+
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> std::io::Result<T> + Send + 'static
+) -> std::io::Result<T> {
+    tokio::task::spawn_blocking(f).await.map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::Other,
+            format!("blocking task panicked: {e}")
+        )
+    })?
+}
+
+/// Only tracks changes; migrations are started with `start`.
+#[async_trait]
+impl VmmActionHandler for MigrationCoordinator {
+    async fn copy(&self, event: &LibrettoEvent) -> std::io::Result<Option<ActionOutcome>> {
+        self.record(event);
+        Ok(None)
+    }
+
+    async fn rollup(&self, event: &LibrettoEvent) -> std::io::Result<Option<ActionOutcome>> {
+        self.record(event);
+        Ok(None)
+    }
+
+    async fn other(&self, event: &LibrettoEvent) -> std::io::Result<Option<ActionOutcome>> {
+        self.record(event);
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::ffi::OsStringExt;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tonic::{Request, Response, Status};
+
+    use crate::dfs::dfs_service_server::{DfsService, DfsServiceServer};
+    use crate::dfs::{HeartbeatRequest, HeartbeatResponse, LaunchResponse, ReplicateResponse, StoreRequest, StoreResponse};
+    use crate::lxd::tests::{operation, serve, sync, Requests};
+    use crate::watcher::resolver::InstanceKind;
+
+    fn metadata(mode: u32) -> PathMetadata {
+        PathMetadata { mode, uid: 0, gid: 0, size: 0, mtime_ns: 1_000_000_000, atime_ns: 1_000_000_000 }
+    }
+
+    fn file(path: &str, offset: u64, size: u64) -> TransferEntry {
+        TransferEntry::File { path: path.into(), metadata: PathMetadata { size, ..metadata(0o644) }, offset }
+    }
+
+    #[test]
+    fn apply_rejects_paths_leaving_the_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        for path in ["../escaped", "a/../../escaped", "/escaped"] {
+            let err = file(path, 0, 4).apply(&root, b"data").unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData, "{path}");
+        }
+        assert!(!dir.path().join("escaped").exists());
+    }
+
+    #[test]
+    fn apply_does_not_follow_symlinked_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        let outside = dir.path().join("etc");
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::write(outside.join("passwd"), b"host").unwrap();
+
+        // The guest replaced directory `x` with a link to the host's /etc.
+        let link = TransferEntry::Symlink { path: "x".into(), target: outside.clone(), metadata: metadata(0o777) };
+        link.apply(&root, &[]).unwrap();
+
+        TransferEntry::Remove { path: "x/passwd".into() }.apply(&root, &[]).unwrap();
+        assert_eq!(std::fs::read(outside.join("passwd")).unwrap(), b"host");
+
+        let err = file("x/passwd", 0, 5).apply(&root, b"guest").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        let dir_entry = TransferEntry::Dir { path: "x/sub".into(), metadata: metadata(0o755) };
+        assert!(dir_entry.apply(&root, &[]).is_err());
+        assert_eq!(std::fs::read(outside.join("passwd")).unwrap(), b"host");
+        assert!(!outside.join("sub").exists());
+    }
+
+    #[test]
+    fn apply_does_not_write_later_chunks_through_a_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        let outside = dir.path().join("target");
+        std::fs::write(&outside, b"host").unwrap();
+
+        file("f", 0, 5).apply(&root, b"first").unwrap();
+        let link = TransferEntry::Symlink { path: "f".into(), target: outside.clone(), metadata: metadata(0o777) };
+        link.apply(&root, &[]).unwrap();
+
+        assert!(file("f", 5, 11).apply(&root, b"second").is_err());
+        assert_eq!(std::fs::read(&outside).unwrap(), b"host");
+
+        // A new first chunk replaces the link instead.
+        file("f", 0, 5).apply(&root, b"again").unwrap();
+        assert_eq!(std::fs::read(root.join("f")).unwrap(), b"again");
+        assert_eq!(std::fs::read(&outside).unwrap(), b"host");
+    }
+
+    /// A source instance directory with a nested file, an empty file, a
+    /// file spanning several chunks and a symlink.
+    fn source_tree(source: &Path) {
+        std::fs::create_dir_all(source.join("rootfs/etc")).unwrap();
+        std::fs::write(source.join("rootfs/etc/hosts"), b"127.0.0.1 localhost\n").unwrap();
+        std::fs::write(source.join("rootfs/empty"), b"").unwrap();
+        std::fs::write(source.join("backup.yaml"), b"name: c1\n").unwrap();
+        std::fs::set_permissions(source.join("backup.yaml"), std::fs::Permissions::from_mode(0o600)).unwrap();
+        std::os::unix::fs::symlink("etc/hosts", source.join("rootfs/hosts")).unwrap();
+    }
+
+    /// Everything that should match between source and target: the type and
+    /// mode of every path, file contents and mtimes, and link targets.
+    /// Directory mtimes change as their entries are written, so they are
+    /// left out.
+    fn tree(root: &Path) -> BTreeMap<PathBuf, (u32, Option<i64>, Vec<u8>)> {
+        scan(root).unwrap().into_iter().map(|(relative, metadata)| {
+            let path = root.join(&relative);
+            let file_type = std::fs::symlink_metadata(&path).unwrap().file_type();
+            let (mtime, contents) = if file_type.is_symlink() {
+                (None, std::fs::read_link(&path).unwrap().into_os_string().into_vec())
+            } else if file_type.is_dir() {
+                (None, Vec::new())
+            } else {
+                (Some(metadata.mtime_ns), std::fs::read(&path).unwrap())
+            };
+            (relative, (metadata.mode, mtime, contents))
+        }).collect()
+    }
+
+    /// The chunks `produce` sends for every path below `source`.
+    fn chunks(source: &Path, chunk_size: usize) -> Vec<Vec<u8>> {
+        let paths = scan(source).unwrap().into_keys().collect();
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(1024);
+        produce(source, &paths, "c1", chunk_size, &sender).unwrap();
+        drop(sender);
+        let mut chunks = Vec::new();
+        while let Some(request) = receiver.blocking_recv() {
+            chunks.push(request.instance_data);
+        }
+        chunks
+    }
+
+    fn apply_all(root: &Path, chunks: &[Vec<u8>]) {
+        for chunk in chunks {
+            let (entry, data) = TransferEntry::decode(chunk).unwrap();
+            entry.apply(root, data).unwrap();
+        }
+    }
+
+    #[test]
+    fn entries_round_trip_through_encoding() {
+        let entries = [
+            file("rootfs/etc/hosts", 4096, 8192),
+            TransferEntry::Dir { path: "rootfs".into(), metadata: metadata(0o755) },
+            TransferEntry::Symlink { path: "rootfs/hosts".into(), target: "etc/hosts".into(), metadata: metadata(0o777) },
+            TransferEntry::Remove { path: "rootfs/gone".into() },
+        ];
+        for entry in entries {
+            let bytes = entry.encode(b"data").unwrap();
+            let (decoded, data) = TransferEntry::decode(&bytes).unwrap();
+            assert_eq!(decoded, entry);
+            assert_eq!(data, b"data");
+        }
+
+        assert!(TransferEntry::decode(&[0, 0]).is_err());
+        assert!(TransferEntry::decode(&[0, 0, 0, 9, b'{']).is_err());
+    }
+
+    #[test]
+    fn produced_chunks_recreate_the_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, target) = (dir.path().join("source"), dir.path().join("target"));
+        source_tree(&source);
+
+        let chunks = chunks(&source, 4);
+        assert!(chunks.len() > 5, "files were not split into chunks");
+        apply_all(&target, &chunks);
+        assert_eq!(tree(&target), tree(&source));
+    }
+
+    #[test]
+    fn replaying_chunks_is_idempotent() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, target) = (dir.path().join("source"), dir.path().join("target"));
+        source_tree(&source);
+        let chunks = chunks(&source, 4);
+
+        // An interrupted stream is resent from the start, and single chunks
+        // may arrive twice or out of order.
+        apply_all(&target, &chunks[..chunks.len() / 2]);
+        apply_all(&target, &chunks);
+        apply_all(&target, &chunks[1..3]);
+        apply_all(&target, &chunks);
+        assert_eq!(tree(&target), tree(&source));
+
+        let hosts: Vec<_> = chunks.iter()
+            .map(|chunk| TransferEntry::decode(chunk).unwrap())
+            .filter(|(entry, _)| entry.path() == Path::new("rootfs/etc/hosts"))
+            .map(|(entry, data)| (entry, data.to_vec()))
+            .collect();
+        for (entry, data) in hosts.iter().rev() {
+            entry.apply(&target, data).unwrap();
+        }
+        assert_eq!(std::fs::read(target.join("rootfs/etc/hosts")).unwrap(), b"127.0.0.1 localhost\n");
+    }
+
+    #[test]
+    fn diff_finds_changes_since_the_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        source_tree(&source);
+        let manifest = scan(&source).unwrap();
+        assert!(diff(&source, &manifest).unwrap().is_empty());
+
+        // Reading a file is not a change.
+        let hosts = source.join("rootfs/etc/hosts");
+        let mtime = filetime::FileTime::from_last_modification_time(&std::fs::metadata(&hosts).unwrap());
+        filetime::set_file_times(&hosts, filetime::FileTime::from_unix_time(1, 0), mtime).unwrap();
+        assert!(diff(&source, &manifest).unwrap().is_empty());
+
+        std::fs::write(&hosts, b"10.0.0.1 db\n").unwrap();
+        std::fs::remove_file(source.join("backup.yaml")).unwrap();
+        std::fs::write(source.join("rootfs/new"), b"").unwrap();
+        std::fs::set_permissions(source.join("rootfs/empty"), std::fs::Permissions::from_mode(0o600)).unwrap();
+        let changed: Vec<PathBuf> = diff(&source, &manifest).unwrap().into_iter().collect();
+        // `rootfs` itself changed by gaining an entry.
+        assert_eq!(changed, ["backup.yaml", "rootfs", "rootfs/empty", "rootfs/etc/hosts", "rootfs/new"].map(PathBuf::from));
+    }
+
+    /// The target node: applies transfers below `root` and counts launches.
+    #[derive(Clone)]
+    struct Target {
+        root: PathBuf,
+        launches: Arc<AtomicUsize>,
+    }
+
+    #[tonic::async_trait]
+    impl DfsService for Target {
+        async fn store(&self, _request: Request<tonic::Streaming<StoreRequest>>) -> Result<Response<StoreResponse>, Status> {
+            Ok(Response::new(StoreResponse { success: true }))
+        }
+
+        async fn launch(&self, _request: Request<LaunchRequest>) -> Result<Response<LaunchResponse>, Status> {
+            self.launches.fetch_add(1, Ordering::SeqCst);
+            Ok(Response::new(LaunchResponse { success: true }))
+        }
+
+        async fn heartbeat(&self, _request: Request<HeartbeatRequest>) -> Result<Response<HeartbeatResponse>, Status> {
+            Ok(Response::new(HeartbeatResponse { success: true }))
+        }
+
+        async fn replicate(&self, request: Request<tonic::Streaming<ReplicateRequest>>) -> Result<Response<ReplicateResponse>, Status> {
+            let mut stream = request.into_inner();
+            while let Some(chunk) = stream.message().await? {
+                let (entry, data) = TransferEntry::decode(&chunk.instance_data).map_err(|e| Status::internal(e.to_string()))?;
+                entry.apply(&self.root, data).map_err(|e| Status::internal(e.to_string()))?;
+            }
+            Ok(Response::new(ReplicateResponse { success: true }))
+        }
+    }
+
+    async fn target(root: PathBuf) -> (String, Arc<AtomicUsize>) {
+        let launches = Arc::new(AtomicUsize::new(0));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = format!("http://{}", listener.local_addr().unwrap());
+        let incoming = tonic::transport::server::TcpIncoming::from_listener(listener, true, None).unwrap();
+        let service = DfsServiceServer::new(Target { root, launches: launches.clone() });
+        tokio::spawn(tonic::transport::Server::builder().add_service(service).serve_with_incoming(incoming));
+        (addr, launches)
+    }
+
+    fn lxd() -> (tempfile::TempDir, LxdClient, Requests) {
+        serve(|request| match request {
+            "GET /1.0/instances/c1" => sync(serde_json::json!({
+                "name": "c1", "status": "Running", "status_code": 103, "type": "container",
+            })),
+            "GET /1.0/instances/c1/state" => sync(serde_json::json!({ "status": "Running", "status_code": 103 })),
+            "PUT /1.0/instances/c1/state" => sync(operation("Running", 103, "")),
+            _ => sync(operation("Success", 200, "")),
+        })
+    }
+
+    #[tokio::test]
+    async fn migrations_resume_from_every_phase() {
+        let phases = [
+            MigrationPhase::Prepare,
+            MigrationPhase::BulkTransfer,
+            MigrationPhase::CatchUp,
+            MigrationPhase::Freeze,
+            MigrationPhase::FinalDelta,
+            MigrationPhase::Launch,
+            MigrationPhase::Cleanup,
+        ];
+        for phase in phases {
+            let dir = tempfile::tempdir().unwrap();
+            let (source, target_root) = (dir.path().join("source"), dir.path().join("target"));
+            source_tree(&source);
+            let (target_addr, launches) = target(target_root.clone()).await;
+            let (_lxd_dir, lxd, requests) = lxd();
+
+            let config = MigrationConfig {
+                state_dir: Some(dir.path().join("migrations")),
+                round_interval_ms: 10,
+                chunk_size: 4,
+                ..Default::default()
+            };
+            let (outcomes, mut progress) = tokio::sync::mpsc::unbounded_channel();
+            let coordinator = MigrationCoordinator::new(config, lxd, outcomes);
+
+            // A restart that happened before the phase got anywhere; later
+            // phases find the frozen source they left behind.
+            let launched = phase.launched();
+            let state = MigrationState {
+                id: "c1-1".to_string(),
+                request: MigrationRequest {
+                    instance: InstancePath {
+                        name: "c1".to_string(),
+                        project: None,
+                        kind: InstanceKind::Container,
+                        snapshot: None,
+                        path: PathBuf::from("/"),
+                        in_rootfs: false,
+                    },
+                    source: source.clone(),
+                    target_node: "node2".to_string(),
+                    target_addr,
+                },
+                phase: phase.clone(),
+                rounds: 0,
+                frozen: matches!(phase, MigrationPhase::FinalDelta) || launched,
+                updated_at: Utc::now(),
+                manifest: BTreeMap::new(),
+            };
+            coordinator.persist(&state).unwrap();
+            assert_eq!(coordinator.resume().unwrap(), 1);
+
+            let finished = loop {
+                let event = tokio::time::timeout(Duration::from_secs(10), progress.recv()).await.unwrap().unwrap();
+                let progress = event.migration().clone().unwrap();
+                if progress.phase.is_finished() {
+                    break progress.phase
+                }
+            };
+            assert_eq!(finished, MigrationPhase::Completed, "resumed in {phase:?}");
+            assert_eq!(coordinator.list().unwrap()[0].phase, MigrationPhase::Completed);
+            assert_eq!(coordinator.resume().unwrap(), 0);
+
+            // Phases up to the final delta send whatever the target lacks,
+            // and everything up to the launch launches exactly once.
+            if !launched {
+                assert_eq!(tree(&target_root), tree(&source), "resumed in {phase:?}");
+            }
+            let expected_launches = usize::from(phase != MigrationPhase::Cleanup);
+            assert_eq!(launches.load(Ordering::SeqCst), expected_launches, "resumed in {phase:?}");
+            let stops = requests.lock().unwrap().iter().filter(|r| *r == "PUT /1.0/instances/c1/state").count();
+            let freezes_and_stops = match phase {
+                MigrationPhase::FinalDelta | MigrationPhase::Launch | MigrationPhase::Cleanup => 1,
+                _ => 2,
+            };
+            assert_eq!(stops, freezes_and_stops, "resumed in {phase:?}");
+        }
+    }
+}
@@ -0,0 +1,254 @@
+use notify::Event;
+use serde::{Serialize, Deserialize};
+use std::path::Path;
+
+use crate::pubsub::{kind_name, VmmAction};
+use crate::watcher::resolver::InstancePath;
+use crate::watcher::rules::{PathPattern, PatternSpec};
+
+/// What to do with an event matched by a rule.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Publish(VmmAction),
+    /// Publish a `VmmAction::Move` from the event's source path to its
+    /// destination.
+    Move,
+    Log,
+    Drop,
+}
+
+/// A rule as written in a config file, e.g.
+/// `{"kind": "modify.metadata.*", "outcome": {"publish": "Rollup"}}`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PolicyRule {
+    /// Glob over the dotted kind name, see `pubsub::kind_name`.
+    pub kind: String,
+    #[serde(default)]
+    pub path: Option<PatternSpec>,
+    /// Matched against the instance's qualified name. A rule with an
+    /// instance pattern never matches events outside an instance.
+    #[serde(default)]
+    pub instance: Option<PatternSpec>,
+    pub outcome: Outcome,
+}
+
+impl PolicyRule {
+    pub fn new(kind: &str, outcome: Outcome) -> Self {
+        Self { kind: kind.to_string(), path: None, instance: None, outcome }
+    }
+}
+
+/// Rules are evaluated in order and the first match wins. Events no rule
+/// matches get `default_outcome`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PolicyConfig {
+    pub rules: Vec<PolicyRule>,
+    #[serde(default = "PolicyConfig::default_outcome")]
+    pub default_outcome: Outcome,
+}
+
+impl PolicyConfig {
+    fn default_outcome() -> Outcome {
+        Outcome::Log
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let contents = std::fs::read(path.as_ref())?;
+        serde_json::from_slice(&contents).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unable to parse policy config {}: {e}", path.as_ref().display())
+            )
+        })
+    }
+}
+
+impl Default for PolicyConfig {
+    /// The mapping `handle_events` has always applied.
+    fn default() -> Self {
+        let rules = vec![
+            PolicyRule::new("access.close.execute", Outcome::Publish(VmmAction::Copy)),
+            PolicyRule::new("access.close.write", Outcome::Publish(VmmAction::Copy)),
+            PolicyRule::new("access.*", Outcome::Log),
+            PolicyRule::new("create.*", Outcome::Publish(VmmAction::Copy)),
+            PolicyRule::new("modify.name.both", Outcome::Move),
+            PolicyRule::new("modify.metadata.ownership", Outcome::Publish(VmmAction::Copy)),
+            PolicyRule::new("modify.metadata.permissions", Outcome::Publish(VmmAction::Copy)),
+            PolicyRule::new("modify.metadata.*", Outcome::Publish(VmmAction::Rollup)),
+            PolicyRule::new("modify.*", Outcome::Publish(VmmAction::Copy)),
+            PolicyRule::new("remove.*", Outcome::Publish(VmmAction::Copy)),
+            PolicyRule::new("other", Outcome::Publish(VmmAction::Snapshot)),
+            PolicyRule::new("any", Outcome::Drop),
+        ];
+
+        Self { rules, default_outcome: Outcome::Log }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct CompiledRule {
+    kind: glob::Pattern,
+    path: Option<PathPattern>,
+    instance: Option<PathPattern>,
+    outcome: Outcome,
+}
+
+impl CompiledRule {
+    fn matches(&self, kind: &str, path: Option<&str>, instance: Option<&str>) -> bool {
+        if !self.kind.matches(kind) {
+            return false
+        }
+        if let Some(pattern) = &self.path {
+            if !path.is_some_and(|path| pattern.matches(path)) {
+                return false
+            }
+        }
+        if let Some(pattern) = &self.instance {
+            if !instance.is_some_and(|instance| pattern.matches(instance)) {
+                return false
+            }
+        }
+
+        true
+    }
+}
+
+/// Compiled form of a `PolicyConfig`.
+#[derive(Clone, Debug)]
+pub struct Policy {
+    rules: Vec<CompiledRule>,
+    default_outcome: Outcome,
+}
+
+impl Policy {
+    pub fn new(config: &PolicyConfig) -> std::io::Result<Self> {
+        let rules = config.rules.iter().map(|rule| {
+            let kind = glob::Pattern::new(&rule.kind).map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("invalid kind pattern {}: {e}", rule.kind)
+                )
+            })?;
+            Ok(CompiledRule {
+                kind,
+                path: rule.path.as_ref().map(PathPattern::try_from).transpose()?,
+                instance: rule.instance.as_ref().map(PathPattern::try_from).transpose()?,
+                outcome: rule.outcome.clone(),
+            })
+        }).collect::<std::io::Result<Vec<_>>>()?;
+
+        Ok(Self { rules, default_outcome: config.default_outcome.clone() })
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Self::new(&PolicyConfig::load(path)?)
+    }
+
+    pub fn evaluate(&self, event: &Event, instance: Option<&InstancePath>) -> &Outcome {
+        let kind = kind_name(&event.kind);
+        let path = event.paths.first().map(|p| p.to_string_lossy());
+        let instance = instance.map(|i| i.qualified_name());
+
+        self.rules.iter()
+            .find(|rule| rule.matches(&kind, path.as_deref(), instance.as_deref()))
+            .map(|rule| &rule.outcome)
+            .unwrap_or(&self.default_outcome)
+    }
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        // The default table only holds literal kind patterns.
+        Self::new(&PolicyConfig::default()).expect("default policy table is valid")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{
+        AccessKind,
+        AccessMode,
+        DataChange,
+        EventKind,
+        MetadataKind,
+        ModifyKind,
+        RemoveKind,
+        RenameMode
+    };
+    use std::path::PathBuf;
+
+    use crate::pubsub::all_kinds;
+    use crate::watcher::resolver::InstanceKind;
+
+    /// What `handle_events` did with each kind before the policy table,
+    /// except that renames with both paths are now published as moves.
+    fn baseline(kind: &EventKind) -> &'static str {
+        match kind {
+            EventKind::Any => "Drop",
+            EventKind::Access(AccessKind::Close(AccessMode::Execute | AccessMode::Write)) => "Publish(Copy)",
+            EventKind::Access(_) => "Log",
+            EventKind::Create(_) => "Publish(Copy)",
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => "Move",
+            EventKind::Modify(ModifyKind::Metadata(MetadataKind::Ownership | MetadataKind::Permissions)) => "Publish(Copy)",
+            EventKind::Modify(ModifyKind::Metadata(_)) => "Publish(Rollup)",
+            EventKind::Modify(_) => "Publish(Copy)",
+            EventKind::Remove(_) => "Publish(Copy)",
+            EventKind::Other => "Publish(Snapshot)",
+        }
+    }
+
+    fn event(kind: EventKind, path: &str) -> Event {
+        Event::new(kind).add_path(PathBuf::from(path))
+    }
+
+    #[test]
+    fn default_table_matches_the_original_handler() {
+        let policy = Policy::default();
+        for kind in all_kinds() {
+            let outcome = policy.evaluate(&event(kind, "/var/lib/file"), None);
+            assert_eq!(format!("{outcome:?}"), baseline(&kind), "{}", kind_name(&kind));
+        }
+    }
+
+    #[test]
+    fn earlier_rules_override_later_ones() {
+        let mut config = PolicyConfig::default();
+        let mut logs = PolicyRule::new("modify.*", Outcome::Log);
+        logs.path = Some(PatternSpec::Glob("/var/log/**".to_string()));
+        let mut drops = PolicyRule::new("*", Outcome::Drop);
+        drops.instance = Some(PatternSpec::Prefix("scratch_".to_string()));
+        config.rules.insert(0, logs);
+        config.rules.insert(1, drops);
+        let policy = Policy::new(&config).unwrap();
+
+        let kind = EventKind::Modify(ModifyKind::Data(DataChange::Content));
+        assert!(matches!(policy.evaluate(&event(kind, "/var/log/syslog"), None), Outcome::Log));
+        assert!(matches!(policy.evaluate(&event(kind, "/var/lib/file"), None), Outcome::Publish(VmmAction::Copy)));
+
+        let instance = InstancePath {
+            name: "c1".to_string(),
+            project: Some("scratch".to_string()),
+            kind: InstanceKind::Container,
+            snapshot: None,
+            path: PathBuf::from("/etc/hosts"),
+            in_rootfs: true,
+        };
+        assert!(matches!(policy.evaluate(&event(kind, "/var/lib/file"), Some(&instance)), Outcome::Drop));
+    }
+
+    #[test]
+    fn unmatched_events_get_the_default_outcome() {
+        let config = PolicyConfig { rules: vec![PolicyRule::new("create.*", Outcome::Log)], default_outcome: Outcome::Drop };
+        let policy = Policy::new(&config).unwrap();
+        let kind = EventKind::Remove(RemoveKind::File);
+        assert!(matches!(policy.evaluate(&event(kind, "/tmp/file"), None), Outcome::Drop));
+    }
+
+    #[test]
+    fn invalid_kind_patterns_are_rejected() {
+        let config = PolicyConfig { rules: vec![PolicyRule::new("modify.[", Outcome::Log)], default_outcome: Outcome::Log };
+        assert_eq!(Policy::new(&config).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,195 @@
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+
+/// Turns messages into frame payloads and back.
+pub trait Codec<M>: Send + Sync {
+    fn encode(&self, msg: &M) -> std::io::Result<Vec<u8>>;
+    fn decode(&self, bytes: &[u8]) -> std::io::Result<M>;
+}
+
+/// The encoding every Libretto component has used so far.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonCodec;
+
+impl<M: Serialize + DeserializeOwned> Codec<M> for JsonCodec {
+    fn encode(&self, msg: &M) -> std::io::Result<Vec<u8>> {
+        serde_json::to_vec(msg).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                e
+            )
+        })
+    }
+
+    fn decode(&self, bytes: &[u8]) -> std::io::Result<M> {
+        serde_json::from_slice(bytes).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                e
+            )
+        })
+    }
+}
+
+/// How a payload is encoded. Binary payloads start with a tag byte naming
+/// their encoding. JSON payloads are sent untagged so consumers that predate
+/// the tag can still read them; no JSON text starts with a tag byte.
+///
+/// Events keep all of notify's attributes in protobuf. JSON and MessagePack
+/// use notify's serde form, which leaves out the process id.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    #[default]
+    Json,
+    /// MessagePack with field names, so fields can be added as with JSON.
+    MessagePack,
+    /// The messages in `proto/pubsub.proto`.
+    Protobuf,
+}
+
+impl Encoding {
+    const MESSAGE_PACK_TAG: u8 = 0x02;
+    const PROTOBUF_TAG: u8 = 0x03;
+
+    fn tag(&self) -> Option<u8> {
+        match self {
+            Encoding::Json => None,
+            Encoding::MessagePack => Some(Self::MESSAGE_PACK_TAG),
+            Encoding::Protobuf => Some(Self::PROTOBUF_TAG),
+        }
+    }
+}
+
+impl std::str::FromStr for Encoding {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Encoding::Json),
+            "msgpack" | "messagepack" | "message_pack" => Ok(Encoding::MessagePack),
+            "protobuf" | "proto" => Ok(Encoding::Protobuf),
+            other => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("unknown pubsub encoding: {other}")
+            )),
+        }
+    }
+}
+
+/// Messages that have a protobuf form. Both methods fail by default, so a
+/// message type only needs an empty impl to be used with `WireCodec`.
+pub trait ProtoMessage: Sized {
+    fn encode_proto(&self) -> std::io::Result<Vec<u8>> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "message has no protobuf encoding"
+        ))
+    }
+
+    fn decode_proto(bytes: &[u8]) -> std::io::Result<Self> {
+        let _ = bytes;
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "message has no protobuf encoding"
+        ))
+    }
+}
+
+/// Encodes with the configured `Encoding` and decodes whatever encoding a
+/// payload is tagged with, so producers can switch encodings without
+/// consumers being restarted first.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WireCodec {
+    encoding: Encoding,
+}
+
+impl WireCodec {
+    pub fn new(encoding: Encoding) -> Self {
+        Self { encoding }
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+}
+
+impl<M: Serialize + DeserializeOwned + ProtoMessage> Codec<M> for WireCodec {
+    fn encode(&self, msg: &M) -> std::io::Result<Vec<u8>> {
+        let body = match self.encoding {
+            Encoding::Json => return JsonCodec.encode(msg),
+            Encoding::MessagePack => rmp_serde::to_vec_named(msg).map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    e
+                )
+            })?,
+            Encoding::Protobuf => msg.encode_proto()?,
+        };
+        let mut payload = Vec::with_capacity(body.len() + 1);
+        payload.extend(self.encoding.tag());
+        payload.extend_from_slice(&body);
+        Ok(payload)
+    }
+
+    fn decode(&self, bytes: &[u8]) -> std::io::Result<M> {
+        match bytes.first() {
+            Some(&Encoding::MESSAGE_PACK_TAG) => rmp_serde::from_slice(&bytes[1..]).map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    e
+                )
+            }),
+            Some(&Encoding::PROTOBUF_TAG) => M::decode_proto(&bytes[1..]),
+            _ => JsonCodec.decode(bytes),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{CreateKind, EventKind};
+    use notify::Event;
+    use std::path::PathBuf;
+
+    use crate::pubsub::FilesystemEvent;
+
+    fn event() -> FilesystemEvent {
+        let event = Event::new(EventKind::Create(CreateKind::File))
+            .add_path(PathBuf::from("/pool/containers/c1/rootfs/etc/hosts"))
+            .set_info("created");
+        FilesystemEvent::new(event, None)
+    }
+
+    #[test]
+    fn every_encoding_round_trips() {
+        let cases = [
+            (Encoding::Json, b'{'),
+            (Encoding::MessagePack, Encoding::MESSAGE_PACK_TAG),
+            (Encoding::Protobuf, Encoding::PROTOBUF_TAG),
+        ];
+        for (encoding, first) in cases {
+            let payload = WireCodec::new(encoding).encode(&event()).unwrap();
+            assert_eq!(payload[0], first, "{encoding:?}");
+
+            // Any codec decodes any payload.
+            let decoded: FilesystemEvent = WireCodec::default().decode(&payload).unwrap();
+            assert_eq!(decoded.event(), event().event(), "{encoding:?}");
+        }
+    }
+
+    #[test]
+    fn json_is_readable_by_consumers_without_the_tag() {
+        let payload = WireCodec::default().encode(&event()).unwrap();
+        assert_eq!(payload, JsonCodec.encode(&event()).unwrap());
+        let decoded: FilesystemEvent = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(decoded.event(), event().event());
+    }
+
+    #[test]
+    fn encodings_are_parsed_from_their_names() {
+        assert_eq!("msgpack".parse::<Encoding>().unwrap(), Encoding::MessagePack);
+        assert_eq!("proto".parse::<Encoding>().unwrap(), Encoding::Protobuf);
+        assert_eq!("xml".parse::<Encoding>().unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,369 @@
+use rand::Rng;
+use serde::{Serialize, Deserialize};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::time::Instant;
+use tokio_rustls::client::TlsStream;
+
+use super::tls::TlsClient;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct BackoffConfig {
+    pub initial_ms: u64,
+    pub max_ms: u64,
+    pub multiplier: f64,
+    /// Fraction of each delay that is randomised, from 0 to 1, so clients
+    /// cut off together do not reconnect together.
+    pub jitter: f64,
+    /// Failed attempts before giving up. `None` retries forever.
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for BackoffConfig {
+    fn default() -> Self {
+        Self { initial_ms: 100, max_ms: 30_000, multiplier: 2.0, jitter: 0.5, max_attempts: None }
+    }
+}
+
+/// Jittered exponential backoff.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    config: BackoffConfig,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(config: BackoffConfig) -> Self {
+        Self { config, attempt: 0 }
+    }
+
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    /// How long to wait before the next attempt, or `None` once the attempts
+    /// are used up.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if self.config.max_attempts.is_some_and(|max| self.attempt >= max) {
+            return None
+        }
+        let exponent = self.attempt.min(63) as i32;
+        self.attempt += 1;
+
+        let delay = (self.config.initial_ms as f64 * self.config.multiplier.max(1.0).powi(exponent))
+            .min(self.config.max_ms as f64);
+        let jitter = self.config.jitter.clamp(0.0, 1.0);
+        let delay = delay * (1.0 - jitter * rand::thread_rng().gen::<f64>());
+
+        Some(Duration::from_millis(delay as u64))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionState {
+    /// Connected again after being disconnected. Subscribers may have missed
+    /// messages published in between.
+    Connected,
+    Disconnected(String),
+    /// Waiting `delay_ms` before the next connection attempt.
+    Reconnecting { attempt: u32, delay_ms: u64 },
+    /// Out of attempts. The next send or receive starts over.
+    Failed(String),
+    /// A publisher's outbox was full, so its oldest unsent message was
+    /// discarded. `total` counts every message it has dropped.
+    Dropped { total: u64 },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectionEvent {
+    pub uri: String,
+    /// The subscribed topic, `None` for publishers.
+    pub topic: Option<String>,
+    pub state: ConnectionState,
+}
+
+/// A plaintext or TLS connection to a broker.
+pub(crate) enum BrokerStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl BrokerStream {
+    /// Writes and flushes `bytes`, so nothing is left in the TLS buffer.
+    pub async fn send(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.write_all(bytes).await?;
+        self.flush().await
+    }
+}
+
+impl AsyncRead for BrokerStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            BrokerStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            BrokerStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for BrokerStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            BrokerStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            BrokerStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            BrokerStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            BrokerStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            BrokerStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            BrokerStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// A broker connection that reconnects with backoff and, for subscribers,
+/// subscribes again after every reconnect.
+pub(crate) struct Connection {
+    uri: String,
+    subscription: Option<String>,
+    tls: Option<TlsClient>,
+    stream: Option<BrokerStream>,
+    backoff: Backoff,
+    /// Set from a reconnect until the connection has carried data, so a
+    /// broker that accepts and then drops connections is backed off from.
+    unhealthy: bool,
+    /// When the next reconnect attempt is due. Kept here rather than in the
+    /// future waiting for it, so a caller that drops that future, e.g. in a
+    /// `select!`, picks up the same wait instead of backing off further.
+    retry_at: Option<Instant>,
+    last_error: Option<String>,
+    events: Option<UnboundedSender<ConnectionEvent>>,
+}
+
+impl Connection {
+    /// Connects once, failing straight away if the broker is not there or
+    /// the TLS handshake fails.
+    pub async fn open(uri: &str, subscription: Option<String>, tls: Option<TlsClient>) -> std::io::Result<Self> {
+        let stream = Self::dial(uri, subscription.as_deref(), tls.as_ref()).await?;
+        Ok(Self {
+            uri: uri.to_string(),
+            subscription,
+            tls,
+            stream: Some(stream),
+            backoff: Backoff::new(BackoffConfig::default()),
+            unhealthy: false,
+            retry_at: None,
+            last_error: None,
+            events: None,
+        })
+    }
+
+    async fn dial(uri: &str, subscription: Option<&str>, tls: Option<&TlsClient>) -> std::io::Result<BrokerStream> {
+        let stream = TcpStream::connect(uri).await?;
+        let mut stream = match tls {
+            Some(tls) => BrokerStream::Tls(Box::new(tls.connect(stream).await?)),
+            None => BrokerStream::Plain(stream),
+        };
+        if let Some(topic) = subscription {
+            stream.send(topic.as_bytes()).await?;
+        }
+        Ok(stream)
+    }
+
+    pub fn set_backoff(&mut self, config: BackoffConfig) {
+        self.backoff = Backoff::new(config);
+    }
+
+    pub fn set_events(&mut self, events: UnboundedSender<ConnectionEvent>) {
+        self.events = Some(events);
+    }
+
+    pub fn uri(&self) -> &str {
+        &self.uri
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    pub(crate) fn emit(&self, state: ConnectionState) {
+        if let Some(events) = &self.events {
+            let _ = events.send(ConnectionEvent {
+                uri: self.uri.clone(),
+                topic: self.subscription.clone(),
+                state,
+            });
+        }
+    }
+
+    /// The live stream, reconnecting first if the last one was dropped.
+    pub async fn stream(&mut self) -> std::io::Result<&mut BrokerStream> {
+        if self.stream.is_none() {
+            self.reconnect().await?;
+        }
+        self.stream.as_mut().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                format!("not connected to {}", self.uri)
+            )
+        })
+    }
+
+    /// Records that the connection carried data, resetting the backoff.
+    pub fn mark_healthy(&mut self) {
+        self.unhealthy = false;
+        self.backoff.reset();
+    }
+
+    pub fn disconnect(&mut self, reason: &std::io::Error) {
+        if self.stream.take().is_some() {
+            log::warn!("lost connection to {}: {reason}", self.uri);
+            self.emit(ConnectionState::Disconnected(reason.to_string()));
+        }
+    }
+
+    /// Replaces the connection, retrying with backoff until it succeeds or
+    /// the attempts run out. Safe to cancel: the pending wait is resumed by
+    /// the next call.
+    pub async fn reconnect(&mut self) -> std::io::Result<()> {
+        self.stream = None;
+        loop {
+            // The first attempt after a healthy connection goes out at once.
+            if self.unhealthy {
+                let retry_at = self.schedule()?;
+                tokio::time::sleep_until(retry_at).await;
+            }
+            if self.attempt().await.is_ok() {
+                return Ok(())
+            }
+        }
+    }
+
+    /// Makes one connection attempt if one is due, without waiting for the
+    /// backoff. Fails with `WouldBlock` while the next attempt is not due.
+    pub async fn try_reconnect(&mut self) -> std::io::Result<()> {
+        if self.stream.is_some() {
+            return Ok(())
+        }
+        if self.unhealthy && Instant::now() < self.schedule()? {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::WouldBlock,
+                format!("waiting to reconnect to {}", self.uri)
+            ))
+        }
+        self.attempt().await
+    }
+
+    /// When the next attempt is due, starting a new backoff delay unless one
+    /// is already pending.
+    fn schedule(&mut self) -> std::io::Result<Instant> {
+        if let Some(retry_at) = self.retry_at {
+            return Ok(retry_at)
+        }
+        let Some(delay) = self.backoff.next_delay() else {
+            let error = std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                self.last_error.take().unwrap_or_else(|| "out of reconnect attempts".to_string())
+            );
+            log::error!("giving up on {}: {error}", self.uri);
+            self.emit(ConnectionState::Failed(error.to_string()));
+            self.backoff.reset();
+            return Err(error)
+        };
+        self.emit(ConnectionState::Reconnecting {
+            attempt: self.backoff.attempt(),
+            delay_ms: delay.as_millis() as u64,
+        });
+        let retry_at = Instant::now() + delay;
+        self.retry_at = Some(retry_at);
+        Ok(retry_at)
+    }
+
+    async fn attempt(&mut self) -> std::io::Result<()> {
+        self.retry_at = None;
+        self.unhealthy = true;
+        match Self::dial(&self.uri, self.subscription.as_deref(), self.tls.as_ref()).await {
+            Ok(stream) => {
+                log::info!("reconnected to {}", self.uri);
+                self.stream = Some(stream);
+                self.last_error = None;
+                self.emit(ConnectionState::Connected);
+                Ok(())
+            }
+            Err(e) => {
+                log::warn!("unable to reconnect to {}: {e}", self.uri);
+                self.last_error = Some(e.to_string());
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn fixed_backoff(delay_ms: u64) -> BackoffConfig {
+        BackoffConfig { initial_ms: delay_ms, max_ms: delay_ms, multiplier: 1.0, jitter: 0.0, max_attempts: None }
+    }
+
+    #[test]
+    fn backoff_grows_to_the_cap_and_gives_up() {
+        let config = BackoffConfig { initial_ms: 100, max_ms: 350, multiplier: 2.0, jitter: 0.0, max_attempts: Some(4) };
+        let mut backoff = Backoff::new(config);
+        let delays: Vec<_> = std::iter::from_fn(|| backoff.next_delay()).collect();
+        assert_eq!(delays, [100, 200, 350, 350].map(Duration::from_millis));
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Some(Duration::from_millis(100)));
+    }
+
+    #[tokio::test]
+    async fn cancelled_reconnects_resume_the_pending_wait() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let uri = listener.local_addr().unwrap().to_string();
+        let mut connection = Connection::open(&uri, None, None).await.unwrap();
+        connection.set_backoff(fixed_backoff(500));
+        drop(listener);
+
+        for _ in 0..20 {
+            let _ = tokio::time::timeout(Duration::from_millis(10), connection.reconnect()).await;
+        }
+
+        assert!(!connection.is_connected());
+        assert_eq!(connection.backoff.attempt(), 1);
+    }
+
+    #[tokio::test]
+    async fn try_reconnect_waits_for_the_backoff() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let uri = listener.local_addr().unwrap().to_string();
+        let mut connection = Connection::open(&uri, None, None).await.unwrap();
+        connection.set_backoff(fixed_backoff(60_000));
+        drop(listener);
+        connection.disconnect(&std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "closed"));
+
+        // The first attempt after a healthy connection is immediate.
+        assert_eq!(connection.try_reconnect().await.unwrap_err().kind(), std::io::ErrorKind::ConnectionRefused);
+        assert_eq!(connection.try_reconnect().await.unwrap_err().kind(), std::io::ErrorKind::WouldBlock);
+        assert_eq!(connection.try_reconnect().await.unwrap_err().kind(), std::io::ErrorKind::WouldBlock);
+        assert_eq!(connection.backoff.attempt(), 1);
+    }
+}
@@ -0,0 +1,246 @@
+use conductor::{HEADER_SIZE, TOPIC_SIZE_OFFSET};
+
+/// Frames larger than this are rejected unless the decoder is configured
+/// otherwise.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+/// One message on the conductor wire: the topic it was published to and its
+/// encoded payload.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub topic: String,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FrameError {
+    /// The header announces a frame larger than the decoder accepts.
+    TooLarge { len: usize, max: usize },
+    /// The header's lengths do not add up to a representable size.
+    LengthOverflow { payload_len: usize, topic_len: usize },
+    /// The frame was complete but its topic is not UTF-8. It has been
+    /// consumed, so decoding can continue with the next frame.
+    InvalidTopic(std::string::FromUtf8Error),
+}
+
+impl FrameError {
+    /// Whether the stream is still aligned on a frame boundary. After any
+    /// other error the connection cannot be trusted and should be dropped.
+    pub fn is_recoverable(&self) -> bool {
+        matches!(self, FrameError::InvalidTopic(_))
+    }
+}
+
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::TooLarge { len, max } => {
+                write!(f, "frame of {len} bytes exceeds the maximum of {max}")
+            }
+            FrameError::LengthOverflow { payload_len, topic_len } => {
+                write!(f, "frame lengths overflow: payload {payload_len}, topic {topic_len}")
+            }
+            FrameError::InvalidTopic(e) => write!(f, "frame topic is not utf-8: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<FrameError> for std::io::Error {
+    fn from(e: FrameError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// `[payload len][topic len][topic][payload]`, lengths as big-endian `usize`.
+pub fn encode_frame(topic: &str, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_SIZE + TOPIC_SIZE_OFFSET + topic.len() + payload.len());
+    frame.extend_from_slice(&payload.len().to_be_bytes());
+    frame.extend_from_slice(&topic.len().to_be_bytes());
+    frame.extend_from_slice(topic.as_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Reassembles frames from a byte stream. Bytes are fed in as they are read
+/// and complete frames taken out; a partial frame stays buffered until the
+/// rest of it arrives. Oversized frames are rejected as soon as their header
+/// is in, before any of the payload is buffered.
+#[derive(Debug)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    max_frame_size: usize,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        Self { buffer: Vec::new(), max_frame_size: DEFAULT_MAX_FRAME_SIZE }
+    }
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Largest frame, header included, that will be accepted.
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    pub fn max_frame_size(&self) -> usize {
+        self.max_frame_size
+    }
+
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buffer
+    }
+
+    /// Drops everything buffered, e.g. a partial frame from a closed
+    /// connection.
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+    }
+
+    /// Bytes received that are not part of a returned frame yet.
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Removes and returns the next complete frame, if there is one. An
+    /// unrecoverable error is returned again on every later call.
+    pub fn next_frame(&mut self) -> Result<Option<Frame>, FrameError> {
+        let header_len = HEADER_SIZE + TOPIC_SIZE_OFFSET;
+        if self.buffer.len() < header_len {
+            return Ok(None)
+        }
+
+        let payload_len = read_len(&self.buffer[..HEADER_SIZE]);
+        let topic_len = read_len(&self.buffer[HEADER_SIZE..header_len]);
+        let frame_len = header_len
+            .checked_add(topic_len)
+            .and_then(|len| len.checked_add(payload_len))
+            .ok_or(FrameError::LengthOverflow { payload_len, topic_len })?;
+        if frame_len > self.max_frame_size {
+            return Err(FrameError::TooLarge { len: frame_len, max: self.max_frame_size })
+        }
+        if self.buffer.len() < frame_len {
+            return Ok(None)
+        }
+
+        let mut frame: Vec<u8> = self.buffer.drain(..frame_len).collect();
+        let payload = frame.split_off(header_len + topic_len);
+        let topic = String::from_utf8(frame.split_off(header_len)).map_err(FrameError::InvalidTopic)?;
+
+        Ok(Some(Frame { topic, payload }))
+    }
+
+    /// Every complete frame in the buffer. Frames with an invalid topic are
+    /// logged and skipped. Frames decoded before an unrecoverable error are
+    /// still returned, and the error with the next call.
+    pub fn frames(&mut self) -> Result<Vec<Frame>, FrameError> {
+        let mut frames = Vec::new();
+        loop {
+            match self.next_frame() {
+                Ok(Some(frame)) => frames.push(frame),
+                Ok(None) => return Ok(frames),
+                Err(e) if e.is_recoverable() => log::warn!("skipping frame: {e}"),
+                Err(e) if frames.is_empty() => return Err(e),
+                Err(_) => return Ok(frames),
+            }
+        }
+    }
+}
+
+fn read_len(bytes: &[u8]) -> usize {
+    let mut len = [0u8; std::mem::size_of::<usize>()];
+    len.copy_from_slice(bytes);
+    usize::from_be_bytes(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::TestResult;
+    use quickcheck_macros::quickcheck;
+
+    fn header(payload_len: usize, topic_len: usize) -> Vec<u8> {
+        let mut header = payload_len.to_be_bytes().to_vec();
+        header.extend_from_slice(&topic_len.to_be_bytes());
+        header
+    }
+
+    #[quickcheck]
+    fn frames_survive_arbitrary_split_points(frames: Vec<(String, Vec<u8>)>, splits: Vec<usize>) -> bool {
+        let bytes: Vec<u8> = frames.iter().flat_map(|(topic, payload)| encode_frame(topic, payload)).collect();
+        let mut cuts: Vec<usize> = splits.iter().map(|split| split % (bytes.len() + 1)).collect();
+        cuts.push(bytes.len());
+        cuts.sort_unstable();
+
+        let mut decoder = FrameDecoder::new();
+        let mut decoded = Vec::new();
+        let mut start = 0;
+        for cut in cuts {
+            decoder.extend(&bytes[start..cut]);
+            start = cut;
+            decoded.extend(decoder.frames().unwrap());
+        }
+
+        let expected: Vec<Frame> = frames.into_iter().map(|(topic, payload)| Frame { topic, payload }).collect();
+        decoded == expected && decoder.buffered() == 0
+    }
+
+    #[quickcheck]
+    fn oversize_frames_are_rejected_from_the_header(excess: usize, topic_len: u16, max: u16) -> TestResult {
+        let (max, topic_len) = (max as usize, topic_len as usize);
+        let payload_len = max.saturating_add(excess);
+        let header = header(payload_len, topic_len);
+        let len = match (header.len() + topic_len).checked_add(payload_len) {
+            Some(len) => len,
+            None => return TestResult::discard(),
+        };
+
+        let mut decoder = FrameDecoder::new().with_max_frame_size(max);
+        decoder.extend(&header);
+        let result = decoder.next_frame();
+        // Nothing beyond the header was reserved for the announced payload.
+        let buffer = decoder.into_inner();
+        TestResult::from_bool(
+            result == Err(FrameError::TooLarge { len, max })
+                && buffer.len() == header.len()
+                && buffer.capacity() < header.len() + topic_len + 1024
+        )
+    }
+
+    #[quickcheck]
+    fn truncated_headers_wait_for_more(payload_len: usize, topic_len: usize, cut: usize) -> bool {
+        let header = header(payload_len, topic_len);
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&header[..cut % header.len()]);
+        decoder.next_frame() == Ok(None)
+    }
+
+    #[test]
+    fn overflowing_lengths_are_rejected() {
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&header(usize::MAX, 1));
+        assert_eq!(decoder.next_frame(), Err(FrameError::LengthOverflow { payload_len: usize::MAX, topic_len: 1 }));
+    }
+
+    #[test]
+    fn frames_with_invalid_topics_are_skipped() {
+        let mut bytes = header(2, 1);
+        bytes.extend_from_slice(&[0xff, b'h', b'i']);
+        bytes.extend(encode_frame("topic", b"ok"));
+
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&bytes);
+        assert_eq!(decoder.frames().unwrap(), vec![Frame { topic: "topic".to_string(), payload: b"ok".to_vec() }]);
+    }
+}
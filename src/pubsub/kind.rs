@@ -0,0 +1,198 @@
+use notify::event::{
+    AccessKind,
+    AccessMode,
+    CreateKind,
+    DataChange,
+    EventKind,
+    MetadataKind,
+    ModifyKind,
+    RemoveKind,
+    RenameMode
+};
+
+/// Dotted lowercase name of an event kind, e.g. `modify.metadata.ownership`
+/// or `access.close.write`. This is how kinds are carried in protobuf
+/// frames, so existing names must not change; policy rules glob over them
+/// too.
+pub fn kind_name(kind: &EventKind) -> String {
+    fn access_mode(mode: &AccessMode) -> &'static str {
+        match mode {
+            AccessMode::Any => "any",
+            AccessMode::Execute => "execute",
+            AccessMode::Read => "read",
+            AccessMode::Write => "write",
+            AccessMode::Other => "other",
+        }
+    }
+
+    match kind {
+        EventKind::Any => "any".to_string(),
+        EventKind::Access(access) => match access {
+            AccessKind::Any => "access.any".to_string(),
+            AccessKind::Read => "access.read".to_string(),
+            AccessKind::Open(mode) => format!("access.open.{}", access_mode(mode)),
+            AccessKind::Close(mode) => format!("access.close.{}", access_mode(mode)),
+            AccessKind::Other => "access.other".to_string(),
+        },
+        EventKind::Create(create) => match create {
+            CreateKind::Any => "create.any",
+            CreateKind::File => "create.file",
+            CreateKind::Folder => "create.folder",
+            CreateKind::Other => "create.other",
+        }.to_string(),
+        EventKind::Modify(modify) => match modify {
+            ModifyKind::Any => "modify.any",
+            ModifyKind::Data(data) => match data {
+                DataChange::Any => "modify.data.any",
+                DataChange::Size => "modify.data.size",
+                DataChange::Content => "modify.data.content",
+                DataChange::Other => "modify.data.other",
+            },
+            ModifyKind::Metadata(metadata) => match metadata {
+                MetadataKind::Any => "modify.metadata.any",
+                MetadataKind::AccessTime => "modify.metadata.access_time",
+                MetadataKind::WriteTime => "modify.metadata.write_time",
+                MetadataKind::Permissions => "modify.metadata.permissions",
+                MetadataKind::Ownership => "modify.metadata.ownership",
+                MetadataKind::Extended => "modify.metadata.extended",
+                MetadataKind::Other => "modify.metadata.other",
+            },
+            ModifyKind::Name(rename) => match rename {
+                RenameMode::Any => "modify.name.any",
+                RenameMode::To => "modify.name.to",
+                RenameMode::From => "modify.name.from",
+                RenameMode::Both => "modify.name.both",
+                RenameMode::Other => "modify.name.other",
+            },
+            ModifyKind::Other => "modify.other",
+        }.to_string(),
+        EventKind::Remove(remove) => match remove {
+            RemoveKind::Any => "remove.any",
+            RemoveKind::File => "remove.file",
+            RemoveKind::Folder => "remove.folder",
+            RemoveKind::Other => "remove.other",
+        }.to_string(),
+        EventKind::Other => "other".to_string(),
+    }
+}
+
+/// The event kind a `kind_name` stands for.
+pub fn parse_kind_name(name: &str) -> Option<EventKind> {
+    fn access_mode(mode: &str) -> Option<AccessMode> {
+        match mode {
+            "any" => Some(AccessMode::Any),
+            "execute" => Some(AccessMode::Execute),
+            "read" => Some(AccessMode::Read),
+            "write" => Some(AccessMode::Write),
+            "other" => Some(AccessMode::Other),
+            _ => None,
+        }
+    }
+
+    let parts: Vec<&str> = name.split('.').collect();
+    let kind = match parts.as_slice() {
+        ["any"] => EventKind::Any,
+        ["other"] => EventKind::Other,
+        ["access", "any"] => EventKind::Access(AccessKind::Any),
+        ["access", "read"] => EventKind::Access(AccessKind::Read),
+        ["access", "open", mode] => EventKind::Access(AccessKind::Open(access_mode(mode)?)),
+        ["access", "close", mode] => EventKind::Access(AccessKind::Close(access_mode(mode)?)),
+        ["access", "other"] => EventKind::Access(AccessKind::Other),
+        ["create", create] => EventKind::Create(match *create {
+            "any" => CreateKind::Any,
+            "file" => CreateKind::File,
+            "folder" => CreateKind::Folder,
+            "other" => CreateKind::Other,
+            _ => return None,
+        }),
+        ["modify", "any"] => EventKind::Modify(ModifyKind::Any),
+        ["modify", "data", data] => EventKind::Modify(ModifyKind::Data(match *data {
+            "any" => DataChange::Any,
+            "size" => DataChange::Size,
+            "content" => DataChange::Content,
+            "other" => DataChange::Other,
+            _ => return None,
+        })),
+        ["modify", "metadata", metadata] => EventKind::Modify(ModifyKind::Metadata(match *metadata {
+            "any" => MetadataKind::Any,
+            "access_time" => MetadataKind::AccessTime,
+            "write_time" => MetadataKind::WriteTime,
+            "permissions" => MetadataKind::Permissions,
+            "ownership" => MetadataKind::Ownership,
+            "extended" => MetadataKind::Extended,
+            "other" => MetadataKind::Other,
+            _ => return None,
+        })),
+        ["modify", "name", rename] => EventKind::Modify(ModifyKind::Name(match *rename {
+            "any" => RenameMode::Any,
+            "to" => RenameMode::To,
+            "from" => RenameMode::From,
+            "both" => RenameMode::Both,
+            "other" => RenameMode::Other,
+            _ => return None,
+        })),
+        ["modify", "other"] => EventKind::Modify(ModifyKind::Other),
+        ["remove", remove] => EventKind::Remove(match *remove {
+            "any" => RemoveKind::Any,
+            "file" => RemoveKind::File,
+            "folder" => RemoveKind::Folder,
+            "other" => RemoveKind::Other,
+            _ => return None,
+        }),
+        _ => return None,
+    };
+    Some(kind)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Every kind notify can report.
+    pub(crate) fn all_kinds() -> Vec<EventKind> {
+        let modes = [AccessMode::Any, AccessMode::Execute, AccessMode::Read, AccessMode::Write, AccessMode::Other];
+        let mut kinds = vec![
+            EventKind::Any,
+            EventKind::Other,
+            EventKind::Access(AccessKind::Any),
+            EventKind::Access(AccessKind::Read),
+            EventKind::Access(AccessKind::Other),
+        ];
+        kinds.extend(modes.iter().map(|mode| EventKind::Access(AccessKind::Open(*mode))));
+        kinds.extend(modes.iter().map(|mode| EventKind::Access(AccessKind::Close(*mode))));
+        kinds.extend([CreateKind::Any, CreateKind::File, CreateKind::Folder, CreateKind::Other].map(EventKind::Create));
+        kinds.extend([
+            ModifyKind::Any,
+            ModifyKind::Other,
+            ModifyKind::Data(DataChange::Any),
+            ModifyKind::Data(DataChange::Size),
+            ModifyKind::Data(DataChange::Content),
+            ModifyKind::Data(DataChange::Other),
+            ModifyKind::Metadata(MetadataKind::Any),
+            ModifyKind::Metadata(MetadataKind::AccessTime),
+            ModifyKind::Metadata(MetadataKind::WriteTime),
+            ModifyKind::Metadata(MetadataKind::Permissions),
+            ModifyKind::Metadata(MetadataKind::Ownership),
+            ModifyKind::Metadata(MetadataKind::Extended),
+            ModifyKind::Metadata(MetadataKind::Other),
+            ModifyKind::Name(RenameMode::Any),
+            ModifyKind::Name(RenameMode::To),
+            ModifyKind::Name(RenameMode::From),
+            ModifyKind::Name(RenameMode::Both),
+            ModifyKind::Name(RenameMode::Other),
+        ].map(EventKind::Modify));
+        kinds.extend([RemoveKind::Any, RemoveKind::File, RemoveKind::Folder, RemoveKind::Other].map(EventKind::Remove));
+        kinds
+    }
+
+    #[test]
+    fn kind_names_round_trip() {
+        for kind in all_kinds() {
+            let name = kind_name(&kind);
+            assert_eq!(parse_kind_name(&name), Some(kind), "{name}");
+        }
+        assert_eq!(parse_kind_name("modify.name"), None);
+        assert_eq!(parse_kind_name("access.close.sideways"), None);
+        assert_eq!(parse_kind_name(""), None);
+    }
+}
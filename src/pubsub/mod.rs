@@ -0,0 +1,428 @@
+use conductor::subscriber::SubStream;
+use notify::Event;
+use tonic::async_trait;
+use derive_more::Display;
+use serde::{Serialize, Deserialize};
+use futures::Stream;
+use std::collections::VecDeque;
+use std::mem::Discriminant;
+use std::path::PathBuf;
+use crate::batch::ChangeSet;
+use crate::migration::MigrationProgress;
+use crate::rollup::Rollup;
+use crate::watcher::resolver::InstancePath;
+
+mod codec;
+mod connection;
+mod frame;
+mod kind;
+mod proto;
+mod tls;
+mod transport;
+
+pub use codec::{Codec, Encoding, JsonCodec, ProtoMessage, WireCodec};
+pub use connection::{Backoff, BackoffConfig, ConnectionEvent, ConnectionState};
+pub use frame::{encode_frame, Frame, FrameDecoder, FrameError, DEFAULT_MAX_FRAME_SIZE};
+pub use kind::{kind_name, parse_kind_name};
+pub use tls::TlsConfig;
+pub use transport::{ConductorPublisher, ConductorSubscriber, DEFAULT_OUTBOX_CAPACITY};
+
+#[cfg(test)]
+pub(crate) use kind::tests::all_kinds;
+
+#[derive(Display)]
+pub struct FilesystemTopic;
+
+#[derive(Display)]
+pub struct LibrettoTopic;
+
+pub type FilesystemSubscriber = ConductorSubscriber<FilesystemEvent, WireCodec>;
+pub type FilesystemPublisher = ConductorPublisher<FilesystemTopic, FilesystemEvent, WireCodec>;
+pub type LibrettoPublisher = ConductorPublisher<LibrettoTopic, LibrettoEvent, WireCodec>;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FilesystemEvent {
+    event: Event,
+    instance: Option<InstancePath>,
+    /// Where a moved path ended up, for `RenameMode::Both` events.
+    #[serde(default)]
+    destination: Option<InstancePath>,
+}
+
+impl FilesystemEvent {
+    pub fn new(
+        event: Event,
+        instance: Option<InstancePath>
+    ) -> Self {
+        Self { event, instance, destination: None }
+    }
+
+    pub fn with_destination(mut self, destination: Option<InstancePath>) -> Self {
+        self.destination = destination;
+        self
+    }
+
+    pub fn event(&self) -> &Event {
+        &self.event
+    }
+
+    pub fn instance(&self) -> &Option<InstancePath> {
+        &self.instance
+    }
+
+    pub fn destination(&self) -> &Option<InstancePath> {
+        &self.destination
+    }
+
+    pub fn into_parts(self) -> (Event, Option<InstancePath>) {
+        (self.event, self.instance)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LibrettoEvent {
+    event: Event,
+    action: VmmAction,
+    instance_name: Option<String>,
+    #[serde(default)]
+    instance: Option<InstancePath>,
+    #[serde(default)]
+    destination: Option<InstancePath>,
+    /// Set when the event stands for a batch of changes to one instance.
+    #[serde(default)]
+    changes: Option<ChangeSet>,
+    /// Set when the event reports the result of Libretto carrying out the
+    /// action itself.
+    #[serde(default)]
+    outcome: Option<ActionOutcome>,
+    /// Set on `Rollup` events carrying the aggregated metadata of an
+    /// instance.
+    #[serde(default)]
+    rollup: Option<Rollup>,
+    /// Set on `Migrate` events reporting a phase transition.
+    #[serde(default)]
+    migration: Option<MigrationProgress>,
+}
+
+impl LibrettoEvent {
+    pub fn new(
+        event: Event,
+        action: VmmAction,
+        instance: Option<InstancePath>
+    ) -> Self {
+        let instance_name = instance.as_ref().map(|i| i.name.clone());
+        Self { event, action, instance_name, instance, destination: None, changes: None, outcome: None, rollup: None, migration: None }
+    }
+
+    pub fn with_destination(mut self, destination: Option<InstancePath>) -> Self {
+        self.destination = destination;
+        self
+    }
+
+    pub fn with_changes(mut self, changes: ChangeSet) -> Self {
+        self.changes = Some(changes);
+        self
+    }
+
+    pub fn with_outcome(mut self, outcome: ActionOutcome) -> Self {
+        self.outcome = Some(outcome);
+        self
+    }
+
+    pub fn with_rollup(mut self, rollup: Rollup) -> Self {
+        self.rollup = Some(rollup);
+        self
+    }
+
+    pub fn with_migration(mut self, migration: MigrationProgress) -> Self {
+        self.migration = Some(migration);
+        self
+    }
+
+    pub fn event(&self) -> &Event {
+        &self.event
+    }
+
+    pub fn action(&self) -> &VmmAction {
+        &self.action
+    }
+
+    pub fn instance_name(&self) -> &Option<String> {
+        &self.instance_name
+    }
+
+    pub fn instance(&self) -> &Option<InstancePath> {
+        &self.instance
+    }
+
+    pub fn destination(&self) -> &Option<InstancePath> {
+        &self.destination
+    }
+
+    pub fn changes(&self) -> &Option<ChangeSet> {
+        &self.changes
+    }
+
+    pub fn outcome(&self) -> &Option<ActionOutcome> {
+        &self.outcome
+    }
+
+    pub fn rollup(&self) -> &Option<Rollup> {
+        &self.rollup
+    }
+
+    pub fn migration(&self) -> &Option<MigrationProgress> {
+        &self.migration
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutcomeStatus {
+    Completed,
+    Failed(String),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ActionOutcome {
+    pub status: OutcomeStatus,
+    /// LXD operation that carried the action out, if one was started.
+    pub operation_id: Option<String>,
+    /// What the action produced, e.g. the snapshot name.
+    pub resource: Option<String>,
+}
+
+impl ActionOutcome {
+    pub fn completed(operation_id: Option<String>, resource: Option<String>) -> Self {
+        Self { status: OutcomeStatus::Completed, operation_id, resource }
+    }
+
+    pub fn failed(reason: String, operation_id: Option<String>, resource: Option<String>) -> Self {
+        Self { status: OutcomeStatus::Failed(reason), operation_id, resource }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum VmmAction {
+    Copy,
+    /// Rename `from` to `to` on the replica rather than copying `to` again.
+    Move { from: PathBuf, to: PathBuf },
+    Migrate,
+    Snapshot,
+    Rollup,
+    /// The watcher may have missed changes under the event's path and the
+    /// receiver should treat its view of it as stale.
+    Degraded(String),
+    Other(String)
+}
+
+pub struct LibrettoSubscriber {
+    inner: ConductorSubscriber<LibrettoEvent, WireCodec>,
+    filter: LibrettoFilter
+}
+
+impl LibrettoSubscriber {
+    pub async fn new(uri: &str) -> std::io::Result<Self> {
+        Self::connect(uri, None).await
+    }
+
+    pub async fn connect(uri: &str, tls: Option<&TlsConfig>) -> std::io::Result<Self> {
+        let inner = ConductorSubscriber::connect(uri, LibrettoTopic, tls).await?;
+        Ok(Self { inner, filter: LibrettoFilter::default() })
+    }
+
+    /// Only yield events the filter matches.
+    pub fn with_filter(mut self, filter: LibrettoFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Adapts the subscriber into a `Stream` of single events. The stream
+    /// ends after the first error.
+    pub fn into_stream(self) -> impl Stream<Item = std::io::Result<LibrettoEvent>> {
+        futures::stream::unfold(
+            Some((self, VecDeque::new())),
+            |state| async move {
+                let (mut subscriber, mut pending) = state?;
+                loop {
+                    if let Some(event) = pending.pop_front() {
+                        return Some((Ok(event), Some((subscriber, pending))))
+                    }
+                    match subscriber.receive().await {
+                        Ok(events) => pending.extend(events),
+                        Err(e) => return Some((Err(e), None)),
+                    }
+                }
+            }
+        )
+    }
+}
+
+/// Selects `LibrettoEvent`s by action and by instance. An empty list lets
+/// everything through on that axis.
+#[derive(Clone, Debug, Default)]
+pub struct LibrettoFilter {
+    actions: Vec<Discriminant<VmmAction>>,
+    instances: Vec<String>
+}
+
+impl LibrettoFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Matches on the action variant only, so `Degraded(String::new())`
+    /// selects every degraded notice.
+    pub fn action(mut self, action: &VmmAction) -> Self {
+        self.actions.push(std::mem::discriminant(action));
+        self
+    }
+
+    /// Matches either the bare or the project qualified instance name.
+    pub fn instance(mut self, name: &str) -> Self {
+        self.instances.push(name.to_string());
+        self
+    }
+
+    pub fn matches(&self, event: &LibrettoEvent) -> bool {
+        if !self.actions.is_empty() && !self.actions.contains(&std::mem::discriminant(event.action())) {
+            return false
+        }
+        if self.instances.is_empty() {
+            return true
+        }
+
+        let qualified = event.instance().as_ref().map(|i| i.qualified_name());
+        self.instances.iter().any(|name| {
+            event.instance_name().as_deref() == Some(name.as_str())
+                || qualified.as_deref() == Some(name.as_str())
+        })
+    }
+}
+
+#[async_trait]
+impl SubStream for LibrettoSubscriber {
+    type Message = Vec<LibrettoEvent>;
+
+    async fn receive(&mut self) -> std::io::Result<Self::Message> {
+        loop {
+            let results: Vec<LibrettoEvent> = self.inner.receive().await?
+                .into_iter()
+                .filter(|event| self.filter.matches(event))
+                .collect();
+            if !results.is_empty() {
+                return Ok(results)
+            }
+        }
+    }
+
+    async fn parse_messages(msg: &mut Vec<u8>) -> std::io::Result<Self::Message> {
+        ConductorSubscriber::<LibrettoEvent, WireCodec>::parse_messages(msg).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use notify::event::{CreateKind, EventKind};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use crate::watcher::resolver::InstanceKind;
+
+    fn event(action: VmmAction, instance: Option<(&str, Option<&str>)>) -> LibrettoEvent {
+        let instance = instance.map(|(name, project)| InstancePath {
+            name: name.to_string(),
+            project: project.map(str::to_string),
+            kind: InstanceKind::Container,
+            snapshot: None,
+            path: PathBuf::from("/etc/hosts"),
+            in_rootfs: true,
+        });
+        let event = Event::new(EventKind::Create(CreateKind::File)).add_path(PathBuf::from("/etc/hosts"));
+        LibrettoEvent::new(event, action, instance)
+    }
+
+    fn label(event: &LibrettoEvent) -> (String, Option<String>) {
+        (format!("{:?}", event.action()), event.instance_name().clone())
+    }
+
+    #[test]
+    fn filters_match_on_action_variant_and_instance_name() {
+        let everything = LibrettoFilter::new();
+        assert!(everything.matches(&event(VmmAction::Rollup, None)));
+
+        let degraded = LibrettoFilter::new().action(&VmmAction::Degraded(String::new()));
+        assert!(degraded.matches(&event(VmmAction::Degraded("overflow".to_string()), None)));
+        assert!(!degraded.matches(&event(VmmAction::Other("overflow".to_string()), None)));
+
+        let c1 = LibrettoFilter::new().instance("c1");
+        assert!(c1.matches(&event(VmmAction::Copy, Some(("c1", None)))));
+        assert!(c1.matches(&event(VmmAction::Copy, Some(("c1", Some("web"))))));
+        assert!(!c1.matches(&event(VmmAction::Copy, Some(("c2", None)))));
+        assert!(!c1.matches(&event(VmmAction::Copy, None)));
+
+        let qualified = LibrettoFilter::new().instance("web_c1").action(&VmmAction::Snapshot);
+        assert!(qualified.matches(&event(VmmAction::Snapshot, Some(("c1", Some("web"))))));
+        assert!(!qualified.matches(&event(VmmAction::Snapshot, Some(("c1", None)))));
+        assert!(!qualified.matches(&event(VmmAction::Copy, Some(("c1", Some("web"))))));
+    }
+
+    #[tokio::test]
+    async fn subscribers_stream_only_matching_events() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let events = vec![
+            event(VmmAction::Copy, Some(("c1", None))),
+            event(VmmAction::Rollup, Some(("c1", None))),
+            event(VmmAction::Copy, Some(("c3", None))),
+            event(VmmAction::Snapshot, Some(("c2", Some("web")))),
+            event(VmmAction::Copy, None),
+            event(VmmAction::Snapshot, Some(("c1", None))),
+        ];
+        let broker = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut topic = vec![0; LibrettoTopic.to_string().len()];
+            socket.read_exact(&mut topic).await.unwrap();
+            assert_eq!(topic, LibrettoTopic.to_string().as_bytes());
+
+            let codec = WireCodec::default();
+            let mut bytes = Vec::new();
+            for event in &events {
+                bytes.extend(encode_frame(&LibrettoTopic.to_string(), &codec.encode(event).unwrap()));
+            }
+            // Split mid-frame, so events arrive across several reads.
+            let (first, second) = bytes.split_at(bytes.len() / 2 + 3);
+            socket.write_all(first).await.unwrap();
+            socket.flush().await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+            socket.write_all(second).await.unwrap();
+            socket
+        });
+
+        let filter = LibrettoFilter::new()
+            .action(&VmmAction::Copy)
+            .action(&VmmAction::Snapshot)
+            .instance("c1")
+            .instance("web_c2");
+        let subscriber = LibrettoSubscriber::new(&addr.to_string()).await.unwrap().with_filter(filter);
+        let stream = subscriber.into_stream();
+        futures::pin_mut!(stream);
+
+        let mut received = Vec::new();
+        for _ in 0..3 {
+            let event = tokio::time::timeout(std::time::Duration::from_secs(5), stream.next()).await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            received.push(label(&event));
+        }
+        assert_eq!(received, vec![
+            ("Copy".to_string(), Some("c1".to_string())),
+            ("Snapshot".to_string(), Some("c2".to_string())),
+            ("Snapshot".to_string(), Some("c1".to_string())),
+        ]);
+        let _socket = broker.await.unwrap();
+    }
+}
@@ -0,0 +1,412 @@
+use notify::event::{Event, Flag};
+use prost::Message;
+use std::ffi::OsString;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+use crate::batch::ChangeSet;
+use crate::migration::{MigrationPhase, MigrationProgress};
+use crate::rollup::{PathMetadata, Rollup};
+use crate::watcher::resolver::{InstanceKind, InstancePath};
+use crate::wire;
+
+use super::codec::ProtoMessage;
+use super::kind::{kind_name, parse_kind_name};
+use super::{ActionOutcome, FilesystemEvent, LibrettoEvent, OutcomeStatus, VmmAction};
+
+fn invalid(reason: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("invalid protobuf message: {reason}")
+    )
+}
+
+fn decode<T: Message + Default>(bytes: &[u8]) -> std::io::Result<T> {
+    T::decode(bytes).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            e
+        )
+    })
+}
+
+fn path_bytes(path: &Path) -> Vec<u8> {
+    path.as_os_str().as_bytes().to_vec()
+}
+
+fn path_from(bytes: Vec<u8>) -> PathBuf {
+    PathBuf::from(OsString::from_vec(bytes))
+}
+
+fn instance_from(instance: Option<wire::InstancePath>) -> std::io::Result<Option<InstancePath>> {
+    instance.map(InstancePath::try_from).transpose()
+}
+
+impl From<&Event> for wire::Event {
+    fn from(event: &Event) -> Self {
+        Self {
+            kind: kind_name(&event.kind),
+            paths: event.paths.iter().map(|path| path_bytes(path)).collect(),
+            tracker: event.tracker().map(|tracker| tracker as u64),
+            need_rescan: event.need_rescan(),
+            info: event.info().map(str::to_string),
+            process_id: event.attrs.process_id(),
+            source: event.source().map(str::to_string),
+        }
+    }
+}
+
+impl TryFrom<wire::Event> for Event {
+    type Error = std::io::Error;
+
+    fn try_from(event: wire::Event) -> Result<Self, Self::Error> {
+        let kind = parse_kind_name(&event.kind)
+            .ok_or_else(|| invalid(&format!("unknown event kind {}", event.kind)))?;
+        let mut decoded = Event::new(kind);
+        if let Some(source) = event.source {
+            // notify only lets the source be set through its serde form.
+            decoded.attrs = serde_json::from_value(serde_json::json!({ "source": source }))
+                .map_err(|e| invalid(&format!("event source: {e}")))?;
+        }
+        decoded.paths = event.paths.into_iter().map(path_from).collect();
+        if let Some(tracker) = event.tracker {
+            decoded = decoded.set_tracker(tracker as usize);
+        }
+        if event.need_rescan {
+            decoded = decoded.set_flag(Flag::Rescan);
+        }
+        if let Some(info) = &event.info {
+            decoded = decoded.set_info(info);
+        }
+        if let Some(process_id) = event.process_id {
+            decoded = decoded.set_process_id(process_id);
+        }
+        Ok(decoded)
+    }
+}
+
+impl From<&InstancePath> for wire::InstancePath {
+    fn from(instance: &InstancePath) -> Self {
+        let kind = match instance.kind {
+            InstanceKind::Container => wire::InstanceKind::Container,
+            InstanceKind::VirtualMachine => wire::InstanceKind::VirtualMachine,
+        };
+        Self {
+            name: instance.name.clone(),
+            project: instance.project.clone(),
+            kind: kind as i32,
+            snapshot: instance.snapshot.clone(),
+            path: path_bytes(&instance.path),
+            in_rootfs: instance.in_rootfs,
+        }
+    }
+}
+
+impl TryFrom<wire::InstancePath> for InstancePath {
+    type Error = std::io::Error;
+
+    fn try_from(instance: wire::InstancePath) -> Result<Self, Self::Error> {
+        let kind = match wire::InstanceKind::try_from(instance.kind) {
+            Ok(wire::InstanceKind::Container) => InstanceKind::Container,
+            Ok(wire::InstanceKind::VirtualMachine) => InstanceKind::VirtualMachine,
+            Err(_) => return Err(invalid(&format!("unknown instance kind {}", instance.kind))),
+        };
+        Ok(Self {
+            name: instance.name,
+            project: instance.project,
+            kind,
+            snapshot: instance.snapshot,
+            path: path_from(instance.path),
+            in_rootfs: instance.in_rootfs,
+        })
+    }
+}
+
+impl From<&VmmAction> for wire::VmmAction {
+    fn from(action: &VmmAction) -> Self {
+        use wire::vmm_action::Kind;
+
+        let mut encoded = Self::default();
+        match action {
+            VmmAction::Copy => encoded.set_kind(Kind::Copy),
+            VmmAction::Move { from, to } => {
+                encoded.set_kind(Kind::Move);
+                encoded.from = path_bytes(from);
+                encoded.to = path_bytes(to);
+            }
+            VmmAction::Migrate => encoded.set_kind(Kind::Migrate),
+            VmmAction::Snapshot => encoded.set_kind(Kind::Snapshot),
+            VmmAction::Rollup => encoded.set_kind(Kind::Rollup),
+            VmmAction::Degraded(reason) => {
+                encoded.set_kind(Kind::Degraded);
+                encoded.detail = reason.clone();
+            }
+            VmmAction::Other(name) => {
+                encoded.set_kind(Kind::Other);
+                encoded.detail = name.clone();
+            }
+        }
+        encoded
+    }
+}
+
+impl TryFrom<wire::VmmAction> for VmmAction {
+    type Error = std::io::Error;
+
+    fn try_from(action: wire::VmmAction) -> Result<Self, Self::Error> {
+        use wire::vmm_action::Kind;
+
+        let kind = Kind::try_from(action.kind)
+            .map_err(|_| invalid(&format!("unknown action kind {}", action.kind)))?;
+        Ok(match kind {
+            Kind::Copy => VmmAction::Copy,
+            Kind::Move => VmmAction::Move { from: path_from(action.from), to: path_from(action.to) },
+            Kind::Migrate => VmmAction::Migrate,
+            Kind::Snapshot => VmmAction::Snapshot,
+            Kind::Rollup => VmmAction::Rollup,
+            Kind::Degraded => VmmAction::Degraded(action.detail),
+            Kind::Other => VmmAction::Other(action.detail),
+        })
+    }
+}
+
+impl From<&ChangeSet> for wire::ChangeSet {
+    fn from(changes: &ChangeSet) -> Self {
+        Self {
+            instance: changes.instance.as_ref().map(Into::into),
+            added: changes.added.iter().map(|path| path_bytes(path)).collect(),
+            modified: changes.modified.iter().map(|path| path_bytes(path)).collect(),
+            removed: changes.removed.iter().map(|path| path_bytes(path)).collect(),
+            metadata: changes.metadata.iter().map(|path| path_bytes(path)).collect(),
+        }
+    }
+}
+
+impl TryFrom<wire::ChangeSet> for ChangeSet {
+    type Error = std::io::Error;
+
+    fn try_from(changes: wire::ChangeSet) -> Result<Self, Self::Error> {
+        Ok(Self {
+            instance: instance_from(changes.instance)?,
+            added: changes.added.into_iter().map(path_from).collect(),
+            modified: changes.modified.into_iter().map(path_from).collect(),
+            removed: changes.removed.into_iter().map(path_from).collect(),
+            metadata: changes.metadata.into_iter().map(path_from).collect(),
+        })
+    }
+}
+
+impl From<&ActionOutcome> for wire::ActionOutcome {
+    fn from(outcome: &ActionOutcome) -> Self {
+        let failure = match &outcome.status {
+            OutcomeStatus::Completed => None,
+            OutcomeStatus::Failed(reason) => Some(reason.clone()),
+        };
+        Self {
+            failure,
+            operation_id: outcome.operation_id.clone(),
+            resource: outcome.resource.clone(),
+        }
+    }
+}
+
+impl From<wire::ActionOutcome> for ActionOutcome {
+    fn from(outcome: wire::ActionOutcome) -> Self {
+        match outcome.failure {
+            Some(reason) => ActionOutcome::failed(reason, outcome.operation_id, outcome.resource),
+            None => ActionOutcome::completed(outcome.operation_id, outcome.resource),
+        }
+    }
+}
+
+impl From<&PathMetadata> for wire::PathMetadata {
+    fn from(metadata: &PathMetadata) -> Self {
+        Self {
+            mode: metadata.mode,
+            uid: metadata.uid,
+            gid: metadata.gid,
+            size: metadata.size,
+            mtime_ns: metadata.mtime_ns,
+            atime_ns: metadata.atime_ns,
+        }
+    }
+}
+
+impl From<wire::PathMetadata> for PathMetadata {
+    fn from(metadata: wire::PathMetadata) -> Self {
+        Self {
+            mode: metadata.mode,
+            uid: metadata.uid,
+            gid: metadata.gid,
+            size: metadata.size,
+            mtime_ns: metadata.mtime_ns,
+            atime_ns: metadata.atime_ns,
+        }
+    }
+}
+
+impl From<&Rollup> for wire::Rollup {
+    fn from(rollup: &Rollup) -> Self {
+        Self {
+            instance: rollup.instance.as_ref().map(Into::into),
+            paths: rollup.paths.iter().map(|(path, metadata)| wire::RollupPath {
+                path: path_bytes(path),
+                metadata: metadata.as_ref().map(Into::into),
+            }).collect(),
+        }
+    }
+}
+
+impl TryFrom<wire::Rollup> for Rollup {
+    type Error = std::io::Error;
+
+    fn try_from(rollup: wire::Rollup) -> Result<Self, Self::Error> {
+        Ok(Self {
+            instance: instance_from(rollup.instance)?,
+            paths: rollup.paths.into_iter()
+                .map(|entry| (path_from(entry.path), entry.metadata.map(Into::into)))
+                .collect(),
+        })
+    }
+}
+
+impl From<&MigrationProgress> for wire::MigrationProgress {
+    fn from(progress: &MigrationProgress) -> Self {
+        let (phase, failure) = match &progress.phase {
+            MigrationPhase::Prepare => (wire::MigrationPhase::Prepare, String::new()),
+            MigrationPhase::BulkTransfer => (wire::MigrationPhase::BulkTransfer, String::new()),
+            MigrationPhase::CatchUp => (wire::MigrationPhase::CatchUp, String::new()),
+            MigrationPhase::Freeze => (wire::MigrationPhase::Freeze, String::new()),
+            MigrationPhase::FinalDelta => (wire::MigrationPhase::FinalDelta, String::new()),
+            MigrationPhase::Launch => (wire::MigrationPhase::Launch, String::new()),
+            MigrationPhase::Cleanup => (wire::MigrationPhase::Cleanup, String::new()),
+            MigrationPhase::Completed => (wire::MigrationPhase::Completed, String::new()),
+            MigrationPhase::Failed(reason) => (wire::MigrationPhase::Failed, reason.clone()),
+        };
+        Self {
+            id: progress.id.clone(),
+            phase: phase as i32,
+            failure,
+            target_node: progress.target_node.clone(),
+            rounds: progress.rounds as u64,
+        }
+    }
+}
+
+impl TryFrom<wire::MigrationProgress> for MigrationProgress {
+    type Error = std::io::Error;
+
+    fn try_from(progress: wire::MigrationProgress) -> Result<Self, Self::Error> {
+        let phase = match wire::MigrationPhase::try_from(progress.phase) {
+            Ok(wire::MigrationPhase::Prepare) => MigrationPhase::Prepare,
+            Ok(wire::MigrationPhase::BulkTransfer) => MigrationPhase::BulkTransfer,
+            Ok(wire::MigrationPhase::CatchUp) => MigrationPhase::CatchUp,
+            Ok(wire::MigrationPhase::Freeze) => MigrationPhase::Freeze,
+            Ok(wire::MigrationPhase::FinalDelta) => MigrationPhase::FinalDelta,
+            Ok(wire::MigrationPhase::Launch) => MigrationPhase::Launch,
+            Ok(wire::MigrationPhase::Cleanup) => MigrationPhase::Cleanup,
+            Ok(wire::MigrationPhase::Completed) => MigrationPhase::Completed,
+            Ok(wire::MigrationPhase::Failed) => MigrationPhase::Failed(progress.failure),
+            Err(_) => return Err(invalid(&format!("unknown migration phase {}", progress.phase))),
+        };
+        Ok(Self {
+            id: progress.id,
+            phase,
+            target_node: progress.target_node,
+            rounds: progress.rounds as usize,
+        })
+    }
+}
+
+impl ProtoMessage for FilesystemEvent {
+    fn encode_proto(&self) -> std::io::Result<Vec<u8>> {
+        Ok(wire::FilesystemEvent {
+            event: Some((&self.event).into()),
+            instance: self.instance.as_ref().map(Into::into),
+            destination: self.destination.as_ref().map(Into::into),
+        }.encode_to_vec())
+    }
+
+    fn decode_proto(bytes: &[u8]) -> std::io::Result<Self> {
+        let decoded: wire::FilesystemEvent = decode(bytes)?;
+        Ok(Self {
+            event: decoded.event.ok_or_else(|| invalid("missing event"))?.try_into()?,
+            instance: instance_from(decoded.instance)?,
+            destination: instance_from(decoded.destination)?,
+        })
+    }
+}
+
+impl ProtoMessage for LibrettoEvent {
+    fn encode_proto(&self) -> std::io::Result<Vec<u8>> {
+        Ok(wire::LibrettoEvent {
+            event: Some((&self.event).into()),
+            action: Some((&self.action).into()),
+            instance_name: self.instance_name.clone(),
+            instance: self.instance.as_ref().map(Into::into),
+            destination: self.destination.as_ref().map(Into::into),
+            changes: self.changes.as_ref().map(Into::into),
+            outcome: self.outcome.as_ref().map(Into::into),
+            rollup: self.rollup.as_ref().map(Into::into),
+            migration: self.migration.as_ref().map(Into::into),
+        }.encode_to_vec())
+    }
+
+    fn decode_proto(bytes: &[u8]) -> std::io::Result<Self> {
+        let decoded: wire::LibrettoEvent = decode(bytes)?;
+        Ok(Self {
+            event: decoded.event.ok_or_else(|| invalid("missing event"))?.try_into()?,
+            action: decoded.action.ok_or_else(|| invalid("missing action"))?.try_into()?,
+            instance_name: decoded.instance_name,
+            instance: instance_from(decoded.instance)?,
+            destination: instance_from(decoded.destination)?,
+            changes: decoded.changes.map(ChangeSet::try_from).transpose()?,
+            outcome: decoded.outcome.map(Into::into),
+            rollup: decoded.rollup.map(Rollup::try_from).transpose()?,
+            migration: decoded.migration.map(MigrationProgress::try_from).transpose()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{EventAttributes, EventKind, ModifyKind, RenameMode};
+
+    use crate::pubsub::all_kinds;
+
+    #[test]
+    fn every_event_kind_survives_protobuf() {
+        for kind in all_kinds() {
+            let event = Event::new(kind).add_path(PathBuf::from("/tmp/file"));
+            let decoded = Event::try_from(wire::Event::from(&event)).unwrap();
+            assert_eq!(decoded.kind, kind);
+        }
+    }
+
+    #[test]
+    fn event_attributes_survive_protobuf() {
+        // notify only lets the source be set through its serde form.
+        let mut attrs: EventAttributes = serde_json::from_value(serde_json::json!({ "source": "inotify" })).unwrap();
+        attrs.set_tracker(7);
+        attrs.set_flag(Flag::Rescan);
+        attrs.set_info("moved");
+        attrs.set_process_id(42);
+        let mut event = Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+            .add_path(PathBuf::from(OsString::from_vec(b"/tmp/\xff".to_vec())))
+            .add_path(PathBuf::from("/tmp/to"));
+        event.attrs = attrs;
+
+        let decoded = Event::try_from(wire::Event::from(&event)).unwrap();
+        assert_eq!(decoded, event);
+        assert_eq!(decoded.source(), Some("inotify"));
+        assert_eq!(decoded.attrs.process_id(), Some(42));
+    }
+
+    #[test]
+    fn unknown_kinds_are_rejected() {
+        let mut event = wire::Event::from(&Event::new(EventKind::Any));
+        event.kind = "modify.sideways".to_string();
+        assert_eq!(Event::try_from(event).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,318 @@
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, RootCertStore};
+use serde::{Serialize, Deserialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+/// TLS settings for broker connections. The broker's certificate must chain
+/// to `ca_path`. With `cert_path` and `key_path` set the client also presents
+/// a certificate, for brokers that require mutual authentication.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TlsConfig {
+    /// PEM bundle of the CAs trusted to sign the broker's certificate.
+    pub ca_path: PathBuf,
+    /// PEM client certificate chain.
+    #[serde(default)]
+    pub cert_path: Option<PathBuf>,
+    /// PEM private key of the client certificate.
+    #[serde(default)]
+    pub key_path: Option<PathBuf>,
+    /// Name the broker's certificate is checked against. Defaults to the host
+    /// part of the broker address.
+    #[serde(default)]
+    pub server_name: Option<String>,
+}
+
+impl TlsConfig {
+    pub fn new(ca_path: impl Into<PathBuf>) -> Self {
+        Self { ca_path: ca_path.into(), cert_path: None, key_path: None, server_name: None }
+    }
+
+    pub fn with_client_auth(mut self, cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Self {
+        self.cert_path = Some(cert_path.into());
+        self.key_path = Some(key_path.into());
+        self
+    }
+
+    pub fn with_server_name(mut self, server_name: &str) -> Self {
+        self.server_name = Some(server_name.to_string());
+        self
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let contents = std::fs::read(path.as_ref())?;
+        serde_json::from_slice(&contents).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unable to parse tls config {}: {e}", path.as_ref().display())
+            )
+        })
+    }
+
+    /// Reads the certificates and key and builds the client side of a
+    /// connection to `uri`.
+    pub(crate) fn client(&self, uri: &str) -> std::io::Result<TlsClient> {
+        let mut roots = RootCertStore::empty();
+        for cert in read_certs(&self.ca_path)? {
+            roots.add(cert).map_err(|e| invalid_input(&self.ca_path, e))?;
+        }
+
+        let builder = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?
+            .with_root_certificates(roots);
+        let config = match (&self.cert_path, &self.key_path) {
+            (Some(cert_path), Some(key_path)) => builder
+                .with_client_auth_cert(read_certs(cert_path)?, read_key(key_path)?)
+                .map_err(|e| invalid_input(key_path, e))?,
+            (None, None) => builder.with_no_client_auth(),
+            _ => return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "a client certificate needs both cert_path and key_path"
+            )),
+        };
+
+        let host = match &self.server_name {
+            Some(name) => name.as_str(),
+            None => host(uri),
+        };
+        let server_name = ServerName::try_from(host.to_string()).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid tls server name {host}: {e}")
+            )
+        })?;
+
+        Ok(TlsClient { connector: TlsConnector::from(Arc::new(config)), server_name })
+    }
+}
+
+/// A configured TLS client, shared by every reconnect of a connection.
+#[derive(Clone)]
+pub(crate) struct TlsClient {
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+}
+
+impl TlsClient {
+    pub async fn connect(&self, stream: TcpStream) -> std::io::Result<TlsStream<TcpStream>> {
+        self.connector.connect(self.server_name.clone(), stream).await
+    }
+}
+
+/// `host` of `host:port`, without the brackets of an IPv6 address.
+fn host(uri: &str) -> &str {
+    let host = uri.rsplit_once(':').map_or(uri, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+fn invalid_input(path: &Path, e: impl std::fmt::Display) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!("{}: {e}", path.display())
+    )
+}
+
+fn read_certs(path: &Path) -> std::io::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = std::io::BufReader::new(std::fs::File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<std::io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(invalid_input(path, "no certificates found"))
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &Path) -> std::io::Result<PrivateKeyDer<'static>> {
+    let mut reader = std::io::BufReader::new(std::fs::File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| invalid_input(path, "no private key found"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use conductor::subscriber::SubStream;
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+    use rustls::server::WebPkiClientVerifier;
+    use rustls::ServerConfig;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    use crate::pubsub::frame::{encode_frame, FrameDecoder};
+    use crate::pubsub::transport::{ConductorPublisher, ConductorSubscriber};
+    use crate::pubsub::JsonCodec;
+
+    struct Authority {
+        cert: rcgen::Certificate,
+        key: KeyPair,
+    }
+
+    impl Authority {
+        fn new() -> Self {
+            let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let key = KeyPair::generate().unwrap();
+            Self { cert: params.self_signed(&key).unwrap(), key }
+        }
+
+        fn issue(&self, name: &str) -> (rcgen::Certificate, KeyPair) {
+            let key = KeyPair::generate().unwrap();
+            let cert = CertificateParams::new(vec![name.to_string()]).unwrap()
+                .signed_by(&key, &self.cert, &self.key)
+                .unwrap();
+            (cert, key)
+        }
+    }
+
+    /// PEM files for a broker signed by one CA and a client signed by another,
+    /// so each side has to trust the right one.
+    struct Pki {
+        dir: tempfile::TempDir,
+        server_ca: Authority,
+        client_ca: Authority,
+    }
+
+    impl Pki {
+        fn new() -> Self {
+            let pki = Self { dir: tempfile::tempdir().unwrap(), server_ca: Authority::new(), client_ca: Authority::new() };
+            let (client_cert, client_key) = pki.client_ca.issue("libretto");
+            pki.write("ca.pem", &pki.server_ca.cert.pem());
+            pki.write("client.pem", &client_cert.pem());
+            pki.write("client.key", &client_key.serialize_pem());
+            pki
+        }
+
+        fn write(&self, name: &str, contents: &str) -> PathBuf {
+            let path = self.dir.path().join(name);
+            std::fs::write(&path, contents).unwrap();
+            path
+        }
+
+        fn path(&self, name: &str) -> PathBuf {
+            self.dir.path().join(name)
+        }
+
+        fn config(&self, client_auth: bool) -> TlsConfig {
+            let config = TlsConfig::new(self.path("ca.pem")).with_server_name("localhost");
+            match client_auth {
+                true => config.with_client_auth(self.path("client.pem"), self.path("client.key")),
+                false => config,
+            }
+        }
+
+        /// A broker serving a certificate from `ca`, asking for (but not
+        /// requiring) a client certificate from the client CA.
+        fn acceptor(&self, ca: &Authority) -> TlsAcceptor {
+            let provider = Arc::new(rustls::crypto::ring::default_provider());
+            let mut roots = RootCertStore::empty();
+            roots.add(self.client_ca.cert.der().clone()).unwrap();
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+                .allow_unauthenticated()
+                .build()
+                .unwrap();
+
+            let (cert, key) = ca.issue("localhost");
+            let key = PrivateKeyDer::try_from(key.serialize_der()).unwrap();
+            let config = ServerConfig::builder_with_provider(provider)
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_client_cert_verifier(verifier)
+                .with_single_cert(vec![cert.der().clone()], key)
+                .unwrap();
+            TlsAcceptor::from(Arc::new(config))
+        }
+    }
+
+    async fn listen() -> (TcpListener, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        (listener, addr)
+    }
+
+    #[tokio::test]
+    async fn messages_round_trip_over_tls() {
+        let pki = Pki::new();
+        let acceptor = pki.acceptor(&pki.server_ca);
+        let (listener, addr) = listen().await;
+        let broker = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut publisher = acceptor.accept(socket).await.unwrap();
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut subscriber = acceptor.accept(socket).await.unwrap();
+
+            let mut topic = [0u8; 5];
+            subscriber.read_exact(&mut topic).await.unwrap();
+            assert_eq!(&topic, b"topic");
+
+            let mut decoder = FrameDecoder::new();
+            let frame = loop {
+                let mut buffer = [0; 1024];
+                let n = publisher.read(&mut buffer).await.unwrap();
+                decoder.extend(&buffer[..n]);
+                if let Some(frame) = decoder.next_frame().unwrap() {
+                    break frame
+                }
+            };
+            subscriber.write_all(&encode_frame(&frame.topic, &frame.payload)).await.unwrap();
+            (publisher, subscriber)
+        });
+
+        let mut publisher = ConductorPublisher::<String, String, JsonCodec>::connect(&addr, Some(&pki.config(false))).await.unwrap();
+        let mut subscriber = ConductorSubscriber::<String, JsonCodec>::connect(&addr, "topic", Some(&pki.config(false))).await.unwrap();
+        publisher.publish_to("topic", &"hello".to_string()).await.unwrap();
+        publisher.flush().await.unwrap();
+
+        let received = tokio::time::timeout(std::time::Duration::from_secs(5), subscriber.receive()).await.unwrap().unwrap();
+        assert_eq!(received, vec!["hello".to_string()]);
+        let _streams = broker.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn untrusted_brokers_are_refused() {
+        let pki = Pki::new();
+        let stranger = Authority::new();
+        let acceptor = pki.acceptor(&stranger);
+        let (listener, addr) = listen().await;
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let _ = acceptor.accept(socket).await;
+        });
+
+        let result = ConductorPublisher::<String, String, JsonCodec>::connect(&addr, Some(&pki.config(false))).await;
+        let e = result.err().expect("connected to a broker signed by an unknown CA");
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData, "{e}");
+    }
+
+    #[tokio::test]
+    async fn client_certificates_are_presented_when_configured() {
+        let pki = Pki::new();
+        let acceptor = pki.acceptor(&pki.server_ca);
+        let (listener, addr) = listen().await;
+        let broker = tokio::spawn(async move {
+            let mut presented = Vec::new();
+            for _ in 0..2 {
+                let (socket, _) = listener.accept().await.unwrap();
+                let stream = acceptor.accept(socket).await.unwrap();
+                presented.push(stream.get_ref().1.peer_certificates().map(|certs| certs.len()));
+            }
+            presented
+        });
+
+        let _with = ConductorPublisher::<String, String, JsonCodec>::connect(&addr, Some(&pki.config(true))).await.unwrap();
+        let _without = ConductorPublisher::<String, String, JsonCodec>::connect(&addr, Some(&pki.config(false))).await.unwrap();
+        assert_eq!(broker.await.unwrap(), vec![Some(1), None]);
+    }
+
+    #[test]
+    fn half_configured_client_auth_is_rejected() {
+        let pki = Pki::new();
+        let mut config = pki.config(true);
+        config.key_path = None;
+        let e = config.client("127.0.0.1:7000").err().unwrap();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,435 @@
+use conductor::{publisher::PubStream, subscriber::SubStream};
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc::UnboundedSender;
+use tonic::async_trait;
+
+use super::codec::{Codec, Encoding, JsonCodec, WireCodec};
+use super::connection::{BackoffConfig, Connection, ConnectionEvent, ConnectionState};
+use super::frame::{encode_frame, Frame, FrameDecoder};
+use super::tls::TlsConfig;
+
+/// Frames a publisher holds while the broker is unreachable, unless
+/// configured otherwise.
+pub const DEFAULT_OUTBOX_CAPACITY: usize = 1024;
+
+/// Publishes `M`s to a conductor broker on topics of type `T`.
+///
+/// Published messages go through a bounded outbox. While the broker is
+/// unreachable they stay there and `publish` returns without waiting; they
+/// are sent, in order, by the first publish or flush after the connection is
+/// back. When the outbox is full the oldest message is dropped to make room,
+/// counted in `dropped` and reported as a `ConnectionState::Dropped` event.
+/// Callers that must know a message reached the broker call `flush` after
+/// publishing it.
+pub struct ConductorPublisher<T, M, C = JsonCodec> {
+    connection: Connection,
+    codec: C,
+    outbox: VecDeque<Vec<u8>>,
+    outbox_capacity: usize,
+    dropped: u64,
+    _marker: PhantomData<fn() -> (T, M)>
+}
+
+impl<T, M, C: Default> ConductorPublisher<T, M, C> {
+    pub async fn new(uri: &str) -> std::io::Result<Self> {
+        Self::connect(uri, None).await
+    }
+
+    /// Connects over TLS when `tls` is set, including every reconnect.
+    pub async fn connect(uri: &str, tls: Option<&TlsConfig>) -> std::io::Result<Self> {
+        let tls = tls.map(|tls| tls.client(uri)).transpose()?;
+        let connection = Connection::open(uri, None, tls).await?;
+        Ok(Self {
+            connection,
+            codec: C::default(),
+            outbox: VecDeque::new(),
+            outbox_capacity: DEFAULT_OUTBOX_CAPACITY,
+            dropped: 0,
+            _marker: PhantomData
+        })
+    }
+}
+
+impl<T, M> ConductorPublisher<T, M, WireCodec> {
+    /// Encodes messages published from now on with `encoding`.
+    pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.codec = WireCodec::new(encoding);
+        self
+    }
+}
+
+impl<T, M, C: Codec<M>> ConductorPublisher<T, M, C> {
+    pub fn with_codec<D: Codec<M>>(self, codec: D) -> ConductorPublisher<T, M, D> {
+        ConductorPublisher {
+            connection: self.connection,
+            codec,
+            outbox: self.outbox,
+            outbox_capacity: self.outbox_capacity,
+            dropped: self.dropped,
+            _marker: PhantomData
+        }
+    }
+
+    pub fn with_backoff(mut self, config: BackoffConfig) -> Self {
+        self.connection.set_backoff(config);
+        self
+    }
+
+    /// Holds at most `capacity` unsent messages, at least one.
+    pub fn with_outbox_capacity(mut self, capacity: usize) -> Self {
+        self.outbox_capacity = capacity.max(1);
+        self
+    }
+
+    /// Reports every change in the connection's state to `events`.
+    pub fn with_state_events(mut self, events: UnboundedSender<ConnectionEvent>) -> Self {
+        self.connection.set_events(events);
+        self
+    }
+
+    /// Messages waiting for the broker.
+    pub fn buffered(&self) -> usize {
+        self.outbox.len()
+    }
+
+    /// Messages dropped because the outbox was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Empties the outbox, for callers about to publish its messages again
+    /// from their own records. Returns how many were discarded.
+    pub fn discard_buffered(&mut self) -> usize {
+        let discarded = self.outbox.len();
+        self.outbox.clear();
+        discarded
+    }
+
+    /// Replaces the broker connection with a fresh one to the same address.
+    pub async fn reconnect(&mut self) -> std::io::Result<()> {
+        self.connection.reconnect().await
+    }
+
+    /// Publishes to a topic chosen at runtime rather than through `T`. Only
+    /// fails if the message cannot be encoded; a message the broker cannot
+    /// take yet is left in the outbox.
+    pub async fn publish_to(&mut self, topic: &str, msg: &M) -> std::io::Result<()> {
+        log::info!("attempting to publish event to topic: {}", topic);
+        let payload = self.codec.encode(msg)?;
+        if self.outbox.len() >= self.outbox_capacity {
+            self.outbox.pop_front();
+            self.dropped += 1;
+            log::warn!("outbox for {} is full, dropped the oldest message", self.connection.uri());
+            self.connection.emit(ConnectionState::Dropped { total: self.dropped });
+        }
+        self.outbox.push_back(encode_frame(topic, &payload));
+
+        if let Err(e) = self.try_flush().await {
+            log::warn!("holding {} messages for {}: {e}", self.outbox.len(), self.connection.uri());
+        }
+        Ok(())
+    }
+
+    /// Sends whatever the outbox holds if the broker is reachable now, making
+    /// at most one connection attempt and only once the backoff allows it.
+    pub async fn try_flush(&mut self) -> std::io::Result<()> {
+        while !self.outbox.is_empty() {
+            self.connection.try_reconnect().await?;
+            self.send_front().await?;
+        }
+        Ok(())
+    }
+
+    /// Waits until everything in the outbox has been written to the broker,
+    /// reconnecting with backoff. Fails once the reconnect attempts are used
+    /// up, with the unsent messages still in the outbox.
+    pub async fn flush(&mut self) -> std::io::Result<()> {
+        while !self.outbox.is_empty() {
+            if !self.connection.is_connected() {
+                self.connection.reconnect().await?;
+            }
+            // A failed write has already dropped the connection, so the
+            // next round reconnects.
+            let _ = self.send_front().await;
+        }
+        Ok(())
+    }
+
+    async fn send_front(&mut self) -> std::io::Result<()> {
+        let Some(frame) = self.outbox.front() else {
+            return Ok(())
+        };
+        let result = match self.connection.stream().await {
+            Ok(stream) => stream.send(frame).await,
+            Err(e) => return Err(e),
+        };
+        match result {
+            Ok(()) => {
+                self.connection.mark_healthy();
+                if let Some(frame) = self.outbox.pop_front() {
+                    log::info!("Succesfully wrote {} bytes to {}", frame.len(), self.connection.uri());
+                }
+                Ok(())
+            }
+            Err(e) => {
+                self.connection.disconnect(&e);
+                Err(e)
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<T, M, C> PubStream for ConductorPublisher<T, M, C>
+where
+    T: ToString + Send,
+    M: Send + Sync,
+    C: Codec<M>
+{
+    type Topic = T;
+    type Message<'async_trait> = M where Self: 'async_trait;
+
+    async fn publish(&mut self, topic: Self::Topic, msg: Self::Message<'async_trait>) -> std::io::Result<()> {
+        self.publish_to(&topic.to_string(), &msg).await
+    }
+}
+
+/// Receives `M`s published to one topic of a conductor broker. A lost
+/// connection is re-established with backoff and the topic subscribed to
+/// again.
+pub struct ConductorSubscriber<M, C = JsonCodec> {
+    connection: Connection,
+    decoder: FrameDecoder,
+    codec: C,
+    _marker: PhantomData<fn() -> M>
+}
+
+impl<M, C: Default> ConductorSubscriber<M, C> {
+    pub async fn new(uri: &str, topic: impl ToString) -> std::io::Result<Self> {
+        Self::connect(uri, topic, None).await
+    }
+
+    /// Connects over TLS when `tls` is set, including every reconnect.
+    pub async fn connect(uri: &str, topic: impl ToString, tls: Option<&TlsConfig>) -> std::io::Result<Self> {
+        let tls = tls.map(|tls| tls.client(uri)).transpose()?;
+        let connection = Connection::open(uri, Some(topic.to_string()), tls).await?;
+        Ok(Self { connection, decoder: FrameDecoder::new(), codec: C::default(), _marker: PhantomData })
+    }
+}
+
+impl<M: Send, C: Codec<M>> ConductorSubscriber<M, C> {
+    pub fn with_codec<D: Codec<M>>(self, codec: D) -> ConductorSubscriber<M, D> {
+        ConductorSubscriber { connection: self.connection, decoder: self.decoder, codec, _marker: PhantomData }
+    }
+
+    /// Rejects frames larger than this, header included. The connection is
+    /// dropped and re-established when one arrives.
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.decoder = FrameDecoder::new().with_max_frame_size(max_frame_size);
+        self
+    }
+
+    pub fn with_backoff(mut self, config: BackoffConfig) -> Self {
+        self.connection.set_backoff(config);
+        self
+    }
+
+    /// Reports every change in the connection's state to `events`. A
+    /// `Connected` event means messages may have been missed.
+    pub fn with_state_events(mut self, events: UnboundedSender<ConnectionEvent>) -> Self {
+        self.connection.set_events(events);
+        self
+    }
+
+    fn disconnect(&mut self, reason: &std::io::Error) {
+        self.connection.disconnect(reason);
+        // A partial frame from the old connection will never be completed.
+        self.decoder.clear();
+    }
+
+    /// Messages that fail to decode are logged and skipped.
+    fn decode(codec: &C, frames: Vec<Frame>) -> Vec<M> {
+        frames.par_iter().filter_map(|frame| {
+            codec.decode(&frame.payload).map_err(|e| {
+                log::error!("unable to decode message on topic {}: {e}", frame.topic);
+            }).ok()
+        }).collect()
+    }
+}
+
+#[async_trait]
+impl<M, C> SubStream for ConductorSubscriber<M, C>
+where
+    M: Send,
+    C: Codec<M> + Default
+{
+    type Message = Vec<M>;
+
+    /// Waits for the next batch of messages, riding out broker restarts.
+    /// Errors only once the reconnect attempts are used up.
+    async fn receive(&mut self) -> std::io::Result<Self::Message> {
+        loop {
+            let mut read_buffer = [0; 1024];
+            let n = match self.connection.stream().await?.read(&mut read_buffer).await {
+                Ok(0) => {
+                    let closed = std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "connection closed by broker"
+                    );
+                    self.disconnect(&closed);
+                    continue;
+                }
+                Ok(n) => n,
+                Err(e) => {
+                    self.disconnect(&e);
+                    continue;
+                }
+            };
+            self.connection.mark_healthy();
+
+            self.decoder.extend(&read_buffer[..n]);
+            let frames = match self.decoder.frames() {
+                Ok(frames) => frames,
+                Err(e) => {
+                    log::error!("dropping connection to {}: {e}", self.connection.uri());
+                    self.disconnect(&e.into());
+                    continue;
+                }
+            };
+            let results = Self::decode(&self.codec, frames);
+            if !results.is_empty() {
+                return Ok(results)
+            }
+        }
+    }
+
+    /// Decodes the complete frames at the front of `msg` and leaves any
+    /// partial frame in it.
+    async fn parse_messages(msg: &mut Vec<u8>) -> std::io::Result<Self::Message> {
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(msg);
+        let frames = decoder.frames()?;
+        *msg = decoder.into_inner();
+
+        Ok(Self::decode(&C::default(), frames))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    fn backoff() -> BackoffConfig {
+        BackoffConfig { initial_ms: 20, max_ms: 200, multiplier: 2.0, jitter: 0.0, max_attempts: None }
+    }
+
+    #[tokio::test]
+    async fn subscriber_reconnects_when_receive_is_cancelled_during_an_outage() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (events, mut states) = tokio::sync::mpsc::unbounded_channel();
+        let mut subscriber = ConductorSubscriber::<String, JsonCodec>::new(&addr.to_string(), "topic").await.unwrap()
+            .with_backoff(backoff())
+            .with_state_events(events);
+        let (socket, _) = listener.accept().await.unwrap();
+        drop(socket);
+        drop(listener);
+
+        // Like the client's `select!`, give up on every receive long before
+        // the backoff delay is over.
+        for _ in 0..40 {
+            let _ = tokio::time::timeout(Duration::from_millis(25), subscriber.receive()).await;
+        }
+
+        let listener = TcpListener::bind(addr).await.unwrap();
+        let broker = tokio::spawn(async move {
+            // A receive cancelled mid-dial hangs up before sending its topic.
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut topic = [0u8; 5];
+                if socket.read_exact(&mut topic).await.is_ok() {
+                    socket.write_all(&encode_frame("topic", b"\"hello\"")).await.unwrap();
+                    return socket
+                }
+            }
+        });
+
+        let mut received = None;
+        for _ in 0..200 {
+            if let Ok(Ok(messages)) = tokio::time::timeout(Duration::from_millis(25), subscriber.receive()).await {
+                received = Some(messages);
+                break;
+            }
+        }
+        assert_eq!(received, Some(vec!["hello".to_string()]));
+        let _socket = broker.await.unwrap();
+
+        let mut reconnecting = 0;
+        while let Ok(event) = states.try_recv() {
+            if let ConnectionState::Reconnecting { .. } = event.state {
+                reconnecting += 1;
+            }
+        }
+        // About one per backoff delay, not one per cancelled receive.
+        assert!(reconnecting <= 10, "{reconnecting} reconnect attempts");
+    }
+
+    #[tokio::test]
+    async fn publisher_buffers_while_the_broker_is_away() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (events, mut states) = tokio::sync::mpsc::unbounded_channel();
+        let mut publisher = ConductorPublisher::<String, String, JsonCodec>::new(&addr.to_string()).await.unwrap()
+            .with_backoff(backoff())
+            .with_outbox_capacity(2)
+            .with_state_events(events);
+        let (socket, _) = listener.accept().await.unwrap();
+        drop(socket);
+        drop(listener);
+
+        for msg in ["a", "b", "c", "d"] {
+            let msg = msg.to_string();
+            let publish = publisher.publish_to("topic", &msg);
+            tokio::time::timeout(Duration::from_millis(500), publish).await
+                .expect("publish waited for the broker")
+                .unwrap();
+        }
+        assert_eq!(publisher.buffered(), 2);
+        // The first write may still land in the dead socket's buffer.
+        assert!(publisher.dropped() >= 1);
+        let mut dropped = Vec::new();
+        while let Ok(event) = states.try_recv() {
+            if let ConnectionState::Dropped { total } = event.state {
+                assert_eq!(event.topic, None);
+                dropped.push(total);
+            }
+        }
+        assert_eq!(dropped, (1..=publisher.dropped()).collect::<Vec<_>>());
+
+        let listener = TcpListener::bind(addr).await.unwrap();
+        let broker = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut decoder = FrameDecoder::new();
+            let mut frames = Vec::new();
+            while frames.len() < 2 {
+                let mut buffer = [0; 1024];
+                let n = socket.read(&mut buffer).await.unwrap();
+                decoder.extend(&buffer[..n]);
+                frames.extend(decoder.frames().unwrap());
+            }
+            frames
+        });
+
+        tokio::time::timeout(Duration::from_secs(5), publisher.flush()).await.unwrap().unwrap();
+        assert_eq!(publisher.buffered(), 0);
+
+        let payloads: Vec<_> = broker.await.unwrap().into_iter().map(|frame| frame.payload).collect();
+        assert_eq!(payloads, [b"\"c\"".to_vec(), b"\"d\"".to_vec()]);
+    }
+}
@@ -0,0 +1,154 @@
+use chrono::{DateTime, Utc};
+use notify::Event;
+use serde::{Serialize, Deserialize};
+use std::io::Write;
+use std::path::PathBuf;
+
+use crate::pubsub::{kind_name, LibrettoEvent};
+use crate::watcher::resolver::InstancePath;
+
+/// Where a dry run writes what the client decided.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportSink {
+    Stdout,
+    /// A JSON lines file, appended to.
+    File(PathBuf),
+}
+
+impl ReportSink {
+    /// `-` or `stdout` for stdout, anything else is a file path.
+    pub fn parse(target: &str) -> Self {
+        match target {
+            "-" | "stdout" => ReportSink::Stdout,
+            path => ReportSink::File(PathBuf::from(path)),
+        }
+    }
+
+    pub fn open(&self) -> std::io::Result<Report> {
+        let writer: Box<dyn Write + Send> = match self {
+            ReportSink::Stdout => Box::new(std::io::stdout()),
+            ReportSink::File(path) => {
+                if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+                    std::fs::create_dir_all(parent)?;
+                }
+                Box::new(std::fs::OpenOptions::new().create(true).append(true).open(path)?)
+            }
+        };
+        Ok(Report { writer })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Decision {
+    /// The event would have been sent to the handlers and the broker.
+    Publish,
+    /// A result event, such as a migration progress report.
+    Outcome,
+    Log,
+    Drop,
+}
+
+/// One line of a dry-run report.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReportEntry {
+    pub at: DateTime<Utc>,
+    pub decision: Decision,
+    pub kind: String,
+    pub paths: Vec<PathBuf>,
+    pub instance: Option<String>,
+    /// What would have been published, for `Publish` and `Outcome`.
+    pub event: Option<LibrettoEvent>,
+}
+
+impl ReportEntry {
+    pub fn published(decision: Decision, event: LibrettoEvent) -> Self {
+        Self {
+            at: Utc::now(),
+            decision,
+            kind: kind_name(&event.event().kind),
+            paths: event.event().paths.clone(),
+            instance: event.instance().as_ref().map(|i| i.qualified_name()),
+            event: Some(event),
+        }
+    }
+
+    pub fn skipped(decision: Decision, event: &Event, instance: Option<&InstancePath>) -> Self {
+        Self {
+            at: Utc::now(),
+            decision,
+            kind: kind_name(&event.kind),
+            paths: event.paths.clone(),
+            instance: instance.map(|i| i.qualified_name()),
+            event: None,
+        }
+    }
+}
+
+/// Writes report entries as JSON lines.
+pub struct Report {
+    writer: Box<dyn Write + Send>,
+}
+
+impl Report {
+    pub fn record(&mut self, entry: &ReportEntry) -> std::io::Result<()> {
+        let mut line = serde_json::to_vec(entry).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                e
+            )
+        })?;
+        line.push(b'\n');
+        self.writer.write_all(&line)?;
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{AccessKind, CreateKind, EventKind};
+
+    use crate::pubsub::VmmAction;
+
+    #[test]
+    fn sinks_are_parsed_from_their_targets() {
+        assert_eq!(ReportSink::parse("-"), ReportSink::Stdout);
+        assert_eq!(ReportSink::parse("stdout"), ReportSink::Stdout);
+        assert_eq!(ReportSink::parse("/tmp/report.jsonl"), ReportSink::File(PathBuf::from("/tmp/report.jsonl")));
+        assert_eq!(ReportSink::parse("report.jsonl"), ReportSink::File(PathBuf::from("report.jsonl")));
+    }
+
+    #[test]
+    fn entries_are_appended_as_json_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("reports/dry-run.jsonl");
+        let sink = ReportSink::File(path.clone());
+
+        let created = Event::new(EventKind::Create(CreateKind::File)).add_path(PathBuf::from("/etc/hosts"));
+        sink.open().unwrap()
+            .record(&ReportEntry::published(Decision::Publish, LibrettoEvent::new(created, VmmAction::Copy, None)))
+            .unwrap();
+        // A second report on the same file adds to it.
+        let read = Event::new(EventKind::Access(AccessKind::Read)).add_path(PathBuf::from("/etc/passwd"));
+        sink.open().unwrap()
+            .record(&ReportEntry::skipped(Decision::Log, &read, None))
+            .unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let entries: Vec<ReportEntry> = contents.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(entries.len(), 2);
+
+        assert_eq!(entries[0].decision, Decision::Publish);
+        assert_eq!(entries[0].kind, "create.file");
+        assert_eq!(entries[0].paths, [PathBuf::from("/etc/hosts")]);
+        assert!(matches!(entries[0].event.as_ref().map(|e| e.action()), Some(VmmAction::Copy)));
+
+        assert_eq!(entries[1].decision, Decision::Log);
+        assert_eq!(entries[1].kind, "access.read");
+        assert_eq!(entries[1].paths, [PathBuf::from("/etc/passwd")]);
+        assert!(entries[1].event.is_none());
+        assert!(contents.contains("\"decision\":\"log\""));
+    }
+}
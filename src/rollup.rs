@@ -0,0 +1,247 @@
+use notify::Event;
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::batch::instance_root;
+use crate::watcher::resolver::InstancePath;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct RollupConfig {
+    /// How often collected metadata changes are sent. Zero sends every
+    /// `Rollup` action on its own.
+    pub interval_ms: u64,
+    /// An instance's rollup is sent early once it covers this many paths.
+    pub max_paths: usize,
+}
+
+impl RollupConfig {
+    pub fn disabled() -> Self {
+        Self { interval_ms: 0, max_paths: 0 }
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms)
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        self.interval_ms == 0
+    }
+
+    pub fn tick(&self) -> Duration {
+        Duration::from_millis((self.interval_ms / 4).clamp(10, 1_000))
+    }
+}
+
+impl Default for RollupConfig {
+    fn default() -> Self {
+        Self { interval_ms: 30_000, max_paths: 10_000 }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PathMetadata {
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    pub mtime_ns: i64,
+    pub atime_ns: i64,
+}
+
+impl PathMetadata {
+    pub fn stat(path: &Path) -> std::io::Result<Self> {
+        let metadata = std::fs::symlink_metadata(path)?;
+        Ok(Self {
+            mode: metadata.mode(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            size: metadata.size(),
+            mtime_ns: metadata.mtime() * 1_000_000_000 + metadata.mtime_nsec(),
+            atime_ns: metadata.atime() * 1_000_000_000 + metadata.atime_nsec(),
+        })
+    }
+}
+
+/// Metadata state of every path in one instance that had metadata-only
+/// changes during a rollup interval, taken when the rollup is sent. `None`
+/// means the path no longer exists.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rollup {
+    /// The instance the paths belong to, with `path` set to `/`.
+    pub instance: Option<InstancePath>,
+    pub paths: BTreeMap<PathBuf, Option<PathMetadata>>,
+}
+
+impl Rollup {
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+}
+
+struct Pending {
+    paths: BTreeSet<PathBuf>,
+    first_seen: Instant,
+}
+
+/// Collects the paths touched by `Rollup` actions per instance, so a storm of
+/// atime updates becomes one event per instance and interval.
+pub struct RollupAggregator {
+    config: RollupConfig,
+    pending: HashMap<Option<InstancePath>, Pending>,
+}
+
+impl RollupAggregator {
+    pub fn new(config: RollupConfig) -> Self {
+        Self { config, pending: HashMap::new() }
+    }
+
+    pub fn config(&self) -> &RollupConfig {
+        &self.config
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.config.is_disabled()
+    }
+
+    /// Records the event's paths, returning the instance's rollup if it has
+    /// reached the size limit.
+    pub fn push(&mut self, instance: Option<&InstancePath>, event: &Event, now: Instant) -> Option<Rollup> {
+        let key = instance_root(instance);
+        let pending = self.pending.entry(key.clone()).or_insert_with(|| Pending {
+            paths: BTreeSet::new(),
+            first_seen: now,
+        });
+        pending.paths.extend(event.paths.iter().cloned());
+
+        if pending.paths.len() >= self.config.max_paths {
+            let pending = self.pending.remove(&key)?;
+            return Some(Self::collect(key, pending))
+        }
+
+        None
+    }
+
+    /// Removes and returns every rollup whose interval has passed.
+    pub fn drain_ready(&mut self, now: Instant) -> Vec<Rollup> {
+        let interval = self.config.interval();
+        let expired: Vec<Option<InstancePath>> = self.pending.iter()
+            .filter(|(_, pending)| now.duration_since(pending.first_seen) >= interval)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        expired.into_iter().filter_map(|key| {
+            let pending = self.pending.remove(&key)?;
+            Some(Self::collect(key, pending))
+        }).collect()
+    }
+
+    pub fn drain_all(&mut self) -> Vec<Rollup> {
+        self.pending.drain()
+            .map(|(key, pending)| Self::collect(key, pending))
+            .collect()
+    }
+
+    fn collect(instance: Option<InstancePath>, pending: Pending) -> Rollup {
+        let paths = pending.paths.into_iter().map(|path| {
+            let metadata = PathMetadata::stat(&path).ok();
+            (path, metadata)
+        }).collect();
+
+        Rollup { instance, paths }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{AccessKind, EventKind, MetadataKind, ModifyKind};
+    use std::os::unix::fs::PermissionsExt;
+
+    use crate::watcher::resolver::InstanceKind;
+
+    fn instance(name: &str) -> InstancePath {
+        InstancePath {
+            name: name.to_string(),
+            project: None,
+            kind: InstanceKind::Container,
+            snapshot: None,
+            path: PathBuf::from("/etc/hosts"),
+            in_rootfs: true,
+        }
+    }
+
+    fn touch(path: &Path) -> Event {
+        Event::new(EventKind::Access(AccessKind::Any)).add_path(path.to_path_buf())
+    }
+
+    fn chmod(path: &Path) -> Event {
+        Event::new(EventKind::Modify(ModifyKind::Metadata(MetadataKind::Permissions)))
+            .add_path(path.to_path_buf())
+    }
+
+    #[test]
+    fn paths_are_collected_per_instance_and_interval() {
+        let dir = tempfile::tempdir().unwrap();
+        let hosts = dir.path().join("hosts");
+        let motd = dir.path().join("motd");
+        let gone = dir.path().join("gone");
+        std::fs::write(&hosts, b"127.0.0.1 localhost\n").unwrap();
+        std::fs::write(&motd, b"hello\n").unwrap();
+
+        let mut rollups = RollupAggregator::new(RollupConfig { interval_ms: 100, max_paths: 100 });
+        let start = Instant::now();
+        let c1 = instance("c1");
+        let c2 = instance("c2");
+        for _ in 0..10 {
+            assert!(rollups.push(Some(&c1), &touch(&hosts), start).is_none());
+        }
+        rollups.push(Some(&c1), &chmod(&gone), start + Duration::from_millis(10));
+        rollups.push(Some(&c2), &touch(&motd), start + Duration::from_millis(50));
+
+        assert!(rollups.drain_ready(start + Duration::from_millis(99)).is_empty());
+
+        std::fs::set_permissions(&hosts, std::fs::Permissions::from_mode(0o600)).unwrap();
+        let ready = rollups.drain_ready(start + Duration::from_millis(100));
+        assert_eq!(ready.len(), 1);
+        let rollup = &ready[0];
+        assert_eq!(rollup.instance.as_ref().map(|i| (i.name.as_str(), i.path.as_path())), Some(("c1", Path::new("/"))));
+        assert_eq!(rollup.len(), 2);
+
+        // Metadata is taken when the rollup is sent, not when it was touched.
+        let metadata = rollup.paths[&hosts].as_ref().unwrap();
+        assert_eq!(metadata.mode & 0o777, 0o600);
+        assert_eq!(metadata.size, 20);
+        assert_eq!(rollup.paths[&gone], None);
+
+        let ready = rollups.drain_all();
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].paths.keys().collect::<Vec<_>>(), vec![&motd]);
+    }
+
+    #[test]
+    fn full_rollups_are_sent_early() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut rollups = RollupAggregator::new(RollupConfig { interval_ms: 30_000, max_paths: 3 });
+        let start = Instant::now();
+        for name in ["a", "b", "a"] {
+            assert!(rollups.push(None, &touch(&dir.path().join(name)), start).is_none());
+        }
+        let rollup = rollups.push(None, &touch(&dir.path().join("c")), start).unwrap();
+        assert_eq!(rollup.instance, None);
+        assert_eq!(rollup.len(), 3);
+        assert!(rollups.drain_all().is_empty());
+    }
+
+    #[test]
+    fn zero_intervals_disable_rollups() {
+        assert!(!RollupAggregator::new(RollupConfig::disabled()).is_enabled());
+        assert!(RollupAggregator::new(RollupConfig::default()).is_enabled());
+    }
+}
@@ -1,6 +1,9 @@
+use std::path::Path;
 use tonic::{Request, Response, Status};
 use crate::dfs::dfs_service_server::DfsService;
 use crate::dfs::{StoreRequest, StoreResponse, LaunchRequest, LaunchResponse, HeartbeatRequest, HeartbeatResponse, ReplicateRequest, ReplicateResponse};
+use crate::migration::TransferEntry;
+use crate::statics::STORAGE_PATH;
 
 #[derive(Default)]
 pub struct DfsRpcService;
@@ -28,10 +31,25 @@ impl DfsService for DfsRpcService {
         Ok(Response::new(HeartbeatResponse { success: true }))
     }
     
+    /// Writes migration transfers below `$STORAGE_PATH/incoming/<instance>`.
     async fn replicate(
         &self,
-        _request: Request<tonic::Streaming<ReplicateRequest>>
+        request: Request<tonic::Streaming<ReplicateRequest>>
     ) -> Result<Response<ReplicateResponse>, Status> {
+        let mut stream = request.into_inner();
+        while let Some(chunk) = stream.message().await? {
+            if chunk.instance_name.is_empty() || chunk.instance_name.contains('/') || chunk.instance_name.starts_with('.') {
+                return Err(Status::invalid_argument(format!("invalid instance name {:?}", chunk.instance_name)))
+            }
+            let root = Path::new(STORAGE_PATH.as_str()).join("incoming").join(&chunk.instance_name);
+            tokio::task::spawn_blocking(move || {
+                let (entry, data) = TransferEntry::decode(&chunk.instance_data)?;
+                entry.apply(&root, data)
+            }).await
+                .map_err(|e| Status::internal(e.to_string()))?
+                .map_err(|e| Status::internal(e.to_string()))?;
+        }
+
         Ok(Response::new(ReplicateResponse { success: true}))
     }
 }
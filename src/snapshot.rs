@@ -0,0 +1,362 @@
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Utc};
+use notify::Event;
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::lxd::LxdClient;
+use crate::pubsub::{ActionOutcome, LibrettoEvent, OutcomeStatus, VmmAction};
+use crate::watcher::resolver::InstancePath;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SnapshotConfig {
+    /// `chrono` format string for snapshot names.
+    #[serde(default = "SnapshotConfig::default_name_template")]
+    pub name_template: String,
+    /// How long LXD keeps the snapshot. `None` keeps it until deleted.
+    #[serde(default = "SnapshotConfig::default_expiry_secs")]
+    pub expiry_secs: Option<u64>,
+    /// Minimum time between two snapshots of the same instance. Requests
+    /// inside it are skipped.
+    #[serde(default = "SnapshotConfig::default_min_interval_secs")]
+    pub min_interval_secs: u64,
+    #[serde(default)]
+    pub stateful: bool,
+    #[serde(default = "SnapshotConfig::default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+impl SnapshotConfig {
+    fn default_name_template() -> String {
+        "libretto-%Y%m%d-%H%M%S".to_string()
+    }
+
+    fn default_expiry_secs() -> Option<u64> {
+        Some(7 * 24 * 60 * 60)
+    }
+
+    fn default_min_interval_secs() -> u64 {
+        300
+    }
+
+    fn default_timeout_secs() -> u64 {
+        300
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let contents = std::fs::read(path.as_ref())?;
+        let config: Self = serde_json::from_slice(&contents).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unable to parse snapshot config {}: {e}", path.as_ref().display())
+            )
+        })?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Rejects a `name_template` chrono cannot format, which would otherwise
+    /// only fail once the first snapshot is taken.
+    pub fn validate(&self) -> std::io::Result<()> {
+        if StrftimeItems::new(&self.name_template).any(|item| item == Item::Error) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("invalid snapshot name template: {}", self.name_template)
+            ))
+        }
+        Ok(())
+    }
+
+    /// The snapshot name for `now`.
+    pub fn snapshot_name(&self, now: DateTime<Utc>) -> std::io::Result<String> {
+        let mut name = String::new();
+        write!(name, "{}", now.format(&self.name_template)).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("invalid snapshot name template: {}", self.name_template)
+            )
+        })?;
+        Ok(name)
+    }
+
+    pub fn min_interval(&self) -> Duration {
+        Duration::from_secs(self.min_interval_secs)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+}
+
+impl Default for SnapshotConfig {
+    fn default() -> Self {
+        Self {
+            name_template: Self::default_name_template(),
+            expiry_secs: Self::default_expiry_secs(),
+            min_interval_secs: Self::default_min_interval_secs(),
+            stateful: false,
+            timeout_secs: Self::default_timeout_secs(),
+        }
+    }
+}
+
+/// Carries out `VmmAction::Snapshot` by snapshotting the instance in LXD and
+/// reports how it went on the outcome channel.
+#[derive(Clone)]
+pub struct SnapshotExecutor {
+    config: SnapshotConfig,
+    lxd: LxdClient,
+    last_taken: Arc<Mutex<HashMap<String, Instant>>>,
+    outcomes: UnboundedSender<LibrettoEvent>,
+}
+
+impl SnapshotExecutor {
+    pub fn new(config: SnapshotConfig, lxd: LxdClient, outcomes: UnboundedSender<LibrettoEvent>) -> Self {
+        Self { config, lxd, last_taken: Arc::new(Mutex::new(HashMap::new())), outcomes }
+    }
+
+    pub fn config(&self) -> &SnapshotConfig {
+        &self.config
+    }
+
+    /// Claims the instance's rate limit slot, returning false if a snapshot
+    /// was taken too recently.
+    fn claim(&self, instance: &InstancePath, now: Instant) -> bool {
+        let Ok(mut guard) = self.last_taken.lock() else {
+            return false
+        };
+        let key = instance.qualified_name();
+        if let Some(last) = guard.get(&key) {
+            if now.duration_since(*last) < self.config.min_interval() {
+                return false
+            }
+        }
+        guard.insert(key, now);
+        true
+    }
+
+    /// Gives back a slot claimed at `claimed` whose snapshot failed, so the
+    /// next request can retry straight away.
+    fn release(&self, instance: &InstancePath, claimed: Instant) {
+        let Ok(mut guard) = self.last_taken.lock() else {
+            return
+        };
+        let key = instance.qualified_name();
+        if guard.get(&key) == Some(&claimed) {
+            guard.remove(&key);
+        }
+    }
+
+    /// Starts a snapshot in the background. Events from inside an existing
+    /// snapshot and rate-limited requests are ignored.
+    pub fn spawn(&self, instance: InstancePath, event: Event) {
+        if instance.snapshot.is_some() {
+            return
+        }
+        let claimed = Instant::now();
+        if !self.claim(&instance, claimed) {
+            log::info!("skipping snapshot of {}, one was taken recently", instance.qualified_name());
+            return
+        }
+
+        let executor = self.clone();
+        tokio::spawn(async move {
+            let outcome = executor.execute(&instance).await;
+            if let OutcomeStatus::Failed(_) = outcome.status {
+                executor.release(&instance, claimed);
+            }
+            let event = LibrettoEvent::new(event, VmmAction::Snapshot, Some(instance)).with_outcome(outcome);
+            if executor.outcomes.send(event).is_err() {
+                log::error!("unable to report snapshot outcome, client has stopped");
+            }
+        });
+    }
+
+    pub async fn execute(&self, instance: &InstancePath) -> ActionOutcome {
+        let lxd = match &instance.project {
+            Some(project) => self.lxd.clone().with_project(project),
+            None => self.lxd.clone(),
+        };
+        let now = Utc::now();
+        let snapshot = match self.config.snapshot_name(now) {
+            Ok(snapshot) => snapshot,
+            Err(e) => return ActionOutcome::failed(e.to_string(), None, None),
+        };
+        let resource = Some(format!("{}/{}", instance.qualified_name(), snapshot));
+        let expires_at = self.config.expiry_secs.map(|secs| now + chrono::Duration::seconds(secs as i64));
+
+        log::info!("creating snapshot {} of {}", snapshot, instance.qualified_name());
+        let operation = match lxd.create_snapshot(&instance.name, &snapshot, self.config.stateful, expires_at).await {
+            Ok(operation) => operation,
+            Err(e) => return ActionOutcome::failed(e.to_string(), None, resource),
+        };
+
+        match lxd.wait(&operation.id, self.config.timeout()).await {
+            Ok(operation) => {
+                log::info!("snapshot {} of {} created", snapshot, instance.qualified_name());
+                ActionOutcome::completed(Some(operation.id), resource)
+            }
+            Err(e) => {
+                log::error!("snapshot {} of {} failed: {e}", snapshot, instance.qualified_name());
+                ActionOutcome::failed(e.to_string(), Some(operation.id), resource)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use notify::event::{EventKind, ModifyKind};
+    use std::path::PathBuf;
+
+    use crate::lxd::tests::{operation, serve, serve_with_bodies, sync};
+    use crate::watcher::resolver::InstanceKind;
+
+    fn instance(name: &str, project: Option<&str>) -> InstancePath {
+        InstancePath {
+            name: name.to_string(),
+            project: project.map(str::to_string),
+            kind: InstanceKind::Container,
+            snapshot: None,
+            path: PathBuf::from("/etc/hosts"),
+            in_rootfs: true,
+        }
+    }
+
+    fn config() -> SnapshotConfig {
+        SnapshotConfig { name_template: "snap".to_string(), timeout_secs: 5, ..Default::default() }
+    }
+
+    fn event() -> Event {
+        Event::new(EventKind::Modify(ModifyKind::Any)).add_path(PathBuf::from("/etc/hosts"))
+    }
+
+    #[test]
+    fn invalid_name_templates_are_rejected_on_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snapshot.json");
+        std::fs::write(&path, br#"{"name_template": "libretto-%Q"}"#).unwrap();
+        assert_eq!(SnapshotConfig::load(&path).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+
+        std::fs::write(&path, br#"{"name_template": "snap-%Y-%m-%d"}"#).unwrap();
+        let config = SnapshotConfig::load(&path).unwrap();
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 12, 0, 0).unwrap();
+        assert_eq!(config.snapshot_name(now).unwrap(), "snap-2024-06-01");
+    }
+
+    #[test]
+    fn the_default_template_is_valid() {
+        let config = SnapshotConfig::default();
+        config.validate().unwrap();
+        let now = Utc.with_ymd_and_hms(2024, 6, 1, 12, 30, 5).unwrap();
+        assert_eq!(config.snapshot_name(now).unwrap(), "libretto-20240601-123005");
+    }
+
+    #[test]
+    fn unformattable_templates_fail_instead_of_panicking() {
+        let config = SnapshotConfig { name_template: "%".to_string(), ..Default::default() };
+        assert!(config.validate().is_err());
+        assert!(config.snapshot_name(Utc::now()).is_err());
+    }
+
+    #[tokio::test]
+    async fn completed_snapshots_report_their_operation() {
+        let (_dir, lxd, requests, bodies) = serve_with_bodies(|_| sync(operation("Success", 200, "")));
+        let (outcomes, _results) = tokio::sync::mpsc::unbounded_channel();
+        let executor = SnapshotExecutor::new(config(), lxd, outcomes);
+
+        let before = Utc::now();
+        let outcome = executor.execute(&instance("c1", Some("web"))).await;
+        assert!(matches!(outcome.status, OutcomeStatus::Completed));
+        assert_eq!(outcome.operation_id.as_deref(), Some("op1"));
+        assert_eq!(outcome.resource.as_deref(), Some("web_c1/snap"));
+        assert_eq!(*requests.lock().unwrap(), vec![
+            "POST /1.0/instances/c1/snapshots?project=web".to_string(),
+            "GET /1.0/operations/op1/wait?timeout=5&project=web".to_string(),
+        ]);
+
+        let body: serde_json::Value = serde_json::from_str(&bodies.lock().unwrap()[0]).unwrap();
+        assert_eq!(body["name"], "snap");
+        assert_eq!(body["stateful"], false);
+        let expires_at: DateTime<Utc> = body["expires_at"].as_str().unwrap().parse().unwrap();
+        let expiry = chrono::Duration::seconds(SnapshotConfig::default_expiry_secs().unwrap() as i64);
+        assert!(expires_at >= before + expiry - chrono::Duration::seconds(1));
+        assert!(expires_at <= Utc::now() + expiry);
+    }
+
+    #[tokio::test]
+    async fn snapshots_without_expiry_omit_it() {
+        let (_dir, lxd, _requests, bodies) = serve_with_bodies(|_| sync(operation("Success", 200, "")));
+        let (outcomes, _results) = tokio::sync::mpsc::unbounded_channel();
+        let config = SnapshotConfig { expiry_secs: None, ..config() };
+        SnapshotExecutor::new(config, lxd, outcomes).execute(&instance("c1", None)).await;
+        let body: serde_json::Value = serde_json::from_str(&bodies.lock().unwrap()[0]).unwrap();
+        assert!(body.get("expires_at").is_none());
+    }
+
+    #[tokio::test]
+    async fn failed_snapshots_report_why() {
+        let (_dir, lxd, _requests) = serve(|request| match request {
+            "POST /1.0/instances/c1/snapshots" => sync(operation("Running", 103, "")),
+            _ => sync(operation("Failure", 400, "disk full")),
+        });
+        let (outcomes, _results) = tokio::sync::mpsc::unbounded_channel();
+        let outcome = SnapshotExecutor::new(config(), lxd, outcomes).execute(&instance("c1", None)).await;
+        match outcome.status {
+            OutcomeStatus::Failed(reason) => assert!(reason.contains("disk full"), "{reason}"),
+            status => panic!("unexpected status {status:?}"),
+        }
+        assert_eq!(outcome.operation_id.as_deref(), Some("op1"));
+        assert_eq!(outcome.resource.as_deref(), Some("c1/snap"));
+    }
+
+    #[tokio::test]
+    async fn snapshots_are_rate_limited_per_instance() {
+        let (_dir, lxd, requests) = serve(|_| sync(operation("Success", 200, "")));
+        let (outcomes, mut results) = tokio::sync::mpsc::unbounded_channel();
+        let executor = SnapshotExecutor::new(config(), lxd, outcomes);
+
+        executor.spawn(instance("c1", None), event());
+        executor.spawn(instance("c1", None), event());
+        executor.spawn(instance("c1", Some("web")), event());
+        let mut snapshot = instance("c2", None);
+        snapshot.snapshot = Some("snap0".to_string());
+        executor.spawn(snapshot, event());
+
+        let mut names = Vec::new();
+        for _ in 0..2 {
+            let result: LibrettoEvent = results.recv().await.unwrap();
+            assert!(matches!(result.outcome().as_ref().unwrap().status, OutcomeStatus::Completed));
+            names.push(result.instance().as_ref().unwrap().qualified_name());
+        }
+        names.sort();
+        assert_eq!(names, ["c1", "web_c1"]);
+        assert_eq!(requests.lock().unwrap().iter().filter(|r| r.starts_with("POST")).count(), 2);
+        assert!(results.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn failed_snapshots_do_not_hold_the_rate_limit() {
+        let (_dir, lxd, requests) = serve(|_| serde_json::json!({
+            "type": "error",
+            "error": "Instance is busy",
+            "error_code": 409,
+        }));
+        let (outcomes, mut results) = tokio::sync::mpsc::unbounded_channel();
+        let executor = SnapshotExecutor::new(config(), lxd, outcomes);
+
+        for _ in 0..2 {
+            executor.spawn(instance("c1", None), event());
+            let result: LibrettoEvent = results.recv().await.unwrap();
+            assert!(matches!(result.outcome().as_ref().unwrap().status, OutcomeStatus::Failed(_)));
+        }
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+}
@@ -2,8 +2,96 @@ use lazy_static::lazy_static;
 use std::env;
 
 lazy_static! {
-    static ref STORAGE_PATH: String = {
+    pub static ref STORAGE_PATH: String = {
         dotenv::dotenv().ok();
         env::var("STORAGE_PATH").unwrap_or_else(|_| "/mnt/libretto".to_string())
     };
+
+    pub static ref FILTER_CONFIG_PATH: Option<String> = {
+        dotenv::dotenv().ok();
+        env::var("LIBRETTO_FILTER_CONFIG").ok()
+    };
+
+    pub static ref WATCH_CONFIG_PATH: Option<String> = {
+        dotenv::dotenv().ok();
+        env::var("LIBRETTO_WATCH_CONFIG").ok()
+    };
+
+    /// The single directory to watch when there is no watch config.
+    pub static ref WATCH_PATH: Option<String> = {
+        dotenv::dotenv().ok();
+        env::var("LIBRETTO_WATCH_PATH").ok()
+    };
+
+    pub static ref POLICY_CONFIG_PATH: Option<String> = {
+        dotenv::dotenv().ok();
+        env::var("LIBRETTO_POLICY_CONFIG").ok()
+    };
+
+    pub static ref SNAPSHOT_CONFIG_PATH: Option<String> = {
+        dotenv::dotenv().ok();
+        env::var("LIBRETTO_SNAPSHOT_CONFIG").ok()
+    };
+
+    pub static ref REPLICA_PATH: Option<String> = {
+        dotenv::dotenv().ok();
+        env::var("LIBRETTO_REPLICA_PATH").ok()
+    };
+
+    pub static ref MIGRATION_CONFIG_PATH: Option<String> = {
+        dotenv::dotenv().ok();
+        env::var("LIBRETTO_MIGRATION_CONFIG").ok()
+    };
+
+    /// `-` or a file path. When set, the client only reports what it would
+    /// publish.
+    pub static ref DRY_RUN_REPORT: Option<String> = {
+        dotenv::dotenv().ok();
+        env::var("LIBRETTO_DRY_RUN").ok()
+    };
+
+    /// `json`, `msgpack` or `protobuf`, for everything Libretto publishes.
+    pub static ref PUBSUB_ENCODING: Option<String> = {
+        dotenv::dotenv().ok();
+        env::var("LIBRETTO_PUBSUB_ENCODING").ok()
+    };
+
+    /// Where the broker accepts publishers.
+    pub static ref BROKER_PUBLISH_ADDR: String = {
+        dotenv::dotenv().ok();
+        env::var("LIBRETTO_BROKER_PUBLISH_ADDR").unwrap_or_else(|_| "127.0.0.1:5555".to_string())
+    };
+
+    /// Where the broker accepts subscribers.
+    pub static ref BROKER_SUBSCRIBE_ADDR: String = {
+        dotenv::dotenv().ok();
+        env::var("LIBRETTO_BROKER_SUBSCRIBE_ADDR").unwrap_or_else(|_| "127.0.0.1:5556".to_string())
+    };
+
+    /// CA bundle for the broker's certificate. Broker connections use TLS
+    /// when this is set.
+    pub static ref TLS_CA_PATH: Option<String> = {
+        dotenv::dotenv().ok();
+        env::var("LIBRETTO_TLS_CA").ok()
+    };
+
+    pub static ref TLS_CERT_PATH: Option<String> = {
+        dotenv::dotenv().ok();
+        env::var("LIBRETTO_TLS_CERT").ok()
+    };
+
+    pub static ref TLS_KEY_PATH: Option<String> = {
+        dotenv::dotenv().ok();
+        env::var("LIBRETTO_TLS_KEY").ok()
+    };
+
+    pub static ref TLS_SERVER_NAME: Option<String> = {
+        dotenv::dotenv().ok();
+        env::var("LIBRETTO_TLS_SERVER_NAME").ok()
+    };
+
+    pub static ref LXD_SOCKET_PATH: String = {
+        dotenv::dotenv().ok();
+        env::var("LXD_SOCKET").unwrap_or_else(|_| "/var/snap/lxd/common/lxd/unix.socket".to_string())
+    };
 }
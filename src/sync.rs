@@ -0,0 +1,531 @@
+use filetime::FileTime;
+use serde::{Serialize, Deserialize};
+use std::fs::{Metadata, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use tonic::async_trait;
+
+use crate::handler::VmmActionHandler;
+use crate::pubsub::{ActionOutcome, LibrettoEvent, VmmAction};
+use crate::statics::STORAGE_PATH;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SyncConfig {
+    /// Where the replica lives. Defaults to `$STORAGE_PATH/replica`.
+    #[serde(default)]
+    pub replica_root: Option<PathBuf>,
+    /// Prefix stripped from source paths before they are placed under the
+    /// replica root. Paths outside it are ignored. Defaults to `/`, which
+    /// mirrors the full host path.
+    #[serde(default)]
+    pub source_root: Option<PathBuf>,
+}
+
+impl SyncConfig {
+    pub fn replica_root(&self) -> PathBuf {
+        self.replica_root.clone().unwrap_or_else(|| {
+            Path::new(STORAGE_PATH.as_str()).join("replica")
+        })
+    }
+
+    pub fn source_root(&self) -> PathBuf {
+        self.source_root.clone().unwrap_or_else(|| PathBuf::from("/"))
+    }
+}
+
+/// Applies `Copy`, `Rollup` and `Move` actions to a replica directory.
+///
+/// Every path is reconciled against the current state of the source rather
+/// than replayed from the event, so applying the same event twice, or out of
+/// date, converges on the same replica.
+///
+/// Containers control the trees being mirrored, so no path is reached through
+/// a symlinked parent on either side. Otherwise a link such as `d -> /etc`
+/// would have this process read or write host files.
+#[derive(Clone, Debug)]
+pub struct ReplicaSync {
+    replica_root: PathBuf,
+    source_root: PathBuf,
+}
+
+impl ReplicaSync {
+    pub fn new(config: &SyncConfig) -> Self {
+        Self { replica_root: config.replica_root(), source_root: config.source_root() }
+    }
+
+    pub fn replica_root(&self) -> &Path {
+        &self.replica_root
+    }
+
+    /// Where `source` lives in the replica, or `None` if it is outside the
+    /// source root or is part of the replica itself.
+    pub fn replica_path(&self, source: &Path) -> Option<PathBuf> {
+        self.relative(source).map(|relative| self.replica_root.join(relative))
+    }
+
+    fn relative(&self, source: &Path) -> Option<PathBuf> {
+        // A replica inside a watched tree would otherwise copy itself.
+        if source.starts_with(&self.replica_root) {
+            return None
+        }
+        source.strip_prefix(&self.source_root).ok().map(Path::to_path_buf)
+    }
+
+    /// Applies an event synchronously.
+    pub fn apply(&self, event: &LibrettoEvent) -> std::io::Result<()> {
+        if let Some(changes) = event.changes() {
+            for path in changes.removed.iter() {
+                self.sync_path(path)?;
+            }
+            for path in changes.added.iter().chain(changes.modified.iter()) {
+                self.sync_path(path)?;
+            }
+            for path in changes.metadata.iter() {
+                self.sync_metadata(path)?;
+            }
+            return Ok(())
+        }
+
+        match event.action() {
+            VmmAction::Move { from, to } => self.apply_move(from, to),
+            VmmAction::Rollup => {
+                for path in &event.event().paths {
+                    self.sync_metadata(path)?;
+                }
+                Ok(())
+            }
+            _ => {
+                for path in &event.event().paths {
+                    self.sync_path(path)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    async fn apply_blocking(&self, event: &LibrettoEvent) -> std::io::Result<Option<ActionOutcome>> {
+        let sync = self.clone();
+        let event = event.clone();
+        tokio::task::spawn_blocking(move || sync.apply(&event)).await.map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("replica sync panicked: {e}")
+            )
+        })??;
+
+        // Successful copies are too frequent to report individually.
+        Ok(None)
+    }
+
+    fn apply_move(&self, from: &Path, to: &Path) -> std::io::Result<()> {
+        match (self.relative(from), self.relative(to)) {
+            (Some(relative_from), Some(relative_to)) => {
+                let replica_from = self.replica_root.join(&relative_from);
+                if parents_exist(&self.replica_root, &relative_from)?
+                    && std::fs::symlink_metadata(&replica_from).is_ok()
+                {
+                    create_parents(&self.replica_root, &relative_to)?;
+                    std::fs::rename(&replica_from, self.replica_root.join(&relative_to))?;
+                }
+                // Covers a source that was never replicated and a move that
+                // was already applied, and picks up anything written since.
+                self.sync_path(to)
+            }
+            (Some(_), None) => self.sync_path(from),
+            (None, Some(_)) => self.sync_path(to),
+            (None, None) => Ok(()),
+        }
+    }
+
+    /// Makes the replica of `source` match it: copied if it exists, removed
+    /// if it does not.
+    pub fn sync_path(&self, source: &Path) -> std::io::Result<()> {
+        let Some(relative) = self.relative(source) else {
+            return Ok(())
+        };
+        let replica = self.replica_root.join(&relative);
+
+        let metadata = if parents_exist(&self.source_root, &relative)? {
+            match std::fs::symlink_metadata(source) {
+                Ok(metadata) => Some(metadata),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e),
+            }
+        } else {
+            None
+        };
+        let Some(metadata) = metadata else {
+            if parents_exist(&self.replica_root, &relative)? {
+                return remove(&replica)
+            }
+            return Ok(())
+        };
+        create_parents(&self.replica_root, &relative)?;
+
+        if metadata.is_dir() {
+            if let Ok(existing) = std::fs::symlink_metadata(&replica) {
+                if !existing.is_dir() {
+                    remove(&replica)?;
+                }
+            }
+            match std::fs::create_dir(&replica) {
+                Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => return Err(e),
+                _ => {}
+            }
+            return copy_metadata(source, &replica, &metadata)
+        }
+
+        if metadata.file_type().is_symlink() {
+            let target = std::fs::read_link(source)?;
+            let tmp = temp_path(&replica);
+            let _ = std::fs::remove_file(&tmp);
+            std::os::unix::fs::symlink(target, &tmp)?;
+            copy_metadata(source, &tmp, &metadata)?;
+            return rename_over(&tmp, &replica)
+        }
+
+        if !metadata.is_file() {
+            log::info!("not replicating special file {}", source.display());
+            return Ok(())
+        }
+
+        if let Ok(existing) = std::fs::symlink_metadata(&replica) {
+            if existing.is_file() && existing.len() == metadata.len()
+                && FileTime::from_last_modification_time(&existing)
+                    == FileTime::from_last_modification_time(&metadata)
+            {
+                return copy_metadata(source, &replica, &metadata)
+            }
+        }
+
+        let tmp = temp_path(&replica);
+        // Left behind if a previous run died mid-copy.
+        let _ = std::fs::remove_file(&tmp);
+        let result = copy_contents(source, &tmp)
+            .and_then(|()| copy_metadata(source, &tmp, &metadata))
+            .and_then(|()| rename_over(&tmp, &replica));
+        if result.is_err() {
+            let _ = std::fs::remove_file(&tmp);
+        }
+
+        result
+    }
+
+    /// Brings only ownership, mode, times and xattrs up to date, falling back
+    /// to a full sync if the replica does not have the path yet.
+    pub fn sync_metadata(&self, source: &Path) -> std::io::Result<()> {
+        let Some(relative) = self.relative(source) else {
+            return Ok(())
+        };
+        if !parents_exist(&self.source_root, &relative)?
+            || !parents_exist(&self.replica_root, &relative)?
+        {
+            return self.sync_path(source)
+        }
+        let replica = self.replica_root.join(&relative);
+
+        match (std::fs::symlink_metadata(source), std::fs::symlink_metadata(&replica)) {
+            (Ok(metadata), Ok(_)) => copy_metadata(source, &replica, &metadata),
+            _ => self.sync_path(source),
+        }
+    }
+}
+
+#[async_trait]
+impl VmmActionHandler for ReplicaSync {
+    async fn copy(&self, event: &LibrettoEvent) -> std::io::Result<Option<ActionOutcome>> {
+        self.apply_blocking(event).await
+    }
+
+    async fn rollup(&self, event: &LibrettoEvent) -> std::io::Result<Option<ActionOutcome>> {
+        self.apply_blocking(event).await
+    }
+
+    async fn other(&self, event: &LibrettoEvent) -> std::io::Result<Option<ActionOutcome>> {
+        match event.action() {
+            VmmAction::Move { .. } => self.apply_blocking(event).await,
+            _ => Ok(None),
+        }
+    }
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    path.with_file_name(format!(".{name}.libretto-tmp"))
+}
+
+fn remove(path: &Path) -> std::io::Result<()> {
+    let result = match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(path),
+        Ok(_) => std::fs::remove_file(path),
+        Err(e) => Err(e),
+    };
+
+    match result {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+fn rename_over(from: &Path, to: &Path) -> std::io::Result<()> {
+    // rename(2) cannot replace a directory with a file.
+    if let Ok(existing) = std::fs::symlink_metadata(to) {
+        if existing.is_dir() {
+            std::fs::remove_dir_all(to)?;
+        }
+    }
+    std::fs::rename(from, to)
+}
+
+fn refused(path: &Path) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("refusing to sync {} through a path that is not a directory", path.display())
+    )
+}
+
+/// Whether every directory leading to `relative` below `root` exists, failing
+/// if any of them is a symlink or anything but a directory.
+fn parents_exist(root: &Path, relative: &Path) -> std::io::Result<bool> {
+    let Some(parent) = relative.parent() else {
+        return Ok(true)
+    };
+    let mut dir = root.to_path_buf();
+    for component in parent.components() {
+        dir.push(component);
+        match std::fs::symlink_metadata(&dir) {
+            Ok(metadata) if metadata.is_dir() => {}
+            Ok(_) => return Err(refused(relative)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+/// Creates the directories leading to `relative` below `root` one at a time,
+/// failing if any of them exists as a symlink or anything but a directory.
+fn create_parents(root: &Path, relative: &Path) -> std::io::Result<()> {
+    let Some(parent) = relative.parent() else {
+        return Ok(())
+    };
+    let mut dir = root.to_path_buf();
+    for component in parent.components() {
+        dir.push(component);
+        match std::fs::symlink_metadata(&dir) {
+            Ok(metadata) if metadata.is_dir() => {}
+            Ok(_) => return Err(refused(relative)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => std::fs::create_dir(&dir)?,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+fn copy_contents(source: &Path, dest: &Path) -> std::io::Result<()> {
+    // Neither end may be swapped for a symlink between the checks and here.
+    let mut reader = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(source)?;
+    let mut writer = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(dest)?;
+    std::io::copy(&mut reader, &mut writer)?;
+    writer.flush()?;
+    writer.sync_all()
+}
+
+fn copy_metadata(source: &Path, dest: &Path, metadata: &Metadata) -> std::io::Result<()> {
+    // Changing ownership needs privileges the replica may not run with.
+    if let Err(e) = std::os::unix::fs::lchown(dest, Some(metadata.uid()), Some(metadata.gid())) {
+        log::debug!("unable to set owner of {}: {e}", dest.display());
+    }
+    if !metadata.file_type().is_symlink() {
+        std::fs::set_permissions(dest, std::fs::Permissions::from_mode(metadata.mode()))?;
+    }
+    copy_xattrs(source, dest)?;
+
+    filetime::set_symlink_file_times(
+        dest,
+        FileTime::from_last_access_time(metadata),
+        FileTime::from_last_modification_time(metadata)
+    )
+}
+
+fn copy_xattrs(source: &Path, dest: &Path) -> std::io::Result<()> {
+    if !xattr::SUPPORTED_PLATFORM {
+        return Ok(())
+    }
+
+    let wanted: Vec<_> = xattr::list(source)?.collect();
+    for name in xattr::list(dest)? {
+        if !wanted.contains(&name) {
+            let _ = xattr::remove(dest, &name);
+        }
+    }
+    for name in wanted {
+        let Some(value) = xattr::get(source, &name)? else {
+            continue;
+        };
+        if let Err(e) = xattr::set(dest, &name, &value) {
+            // Security and trusted namespaces need privileges.
+            log::debug!("unable to set xattr {:?} on {}: {e}", name, dest.display());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{CreateKind, EventKind, ModifyKind, RenameMode};
+    use notify::Event;
+
+    use crate::batch::ChangeSet;
+
+    struct Trees {
+        _dir: tempfile::TempDir,
+        source: PathBuf,
+        replica: PathBuf,
+        outside: PathBuf,
+        sync: ReplicaSync,
+    }
+
+    fn trees() -> Trees {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        let replica = dir.path().join("replica");
+        let outside = dir.path().join("outside");
+        for path in [&source, &replica, &outside] {
+            std::fs::create_dir(path).unwrap();
+        }
+        let sync = ReplicaSync::new(&SyncConfig {
+            replica_root: Some(replica.clone()),
+            source_root: Some(source.clone()),
+        });
+        Trees { _dir: dir, source, replica, outside, sync }
+    }
+
+    fn copy(path: &Path) -> LibrettoEvent {
+        let event = Event::new(EventKind::Create(CreateKind::File)).add_path(path.to_path_buf());
+        LibrettoEvent::new(event, VmmAction::Copy, None)
+    }
+
+    fn moved(from: &Path, to: &Path) -> LibrettoEvent {
+        let event = Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+            .add_path(from.to_path_buf())
+            .add_path(to.to_path_buf());
+        let action = VmmAction::Move { from: from.to_path_buf(), to: to.to_path_buf() };
+        LibrettoEvent::new(event, action, None)
+    }
+
+    fn write(path: &Path, contents: &[u8]) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn repeated_copies_preserve_contents_and_metadata() {
+        let trees = trees();
+        let source = trees.source.join("c1/etc/hosts");
+        write(&source, b"127.0.0.1 localhost\n");
+        std::fs::set_permissions(&source, std::fs::Permissions::from_mode(0o640)).unwrap();
+        let xattrs = xattr::set(&source, "user.libretto", b"value").is_ok();
+        filetime::set_file_mtime(&source, FileTime::from_unix_time(1_600_000_000, 0)).unwrap();
+
+        let event = copy(&source);
+        trees.sync.apply(&event).unwrap();
+        trees.sync.apply(&event).unwrap();
+
+        let replica = trees.replica.join("c1/etc/hosts");
+        assert_eq!(std::fs::read(&replica).unwrap(), b"127.0.0.1 localhost\n");
+        let metadata = std::fs::metadata(&replica).unwrap();
+        assert_eq!(metadata.mode() & 0o7777, 0o640);
+        assert_eq!(FileTime::from_last_modification_time(&metadata), FileTime::from_unix_time(1_600_000_000, 0));
+        if xattrs {
+            assert_eq!(xattr::get(&replica, "user.libretto").unwrap(), Some(b"value".to_vec()));
+        }
+        assert!(!temp_path(&replica).exists());
+    }
+
+    #[test]
+    fn symlinks_are_copied_as_links() {
+        let trees = trees();
+        let source = trees.source.join("c1/etc/localtime");
+        std::fs::create_dir_all(source.parent().unwrap()).unwrap();
+        std::os::unix::fs::symlink("/usr/share/zoneinfo/UTC", &source).unwrap();
+
+        trees.sync.apply(&copy(&source)).unwrap();
+        trees.sync.apply(&copy(&source)).unwrap();
+        let replica = trees.replica.join("c1/etc/localtime");
+        assert_eq!(std::fs::read_link(replica).unwrap(), PathBuf::from("/usr/share/zoneinfo/UTC"));
+    }
+
+    #[test]
+    fn moves_already_applied_are_harmless() {
+        let trees = trees();
+        let from = trees.source.join("c1/a");
+        let to = trees.source.join("c1/dir/b");
+        write(&from, b"contents");
+        trees.sync.apply(&copy(&from)).unwrap();
+
+        write(&to, b"contents");
+        std::fs::remove_file(&from).unwrap();
+        let event = moved(&from, &to);
+        trees.sync.apply(&event).unwrap();
+        trees.sync.apply(&event).unwrap();
+
+        assert!(!trees.replica.join("c1/a").exists());
+        assert_eq!(std::fs::read(trees.replica.join("c1/dir/b")).unwrap(), b"contents");
+    }
+
+    #[test]
+    fn replayed_removals_are_harmless() {
+        let trees = trees();
+        let source = trees.source.join("c1/etc/motd");
+        write(&source, b"hello");
+        trees.sync.apply(&copy(&source)).unwrap();
+        std::fs::remove_dir_all(trees.source.join("c1/etc")).unwrap();
+
+        let mut changes = ChangeSet::default();
+        changes.removed.insert(source.clone());
+        let event = copy(&source).with_changes(changes);
+        trees.sync.apply(&event).unwrap();
+        trees.sync.apply(&event).unwrap();
+        assert!(!trees.replica.join("c1/etc/motd").exists());
+    }
+
+    #[test]
+    fn symlinked_source_parents_are_refused() {
+        let trees = trees();
+        write(&trees.outside.join("shadow"), b"secret");
+        std::fs::create_dir(trees.source.join("c1")).unwrap();
+        std::os::unix::fs::symlink(&trees.outside, trees.source.join("c1/d")).unwrap();
+
+        let e = trees.sync.apply(&copy(&trees.source.join("c1/d/shadow"))).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        assert!(!trees.replica.join("c1/d/shadow").exists());
+    }
+
+    #[test]
+    fn symlinked_replica_parents_are_refused() {
+        let trees = trees();
+        let source = trees.source.join("c1/d/passwd");
+        write(&source, b"root:x:0:0::/root:/bin/sh\n");
+        std::fs::create_dir(trees.replica.join("c1")).unwrap();
+        std::os::unix::fs::symlink(&trees.outside, trees.replica.join("c1/d")).unwrap();
+
+        let e = trees.sync.apply(&copy(&source)).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        let e = trees.sync.sync_metadata(&source).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        let e = trees.sync.apply(&moved(&source, &trees.source.join("c1/d/group"))).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(std::fs::read_dir(&trees.outside).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,184 @@
+use notify::{Config, Event, PollWatcher, RecommendedWatcher, Watcher};
+use serde::{Serialize, Deserialize};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::roots::WatchRoot;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PollConfig {
+    pub interval_ms: u64,
+    /// Hash file contents on every poll instead of only comparing mtimes.
+    /// Catches writes that keep the mtime, at the cost of reading every file.
+    #[serde(default)]
+    pub compare_contents: bool,
+}
+
+impl PollConfig {
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms)
+    }
+}
+
+impl Default for PollConfig {
+    fn default() -> Self {
+        Self { interval_ms: 5_000, compare_contents: false }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendConfig {
+    /// inotify on Linux. Falls back to polling with the root's
+    /// `poll_fallback` settings if the watch cannot be registered.
+    #[default]
+    Native,
+    Poll(PollConfig),
+}
+
+pub type EventCallback = Arc<dyn Fn(notify::Result<Event>) + Send + Sync>;
+
+/// Owns every notify watcher in use, creating at most one native watcher and
+/// one poll watcher per distinct poll configuration. All of them feed the
+/// same callback.
+pub struct Backends {
+    callback: EventCallback,
+    native: Option<RecommendedWatcher>,
+    polling: Vec<(PollConfig, PollWatcher)>,
+}
+
+impl Backends {
+    pub fn new(callback: EventCallback) -> Self {
+        Self { callback, native: None, polling: Vec::new() }
+    }
+
+    /// Registers the root with its configured backend and returns the backend
+    /// actually in use, which differs from the configured one after a
+    /// fallback.
+    pub fn watch(&mut self, root: &WatchRoot) -> std::io::Result<BackendConfig> {
+        match root.backend() {
+            BackendConfig::Native => match self.watch_native(root) {
+                Ok(()) => Ok(BackendConfig::Native),
+                Err(e) => {
+                    log::warn!(
+                        "unable to watch {} natively, falling back to polling: {e}",
+                        root.path().display()
+                    );
+                    self.fallback_to_poll(root)
+                }
+            },
+            BackendConfig::Poll(config) => {
+                self.watch_poll(root, config)?;
+                Ok(BackendConfig::Poll(config))
+            }
+        }
+    }
+
+    /// Drops any native watch on the root, which may have been registered
+    /// only partially, and polls it with the root's poll settings instead.
+    pub fn fallback_to_poll(&mut self, root: &WatchRoot) -> std::io::Result<BackendConfig> {
+        if let Some(watcher) = self.native.as_mut() {
+            let _ = watcher.unwatch(root.path());
+        }
+        let config = root.poll_config();
+        self.watch_poll(root, config)?;
+        Ok(BackendConfig::Poll(config))
+    }
+
+    fn watch_native(&mut self, root: &WatchRoot) -> notify::Result<()> {
+        if self.native.is_none() {
+            let callback = self.callback.clone();
+            self.native = Some(notify::recommended_watcher(move |res| callback(res))?);
+        }
+
+        match self.native.as_mut() {
+            Some(watcher) => watcher.watch(root.path(), root.recursive()),
+            None => Ok(()),
+        }
+    }
+
+    fn watch_poll(&mut self, root: &WatchRoot, config: PollConfig) -> std::io::Result<()> {
+        let idx = match self.polling.iter().position(|(c, _)| *c == config) {
+            Some(idx) => idx,
+            None => {
+                let callback = self.callback.clone();
+                let watcher = PollWatcher::new(
+                    move |res| callback(res),
+                    Config::default()
+                        .with_poll_interval(config.interval())
+                        .with_compare_contents(config.compare_contents)
+                ).map_err(|e| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        format!("unable to create poll watcher: {e}")
+                    )
+                })?;
+                self.polling.push((config, watcher));
+                self.polling.len() - 1
+            }
+        };
+
+        self.polling[idx].1.watch(root.path(), root.recursive()).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("unable to watch {}: {e}", root.path().display())
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::RecursiveMode;
+
+    use crate::watcher::resolver::InstanceLayout;
+    use crate::watcher::rules::PathFilter;
+
+    fn root(path: &std::path::Path) -> WatchRoot {
+        WatchRoot::new(path, RecursiveMode::Recursive, InstanceLayout::default(), PathFilter::default(), None)
+    }
+
+    fn backends() -> Backends {
+        Backends::new(Arc::new(|_| {}))
+    }
+
+    #[test]
+    fn native_roots_are_watched_natively() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut backends = backends();
+        assert_eq!(backends.watch(&root(dir.path())).unwrap(), BackendConfig::Native);
+        assert!(backends.polling.is_empty());
+    }
+
+    #[test]
+    fn failed_native_watches_fall_back_to_the_roots_poll_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing");
+        let mut backends = backends();
+        assert_eq!(backends.watch(&root(&missing)).unwrap(), BackendConfig::Poll(PollConfig::default()));
+
+        let fallback = PollConfig { interval_ms: 250, compare_contents: true };
+        let configured = root(&missing).with_poll_fallback(Some(fallback));
+        assert_eq!(backends.watch(&configured).unwrap(), BackendConfig::Poll(fallback));
+        assert_eq!(backends.polling.len(), 2);
+    }
+
+    #[test]
+    fn poll_watchers_are_shared_per_configuration() {
+        let first = tempfile::tempdir().unwrap();
+        let second = tempfile::tempdir().unwrap();
+        let config = PollConfig { interval_ms: 100, compare_contents: false };
+        let mut backends = backends();
+        for dir in [&first, &second] {
+            let root = root(dir.path()).with_backend(BackendConfig::Poll(config));
+            assert_eq!(backends.watch(&root).unwrap(), BackendConfig::Poll(config));
+        }
+        assert_eq!(backends.polling.len(), 1);
+        assert!(backends.native.is_none());
+
+        // Falling back keeps a root's own poll settings.
+        let root = root(first.path()).with_backend(BackendConfig::Poll(config));
+        assert_eq!(backends.fallback_to_poll(&root).unwrap(), BackendConfig::Poll(config));
+    }
+}
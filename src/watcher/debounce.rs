@@ -0,0 +1,276 @@
+use notify::event::{EventKind, ModifyKind};
+use serde::{Serialize, Deserialize};
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::pubsub::FilesystemEvent;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct DebounceConfig {
+    /// How long a path has to stay quiet before its merged event is emitted.
+    pub quiet_window_ms: u64,
+    /// Upper bound on how long a continuously busy path is held back.
+    pub max_latency_ms: u64,
+}
+
+impl DebounceConfig {
+    pub fn disabled() -> Self {
+        Self { quiet_window_ms: 0, max_latency_ms: 0 }
+    }
+
+    pub fn quiet_window(&self) -> Duration {
+        Duration::from_millis(self.quiet_window_ms)
+    }
+
+    pub fn max_latency(&self) -> Duration {
+        Duration::from_millis(self.max_latency_ms.max(self.quiet_window_ms))
+    }
+
+    pub fn is_disabled(&self) -> bool {
+        self.quiet_window_ms == 0
+    }
+
+    /// How often pending events should be checked for expiry.
+    pub fn tick(&self) -> Duration {
+        Duration::from_millis((self.quiet_window_ms / 2).clamp(10, 250))
+    }
+}
+
+impl Default for DebounceConfig {
+    fn default() -> Self {
+        Self { quiet_window_ms: 200, max_latency_ms: 2_000 }
+    }
+}
+
+struct Pending {
+    event: FilesystemEvent,
+    first_seen: Instant,
+    last_seen: Instant,
+}
+
+/// Merges bursts of events for the same path into a single event.
+pub struct Debouncer {
+    config: DebounceConfig,
+    pending: HashMap<PathBuf, Pending>,
+    ready: VecDeque<FilesystemEvent>,
+}
+
+impl Debouncer {
+    pub fn new(config: DebounceConfig) -> Self {
+        Self {
+            config,
+            pending: HashMap::new(),
+            ready: VecDeque::new(),
+        }
+    }
+
+    pub fn config(&self) -> &DebounceConfig {
+        &self.config
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len() + self.ready.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn push(&mut self, event: FilesystemEvent, now: Instant) {
+        let Some(path) = event.event().paths.first().cloned() else {
+            return
+        };
+
+        // Renames and rescan notices carry meaning that merging would lose,
+        // so they are passed straight through after anything pending on the
+        // paths they touch.
+        if self.config.is_disabled() || !is_coalescable(event.event()) {
+            for p in &event.event().paths {
+                if let Some(pending) = self.pending.remove(p) {
+                    self.ready.push_back(pending.event);
+                }
+            }
+            self.ready.push_back(event);
+            return
+        }
+
+        match self.pending.get_mut(&path) {
+            Some(pending) => {
+                pending.last_seen = now;
+                if supersedes(&event.event().kind, &pending.event.event().kind) {
+                    pending.event = event;
+                }
+            }
+            None => {
+                self.pending.insert(path, Pending { event, first_seen: now, last_seen: now });
+            }
+        }
+    }
+
+    /// Removes and returns every event whose path has been quiet for the
+    /// configured window, or that has been held for the maximum latency.
+    pub fn drain_ready(&mut self, now: Instant) -> Vec<FilesystemEvent> {
+        let quiet_window = self.config.quiet_window();
+        let max_latency = self.config.max_latency();
+        let mut expired: Vec<PathBuf> = self.pending.iter().filter(|(_, pending)| {
+            now.duration_since(pending.last_seen) >= quiet_window
+                || now.duration_since(pending.first_seen) >= max_latency
+        }).map(|(path, _)| path.clone()).collect();
+
+        expired.sort_by_key(|path| self.pending[path].first_seen);
+
+        let mut events: Vec<FilesystemEvent> = self.ready.drain(..).collect();
+        events.extend(expired.iter().filter_map(|path| {
+            self.pending.remove(path).map(|pending| pending.event)
+        }));
+
+        events
+    }
+
+    pub fn drain_all(&mut self) -> Vec<FilesystemEvent> {
+        let mut pending: Vec<Pending> = self.pending.drain().map(|(_, p)| p).collect();
+        pending.sort_by_key(|p| p.first_seen);
+
+        let mut events: Vec<FilesystemEvent> = self.ready.drain(..).collect();
+        events.extend(pending.into_iter().map(|p| p.event));
+        events
+    }
+}
+
+fn is_coalescable(event: &notify::Event) -> bool {
+    !event.need_rescan() && !matches!(event.kind, EventKind::Modify(ModifyKind::Name(_)))
+}
+
+fn strength(kind: &EventKind) -> u8 {
+    match kind {
+        EventKind::Remove(_) => 5,
+        EventKind::Create(_) => 4,
+        EventKind::Modify(ModifyKind::Metadata(_)) => 2,
+        EventKind::Modify(_) => 3,
+        EventKind::Other => 1,
+        EventKind::Access(_) => 1,
+        EventKind::Any => 0,
+    }
+}
+
+/// Whether `incoming` should replace the `current` merged event. The stronger
+/// kind wins, except that a create after a remove means the path exists again.
+fn supersedes(incoming: &EventKind, current: &EventKind) -> bool {
+    if incoming.is_create() && current.is_remove() {
+        return true
+    }
+
+    strength(incoming) >= strength(current)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{CreateKind, DataChange, Flag, MetadataKind, RemoveKind, RenameMode};
+    use notify::Event;
+
+    fn event(kind: EventKind, path: &str) -> FilesystemEvent {
+        FilesystemEvent::new(Event::new(kind).add_path(PathBuf::from(path)), None)
+    }
+
+    fn write() -> EventKind {
+        EventKind::Modify(ModifyKind::Data(DataChange::Content))
+    }
+
+    fn summary(events: &[FilesystemEvent]) -> Vec<(EventKind, PathBuf)> {
+        events.iter().map(|e| (e.event().kind, e.event().paths[0].clone())).collect()
+    }
+
+    fn debouncer() -> Debouncer {
+        Debouncer::new(DebounceConfig { quiet_window_ms: 100, max_latency_ms: 1_000 })
+    }
+
+    #[test]
+    fn bursts_for_a_path_merge_into_the_strongest_event() {
+        let mut debouncer = debouncer();
+        let start = Instant::now();
+        debouncer.push(event(EventKind::Create(CreateKind::File), "/a"), start);
+        debouncer.push(event(write(), "/a"), start + Duration::from_millis(10));
+        debouncer.push(event(EventKind::Modify(ModifyKind::Metadata(MetadataKind::Any)), "/a"), start + Duration::from_millis(20));
+        debouncer.push(event(write(), "/b"), start + Duration::from_millis(30));
+        assert_eq!(debouncer.len(), 2);
+
+        // Neither path has been quiet for the window yet.
+        assert!(debouncer.drain_ready(start + Duration::from_millis(110)).is_empty());
+
+        let ready = debouncer.drain_ready(start + Duration::from_millis(120));
+        assert_eq!(summary(&ready), vec![(EventKind::Create(CreateKind::File), PathBuf::from("/a"))]);
+
+        let ready = debouncer.drain_ready(start + Duration::from_millis(130));
+        assert_eq!(summary(&ready), vec![(write(), PathBuf::from("/b"))]);
+        assert!(debouncer.is_empty());
+    }
+
+    #[test]
+    fn a_create_after_a_remove_wins() {
+        let mut debouncer = debouncer();
+        let start = Instant::now();
+        debouncer.push(event(EventKind::Remove(RemoveKind::File), "/a"), start);
+        debouncer.push(event(EventKind::Create(CreateKind::File), "/a"), start);
+        assert_eq!(summary(&debouncer.drain_all()), vec![(EventKind::Create(CreateKind::File), PathBuf::from("/a"))]);
+
+        debouncer.push(event(write(), "/a"), start);
+        debouncer.push(event(EventKind::Remove(RemoveKind::File), "/a"), start);
+        debouncer.push(event(write(), "/a"), start);
+        assert_eq!(summary(&debouncer.drain_all()), vec![(EventKind::Remove(RemoveKind::File), PathBuf::from("/a"))]);
+    }
+
+    #[test]
+    fn busy_paths_are_released_after_the_maximum_latency() {
+        let mut debouncer = debouncer();
+        let start = Instant::now();
+        for ms in (0..1_000).step_by(50) {
+            debouncer.push(event(write(), "/a"), start + Duration::from_millis(ms));
+        }
+        assert!(debouncer.drain_ready(start + Duration::from_millis(999)).is_empty());
+        assert_eq!(debouncer.drain_ready(start + Duration::from_millis(1_000)).len(), 1);
+    }
+
+    #[test]
+    fn renames_and_rescans_flush_pending_events_and_pass_through() {
+        let mut debouncer = debouncer();
+        let start = Instant::now();
+        debouncer.push(event(write(), "/a"), start);
+        debouncer.push(event(write(), "/c"), start);
+        let rename = Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+            .add_path(PathBuf::from("/a"))
+            .add_path(PathBuf::from("/b"));
+        debouncer.push(FilesystemEvent::new(rename, None), start);
+        let rescan = Event::new(EventKind::Other).add_path(PathBuf::from("/d")).set_flag(Flag::Rescan);
+        debouncer.push(FilesystemEvent::new(rescan, None), start);
+
+        let ready = debouncer.drain_ready(start);
+        assert_eq!(summary(&ready), vec![
+            (write(), PathBuf::from("/a")),
+            (EventKind::Modify(ModifyKind::Name(RenameMode::Both)), PathBuf::from("/a")),
+            (EventKind::Other, PathBuf::from("/d")),
+        ]);
+        assert_eq!(debouncer.len(), 1);
+    }
+
+    #[test]
+    fn disabled_debouncing_passes_everything_through() {
+        let mut debouncer = Debouncer::new(DebounceConfig::disabled());
+        let start = Instant::now();
+        debouncer.push(event(write(), "/a"), start);
+        debouncer.push(event(write(), "/a"), start);
+        assert_eq!(debouncer.drain_ready(start).len(), 2);
+    }
+
+    #[test]
+    fn drain_all_keeps_arrival_order() {
+        let mut debouncer = debouncer();
+        let start = Instant::now();
+        for (ms, path) in [(0, "/c"), (1, "/a"), (2, "/b")] {
+            debouncer.push(event(write(), path), start + Duration::from_millis(ms));
+        }
+        let paths: Vec<PathBuf> = summary(&debouncer.drain_all()).into_iter().map(|(_, p)| p).collect();
+        assert_eq!(paths, vec![PathBuf::from("/c"), PathBuf::from("/a"), PathBuf::from("/b")]);
+    }
+}
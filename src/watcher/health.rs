@@ -0,0 +1,264 @@
+use notify::event::{EventKind, Flag};
+use notify::Event;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+use super::backend::{BackendConfig, Backends};
+use super::debounce::Debouncer;
+use super::roots::{WatchRoot, WatchRoots};
+use super::scan::{walk_events, Baselines};
+
+/// Prefix of `Event::info` on the notice published when the watcher's view
+/// of a subtree can no longer be trusted.
+pub const DEGRADED_INFO: &str = "libretto:degraded";
+
+#[derive(Clone, Debug)]
+pub enum HealthSignal {
+    /// The kernel event queue overflowed and events were dropped. notify does
+    /// not say where, so `None` means every root.
+    Overflow { path: Option<PathBuf> },
+    /// inotify ran out of watches, so changes under `paths` (or everywhere
+    /// for an empty list) are no longer reported.
+    WatchLimit { paths: Vec<PathBuf> },
+}
+
+impl HealthSignal {
+    fn reason(&self) -> &'static str {
+        match self {
+            HealthSignal::Overflow { .. } => "event queue overflow",
+            HealthSignal::WatchLimit { .. } => "watch limit exhausted",
+        }
+    }
+}
+
+pub fn degraded_event(path: &Path, reason: &str) -> Event {
+    Event::new(EventKind::Other)
+        .add_path(path.to_path_buf())
+        .set_flag(Flag::Rescan)
+        .set_info(&format!("{DEGRADED_INFO}: {reason}"))
+}
+
+/// Classifies a notify callback result, returning a signal for the
+/// conditions that mean events were lost.
+pub fn classify(res: &notify::Result<Event>) -> Option<HealthSignal> {
+    match res {
+        Ok(event) if event.need_rescan() => {
+            Some(HealthSignal::Overflow { path: event.paths.first().cloned() })
+        }
+        Err(e) if matches!(e.kind, notify::ErrorKind::MaxFilesWatch) => {
+            Some(HealthSignal::WatchLimit { paths: e.paths.clone() })
+        }
+        _ => None,
+    }
+}
+
+/// Reports whether this process has used up the inotify watches allowed for
+/// its user. notify drops watch errors for directories created after the
+/// initial registration, so this is the only way to notice those.
+fn inotify_watches_exhausted() -> bool {
+    let Ok(max) = std::fs::read_to_string("/proc/sys/fs/inotify/max_user_watches") else {
+        return false
+    };
+    let Ok(max) = max.trim().parse::<usize>() else {
+        return false
+    };
+    let Ok(fds) = std::fs::read_dir("/proc/self/fdinfo") else {
+        return false
+    };
+
+    let used: usize = fds.flatten().filter_map(|fd| {
+        std::fs::read_to_string(fd.path()).ok()
+    }).map(|info| {
+        info.lines().filter(|line| line.starts_with("inotify wd:")).count()
+    }).sum();
+
+    used >= max
+}
+
+pub struct HealthMonitor {
+    roots: WatchRoots,
+    debouncer: Arc<Mutex<Debouncer>>,
+    backends: Arc<Mutex<Backends>>,
+    baselines: Option<Baselines>,
+}
+
+impl HealthMonitor {
+    pub fn new(
+        roots: WatchRoots,
+        debouncer: Arc<Mutex<Debouncer>>,
+        backends: Arc<Mutex<Backends>>,
+        baselines: Option<Baselines>,
+    ) -> Self {
+        Self { roots, debouncer, backends, baselines }
+    }
+
+    pub fn channel() -> (UnboundedSender<HealthSignal>, UnboundedReceiver<HealthSignal>) {
+        tokio::sync::mpsc::unbounded_channel()
+    }
+
+    pub async fn run(self, mut signals: UnboundedReceiver<HealthSignal>) {
+        let mut check_interval = tokio::time::interval(tokio::time::Duration::from_secs(30));
+        let mut limit_reported = false;
+        loop {
+            let signal = tokio::select! {
+                signal = signals.recv() => match signal {
+                    Some(signal) => signal,
+                    None => break,
+                },
+                _ = check_interval.tick() => {
+                    let exhausted = tokio::task::spawn_blocking(inotify_watches_exhausted)
+                        .await
+                        .unwrap_or(false);
+                    if !exhausted || limit_reported {
+                        limit_reported = exhausted;
+                        continue;
+                    }
+                    limit_reported = true;
+                    HealthSignal::WatchLimit { paths: Vec::new() }
+                }
+            };
+
+            self.handle(signal).await;
+        }
+    }
+
+    fn affected(&self, paths: &[PathBuf]) -> Vec<(WatchRoot, PathBuf)> {
+        if paths.is_empty() {
+            return self.roots.iter().map(|root| (root.clone(), root.path().to_path_buf())).collect()
+        }
+
+        paths.iter().filter_map(|path| {
+            self.roots.route(path).map(|root| (root.clone(), path.clone()))
+        }).collect()
+    }
+
+    async fn handle(&self, signal: HealthSignal) {
+        let reason = signal.reason();
+        let affected = match &signal {
+            HealthSignal::Overflow { path } => self.affected(path.as_slice()),
+            HealthSignal::WatchLimit { paths } => self.affected(paths),
+        };
+        log::warn!("watcher degraded ({reason}), rescanning {} subtrees", affected.len());
+
+        for (root, subtree) in affected {
+            let mut recovered = true;
+            if let HealthSignal::WatchLimit { .. } = signal {
+                if root.backend() == BackendConfig::Native {
+                    if let Ok(mut backends) = self.backends.lock() {
+                        if let Err(e) = backends.fallback_to_poll(&root) {
+                            log::error!("unable to switch {} to polling: {e}", root.path().display());
+                            recovered = false;
+                        }
+                    }
+                }
+            }
+
+            // Without a manifest the subtree is walked and everything in it
+            // reported again, which covers all but removals.
+            let rescan_path = subtree.clone();
+            let events = match self.baselines.clone() {
+                Some(baselines) => tokio::task::spawn_blocking(move || baselines.rescan(&rescan_path)).await,
+                None => {
+                    log::warn!(
+                        "no manifest kept for {}, removals missed there cannot be recovered",
+                        root.path().display()
+                    );
+                    recovered = false;
+                    let recursive = root.recursive();
+                    tokio::task::spawn_blocking(move || walk_events(&rescan_path, recursive)).await
+                }
+            };
+            match events {
+                Ok(Ok(events)) => {
+                    log::info!("rescan of {} found {} changes", subtree.display(), events.len());
+                    for event in events {
+                        self.push(&root, event);
+                    }
+                }
+                Ok(Err(e)) => {
+                    log::error!("unable to rescan {}: {e}", subtree.display());
+                    recovered = false;
+                }
+                Err(e) => {
+                    log::error!("rescan of {} panicked: {e}", subtree.display());
+                    recovered = false;
+                }
+            }
+
+            if !recovered {
+                self.push(&root, degraded_event(&subtree, reason));
+            }
+        }
+    }
+
+    fn push(&self, root: &WatchRoot, event: Event) {
+        let Some(event) = root.accept(event) else {
+            return
+        };
+        if let Ok(mut guard) = self.debouncer.lock() {
+            guard.push(event, Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::debounce::DebounceConfig;
+    use super::super::roots::RootConfig;
+    use super::super::scan::BaselineConfig;
+
+    fn monitor(root: &Path, baselines: Option<Baselines>) -> (HealthMonitor, Arc<Mutex<Debouncer>>) {
+        let roots = WatchRoots::new(vec![WatchRoot::from_config(&RootConfig::new(root)).unwrap()]);
+        let debouncer = Arc::new(Mutex::new(Debouncer::new(DebounceConfig::disabled())));
+        let backends = Arc::new(Mutex::new(Backends::new(Arc::new(|_| {}))));
+        (HealthMonitor::new(roots, debouncer.clone(), backends, baselines), debouncer)
+    }
+
+    fn drained(debouncer: &Mutex<Debouncer>) -> Vec<Event> {
+        debouncer.lock().unwrap().drain_all().iter().map(|e| e.event().clone()).collect()
+    }
+
+    fn is_degraded(event: &Event) -> bool {
+        event.info().is_some_and(|info| info.starts_with(DEGRADED_INFO))
+    }
+
+    #[tokio::test]
+    async fn overflows_are_recovered_from_the_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        let roots = WatchRoots::new(vec![WatchRoot::from_config(&RootConfig::new(&root)).unwrap()]);
+        let config = BaselineConfig { enabled: true, hash: false, manifest_dir: Some(dir.path().join("manifests")) };
+        let (baselines, _) = Baselines::scan(&roots, config).unwrap();
+        std::fs::write(root.join("missed"), b"data").unwrap();
+
+        let (monitor, debouncer) = monitor(&root, Some(baselines));
+        monitor.handle(HealthSignal::Overflow { path: None }).await;
+
+        let events = drained(&debouncer);
+        assert!(!events.iter().any(is_degraded));
+        assert_eq!(events.iter().flat_map(|e| e.paths.clone()).collect::<Vec<_>>(), vec![root.join("missed")]);
+    }
+
+    #[tokio::test]
+    async fn overflows_without_a_manifest_walk_the_subtree() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("sub")).unwrap();
+        std::fs::write(dir.path().join("sub/file"), b"data").unwrap();
+
+        let (monitor, debouncer) = monitor(dir.path(), None);
+        monitor.handle(HealthSignal::Overflow { path: None }).await;
+
+        let events = drained(&debouncer);
+        let created: Vec<_> = events.iter()
+            .filter(|e| matches!(e.kind, EventKind::Create(_)))
+            .flat_map(|e| e.paths.clone())
+            .collect();
+        assert_eq!(created, vec![dir.path().join("sub"), dir.path().join("sub/file")]);
+        // Removals cannot be found without a manifest.
+        assert_eq!(events.iter().filter(|e| is_degraded(e)).count(), 1);
+    }
+}
@@ -0,0 +1,468 @@
+use notify::{Event, RecursiveMode};
+use serde::{Serialize, Deserialize};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::journal::{Journal, JournalConfig, JournalEntry};
+use crate::pubsub::{FilesystemEvent, FilesystemPublisher, FilesystemTopic};
+
+pub mod backend;
+pub mod debounce;
+pub mod health;
+pub mod queue;
+pub mod rename;
+pub mod resolver;
+pub mod roots;
+pub mod rules;
+pub mod scan;
+
+pub use backend::{BackendConfig, Backends, EventCallback, PollConfig};
+pub use debounce::{DebounceConfig, Debouncer};
+pub use health::{HealthMonitor, HealthSignal};
+pub use queue::{EventQueue, OverflowPolicy, QueueConfig, QueueStats};
+pub use rename::{RenameConfig, RenameCorrelator};
+pub use resolver::{InstanceKind, InstanceLayout, InstancePath, InstanceResolver};
+pub use roots::{RootConfig, WatchConfig, WatchRoot, WatchRoots};
+pub use rules::{FilterConfig, PathFilter, RuleAction};
+pub use scan::{BaselineConfig, Baselines, Manifest};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RoutedEvent {
+    /// Journal sequence number, absent when journaling is disabled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    pub topic: String,
+    pub event: FilesystemEvent,
+}
+
+impl From<JournalEntry<RoutedEvent>> for RoutedEvent {
+    fn from(entry: JournalEntry<RoutedEvent>) -> Self {
+        Self { seq: Some(entry.seq), ..entry.item }
+    }
+}
+
+type SharedJournal = Arc<Mutex<Journal<RoutedEvent>>>;
+
+/// Most events handed to the publisher between two journal flushes.
+const DELIVERY_BATCH: usize = 512;
+
+pub async fn monitor_directory(
+    watch_path: &str,
+    layout: InstanceLayout,
+    filter: PathFilter,
+    debounce: DebounceConfig,
+    queue: EventQueue<RoutedEvent>,
+    publisher: FilesystemPublisher,
+) -> std::io::Result<()> {
+    let root = WatchRoot::new(watch_path, RecursiveMode::Recursive, layout, filter, None);
+    monitor_roots(
+        WatchRoots::new(vec![root]),
+        debounce,
+        BaselineConfig::default(),
+        JournalConfig::default(),
+        RenameConfig::default(),
+        queue,
+        publisher
+    ).await
+}
+
+pub async fn monitor_roots(
+    roots: WatchRoots,
+    debounce: DebounceConfig,
+    baseline: BaselineConfig,
+    journal: JournalConfig,
+    rename: RenameConfig,
+    queue: EventQueue<RoutedEvent>,
+    mut publisher: FilesystemPublisher,
+) -> std::io::Result<()> {
+    let journal: Option<SharedJournal> = if journal.enabled {
+        let journal = Journal::open(journal)?;
+        log::info!(
+            "journal opened, committed through {}, last written {}",
+            journal.committed(),
+            journal.last_seq()
+        );
+        Some(Arc::new(Mutex::new(journal)))
+    } else {
+        None
+    };
+
+    let reload_roots = roots.clone();
+    tokio::spawn(async move {
+        let mut hangup = tokio::signal::unix::signal(
+            tokio::signal::unix::SignalKind::hangup()
+        )?;
+        while hangup.recv().await.is_some() {
+            for root in reload_roots.iter() {
+                if let Err(e) = root.filter().reload() {
+                    log::error!("unable to reload path filter for {}: {e}", root.path().display());
+                }
+            }
+        }
+
+        Ok::<(), std::io::Error>(())
+    });
+
+    let (sender, mut receiver) = queue.split();
+    let debouncer = Arc::new(Mutex::new(Debouncer::new(debounce)));
+    let correlator = Arc::new(Mutex::new(RenameCorrelator::new(rename)));
+    let watcher_debouncer = debouncer.clone();
+    let watcher_correlator = correlator.clone();
+    let watcher_roots = roots.clone();
+    let (health_sender, health_receiver) = HealthMonitor::channel();
+    let callback: EventCallback = Arc::new(move |res: Result<Event, notify::Error>| {
+        let inner_debouncer = watcher_debouncer.clone();
+        if let Some(signal) = health::classify(&res) {
+            log::warn!("watcher lost events: {:?}", signal);
+            let _ = health_sender.send(signal);
+            return
+        }
+        match res {
+            Ok(event) => {
+                log::info!("watcher discovered event: {:?}", event);
+                let now = Instant::now();
+                let events = match watcher_correlator.lock() {
+                    Ok(mut guard) => guard.push(event, now),
+                    Err(e) => {
+                        log::error!("unable to acquire lock on rename correlator: {e}");
+                        return
+                    }
+                };
+
+                let events: Vec<FilesystemEvent> = events.into_iter()
+                    .flat_map(|event| watcher_roots.accept(event))
+                    .collect();
+                if events.is_empty() {
+                    return
+                }
+
+                log::info!("Change detected in non-system path...");
+                if let Ok(mut guard) = inner_debouncer.lock() {
+                    for event in events {
+                        guard.push(event, now);
+                    }
+                    drop(guard);
+                }
+            }
+            Err(e) => log::error!("watch error: {:?}", e)
+        }
+    });
+
+    let mut backends = Backends::new(callback);
+    for root in roots.iter() {
+        let backend = backends.watch(root)?;
+        log::info!("watching {} with {:?} backend", root.path().display(), backend);
+    }
+    let backends = Arc::new(Mutex::new(backends));
+
+    // Watches are registered before scanning so nothing falls between the
+    // scan and the first live event; the debouncer absorbs the overlap.
+    let baselines = if baseline.enabled {
+        let scan_roots = roots.clone();
+        let (baselines, changes) = tokio::task::spawn_blocking(move || {
+            Baselines::scan(&scan_roots, baseline)
+        }).await.map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("baseline scan panicked: {e}")
+            )
+        })??;
+
+        if let Ok(mut guard) = debouncer.lock() {
+            let now = Instant::now();
+            for (root, events) in changes {
+                for event in events.into_iter().filter_map(|e| root.accept(e)) {
+                    guard.push(event, now);
+                }
+            }
+        }
+
+        Some(baselines)
+    } else {
+        None
+    };
+
+    let health = HealthMonitor::new(roots.clone(), debouncer.clone(), backends.clone(), baselines.clone());
+    tokio::spawn(health.run(health_receiver));
+
+    let tick_journal = journal.clone();
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(debounce.tick());
+        loop {
+            tick.tick().await;
+            let now = Instant::now();
+            // Rename halves that never found their partner.
+            let unpaired = match correlator.lock() {
+                Ok(mut guard) => guard.expire(now),
+                Err(e) => {
+                    log::error!("unable to acquire lock on rename correlator: {e}");
+                    Vec::new()
+                }
+            };
+            let ready = match debouncer.lock() {
+                Ok(mut guard) => {
+                    for event in unpaired.into_iter().flat_map(|event| roots.accept(event)) {
+                        guard.push(event, now);
+                    }
+                    guard.drain_ready(now)
+                }
+                Err(e) => {
+                    log::error!("unable to acquire lock on debouncer: {e}");
+                    continue;
+                }
+            };
+            for event in ready {
+                let topic = event.event().paths.first()
+                    .and_then(|p| roots.route(p))
+                    .map(|root| root.topic().to_string())
+                    .unwrap_or_else(|| FilesystemTopic.to_string());
+                let mut routed = RoutedEvent { seq: None, topic, event };
+                if let Some(journal) = &tick_journal {
+                    match journal.lock().map(|mut j| j.append(&routed)) {
+                        Ok(Ok(seq)) => routed.seq = Some(seq),
+                        Ok(Err(e)) => log::error!("unable to journal event, delivery not durable: {e}"),
+                        Err(e) => log::error!("unable to acquire lock on journal: {e}"),
+                    }
+                }
+                if let Err(e) = sender.send_async(routed).await {
+                    log::error!("unable to enqueue debounced event: {e}");
+                }
+            }
+        }
+    });
+
+    // Every stage that can wait on the broker watches this, so a stop request
+    // is seen even while delivery is retrying.
+    let (stop, mut stopped) = tokio::sync::watch::channel(false);
+    tokio::spawn(async move {
+        match tokio::signal::ctrl_c().await {
+            Ok(()) => {
+                let _ = stop.send(true);
+            }
+            Err(e) => log::error!("unable to listen for shutdown signal: {e}"),
+        }
+    });
+
+    let delivery = tokio::spawn(
+        async move {
+            // Whatever the previous run accepted but never got acknowledged
+            // goes out before anything new.
+            if let Some(journal) = &journal {
+                let replay = match journal.lock().map(|j| j.pending(None)) {
+                    Ok(Ok(entries)) => entries.into_iter().map(RoutedEvent::from).collect(),
+                    Ok(Err(e)) => {
+                        log::error!("unable to read journal for replay: {e}");
+                        VecDeque::new()
+                    }
+                    Err(e) => {
+                        log::error!("unable to acquire lock on journal: {e}");
+                        VecDeque::new()
+                    }
+                };
+                if !replay.is_empty() {
+                    log::info!("replaying {} journaled events", replay.len());
+                    let delivered = deliver(&mut publisher, Some(journal), baselines.as_ref(), replay, &mut stopped).await;
+                    flush_journal(Some(journal));
+                    if !delivered {
+                        save_manifests(baselines.as_ref());
+                        return Ok(())
+                    }
+                }
+            }
+
+            let mut heartbeat_interval = tokio::time::interval(tokio::time::Duration::from_secs(20));
+            loop {
+                tokio::select! {
+                    Ok(Some(routed)) = receiver.recv() => {
+                        // Whatever else is already queued goes out with it, so
+                        // the journal offset is written once per batch.
+                        let mut batch = VecDeque::from([routed]);
+                        while batch.len() < DELIVERY_BATCH {
+                            match receiver.try_recv() {
+                                Ok(Some(routed)) => batch.push_back(routed),
+                                Ok(None) => break,
+                                Err(e) => {
+                                    log::error!("unable to read event queue: {e}");
+                                    break;
+                                }
+                            }
+                        }
+                        let delivered = deliver(&mut publisher, journal.as_ref(), baselines.as_ref(), batch, &mut stopped).await;
+                        flush_journal(journal.as_ref());
+                        if !delivered {
+                            save_manifests(baselines.as_ref());
+                            break;
+                        }
+                    },
+                    _heartbeat = heartbeat_interval.tick() => {
+                        log::info!(
+                            "Filesystem monitor still alive, queue depth: {}, dropped: {}",
+                            receiver.depth(),
+                            receiver.dropped()
+                        );
+                        flush_journal(journal.as_ref());
+                        save_manifests(baselines.as_ref());
+                    }
+                    _ = stop_requested(&mut stopped) => {
+                        flush_journal(journal.as_ref());
+                        save_manifests(baselines.as_ref());
+                        break;
+                    }
+                }
+            }
+
+            Ok::<(), std::io::Error>(())
+        }
+    );
+
+    // Delivery stops on the same signal, once the journal offset and the
+    // manifests are saved.
+    match delivery.await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => log::error!("event delivery failed: {e}"),
+        Err(e) => log::error!("event delivery task failed: {e}"),
+    }
+    drop(backends);
+
+    Ok(())
+}
+
+/// Publishes `pending` in order, committing each event to the journal once
+/// the broker has it. The publisher's flush rides out short outages; if it
+/// gives up, it reconnects and everything the journal still holds up to the
+/// failed event is sent again, so nothing accepted is lost while the broker
+/// is away.
+///
+/// Returns false if the process is asked to stop first. Whatever was not
+/// delivered by then is still in the journal for the next run.
+async fn deliver(
+    publisher: &mut FilesystemPublisher,
+    journal: Option<&SharedJournal>,
+    baselines: Option<&Baselines>,
+    mut pending: VecDeque<RoutedEvent>,
+    stopped: &mut tokio::sync::watch::Receiver<bool>,
+) -> bool {
+    loop {
+        let published = tokio::select! {
+            published = publish_pending(publisher, journal, baselines, &mut pending) => published,
+            _ = stop_requested(stopped) => return false,
+        };
+        let e = match published {
+            Ok(()) => return true,
+            Err(e) => e,
+        };
+        log::error!("unable to publish event, reconnecting: {e}");
+        // Everything still in the outbox is in `pending` or the journal and
+        // goes out again from there.
+        publisher.discard_buffered();
+
+        let reconnected = tokio::select! {
+            reconnected = publisher.reconnect() => reconnected,
+            _ = stop_requested(stopped) => return false,
+        };
+        if let Err(e) = reconnected {
+            log::error!("unable to reconnect publisher: {e}");
+            continue;
+        }
+
+        let (Some(journal), Some(seq)) = (journal, pending.back().and_then(|r| r.seq)) else {
+            continue;
+        };
+        match journal.lock().map(|j| j.pending(Some(seq))) {
+            Ok(Ok(entries)) => {
+                let untracked = pending.into_iter().filter(|r| r.seq.is_none());
+                pending = entries.into_iter().map(RoutedEvent::from).chain(untracked).collect();
+                log::info!("replaying {} journaled events after reconnect", pending.len());
+            }
+            Ok(Err(e)) => log::error!("unable to read journal for replay: {e}"),
+            Err(e) => log::error!("unable to acquire lock on journal: {e}"),
+        }
+    }
+}
+
+async fn publish_pending(
+    publisher: &mut FilesystemPublisher,
+    journal: Option<&SharedJournal>,
+    baselines: Option<&Baselines>,
+    pending: &mut VecDeque<RoutedEvent>,
+) -> std::io::Result<()> {
+    while let Some(routed) = pending.front() {
+        if let (Some(journal), Some(seq)) = (journal, routed.seq) {
+            let skipped = skipped_entries(journal, seq);
+            if !skipped.is_empty() {
+                log::info!("replaying {} journaled events the queue dropped", skipped.len());
+                for routed in skipped.into_iter().rev() {
+                    pending.push_front(routed);
+                }
+                continue;
+            }
+        }
+        publisher.publish_to(&routed.topic, &routed.event).await?;
+        publisher.flush().await?;
+        log::info!("Succesfully published event...");
+
+        let Some(routed) = pending.pop_front() else {
+            break;
+        };
+        if let (Some(journal), Some(seq)) = (journal, routed.seq) {
+            match journal.lock() {
+                Ok(mut guard) => guard.commit(seq),
+                Err(e) => log::error!("unable to acquire lock on journal: {e}"),
+            }
+        }
+        if let Some(baselines) = baselines {
+            baselines.record(routed.event.event());
+        }
+    }
+
+    Ok(())
+}
+
+/// Resolves once the process has been asked to stop.
+async fn stop_requested(stopped: &mut tokio::sync::watch::Receiver<bool>) {
+    // Without a signal handler nothing will ever ask.
+    if stopped.wait_for(|stop| *stop).await.is_err() {
+        std::future::pending::<()>().await
+    }
+}
+
+fn save_manifests(baselines: Option<&Baselines>) {
+    if let Some(Err(e)) = baselines.map(|b| b.flush()) {
+        log::error!("unable to save manifests: {e}");
+    }
+}
+
+/// Writes the journal's committed offset to disk.
+fn flush_journal(journal: Option<&SharedJournal>) {
+    let Some(journal) = journal else {
+        return
+    };
+    match journal.lock().map(|mut j| j.flush()) {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => log::error!("unable to save journal offset: {e}"),
+        Err(e) => log::error!("unable to acquire lock on journal: {e}"),
+    }
+}
+
+/// Journaled events below `seq` that were never delivered. The queue's drop
+/// policies only bound memory while the journal is enabled: what they drop
+/// is read back from the journal here, before anything newer is committed
+/// past it.
+fn skipped_entries(journal: &SharedJournal, seq: u64) -> VecDeque<RoutedEvent> {
+    let entries = match journal.lock() {
+        Ok(guard) if guard.committed() + 1 < seq => guard.pending(Some(seq - 1)),
+        Ok(_) => return VecDeque::new(),
+        Err(e) => {
+            log::error!("unable to acquire lock on journal: {e}");
+            return VecDeque::new()
+        }
+    };
+    match entries {
+        Ok(entries) => entries.into_iter().map(RoutedEvent::from).collect(),
+        Err(e) => {
+            log::error!("unable to read journal for replay: {e}");
+            VecDeque::new()
+        }
+    }
+}
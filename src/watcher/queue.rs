@@ -0,0 +1,487 @@
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "policy")]
+pub enum OverflowPolicy {
+    /// Wait for the consumer to make room.
+    #[default]
+    Block,
+    // With the journal enabled, events dropped by the next two policies are
+    // read back from the journal before anything newer is delivered.
+    /// Evict the oldest queued event to make room for the new one.
+    DropOldest,
+    /// Discard the new event and count it.
+    DropNewest,
+    /// Append overflowing events to a file and feed them back in order once
+    /// the in-memory queue drains. Events left in the file by a previous run
+    /// are delivered again before anything new.
+    SpillToDisk { path: PathBuf },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct QueueConfig {
+    pub capacity: usize,
+    #[serde(default)]
+    pub overflow: OverflowPolicy,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self { capacity: 10_000, overflow: OverflowPolicy::Block }
+    }
+}
+
+struct Spill {
+    path: PathBuf,
+    writer: File,
+    pending: usize,
+    read_offset: u64,
+}
+
+impl Spill {
+    /// Opens the spill file, keeping whatever a previous run left in it so it
+    /// can be replayed. A line torn by a crash mid-write is cut off.
+    fn open(path: PathBuf) -> std::io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let writer = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)?;
+
+        let mut reader = BufReader::new(&writer);
+        let mut pending = 0;
+        let mut complete = 0;
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            let n = reader.read_until(b'\n', &mut line)?;
+            if n == 0 || line.last() != Some(&b'\n') {
+                break;
+            }
+            complete += n as u64;
+            pending += 1;
+        }
+        writer.set_len(complete)?;
+        if pending > 0 {
+            log::info!("replaying {pending} events spilled by a previous run from {}", path.display());
+        }
+
+        Ok(Self { path, writer, pending, read_offset: 0 })
+    }
+
+    fn write<T: Serialize>(&mut self, item: &T) -> std::io::Result<()> {
+        let mut line = serde_json::to_vec(item).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::Other,
+                e
+            )
+        })?;
+        line.push(b'\n');
+        self.writer.write_all(&line)?;
+        self.pending += 1;
+        Ok(())
+    }
+
+    fn read<T: DeserializeOwned>(&mut self, max: usize) -> std::io::Result<Vec<T>> {
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        reader.seek(SeekFrom::Start(self.read_offset))?;
+
+        let mut items = Vec::new();
+        let mut line = String::new();
+        while items.len() < max && self.pending > 0 {
+            line.clear();
+            let n = reader.read_line(&mut line)?;
+            if n == 0 {
+                break;
+            }
+            self.read_offset += n as u64;
+            self.pending -= 1;
+            match serde_json::from_str(&line) {
+                Ok(item) => items.push(item),
+                Err(e) => log::error!("discarding unreadable spilled event: {e}"),
+            }
+        }
+
+        if self.pending == 0 {
+            self.writer.set_len(0)?;
+            self.read_offset = 0;
+        }
+
+        Ok(items)
+    }
+}
+
+struct State<T> {
+    queue: VecDeque<T>,
+    spill: Option<Spill>,
+}
+
+impl<T> State<T> {
+    fn depth(&self) -> usize {
+        self.queue.len() + self.spill.as_ref().map(|s| s.pending).unwrap_or(0)
+    }
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    space: Condvar,
+    space_async: tokio::sync::Notify,
+    available: tokio::sync::Notify,
+    counters: Arc<Counters>,
+    senders: AtomicUsize,
+    closed: AtomicBool,
+}
+
+impl<T> Shared<T> {
+    fn lock(&self) -> std::io::Result<MutexGuard<'_, State<T>>> {
+        self.state.lock().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("unable to acquire lock on event queue: {e}")
+            )
+        })
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    depth: AtomicUsize,
+    dropped: AtomicU64,
+}
+
+/// Cheap handle for observing a queue without holding either end of it.
+#[derive(Clone)]
+pub struct QueueStats {
+    counters: Arc<Counters>,
+}
+
+impl QueueStats {
+    pub fn depth(&self) -> usize {
+        self.counters.depth.load(Ordering::SeqCst)
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.counters.dropped.load(Ordering::SeqCst)
+    }
+}
+
+/// Bounded queue between the watcher and the publisher.
+pub struct EventQueue<T> {
+    sender: EventSender<T>,
+    receiver: EventReceiver<T>,
+}
+
+impl<T: Serialize + DeserializeOwned + Send + 'static> EventQueue<T> {
+    pub fn new(config: QueueConfig) -> std::io::Result<Self> {
+        let spill = match &config.overflow {
+            OverflowPolicy::SpillToDisk { path } => Some(Spill::open(path.clone())?),
+            _ => None,
+        };
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State { queue: VecDeque::with_capacity(config.capacity), spill }),
+            capacity: config.capacity.max(1),
+            policy: config.overflow,
+            space: Condvar::new(),
+            space_async: tokio::sync::Notify::new(),
+            available: tokio::sync::Notify::new(),
+            counters: Arc::new(Counters::default()),
+            senders: AtomicUsize::new(1),
+            closed: AtomicBool::new(false),
+        });
+        let depth = shared.lock()?.depth();
+        shared.counters.depth.store(depth, Ordering::SeqCst);
+
+        Ok(Self {
+            sender: EventSender { shared: shared.clone() },
+            receiver: EventReceiver { shared },
+        })
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        self.receiver.stats()
+    }
+
+    pub fn split(self) -> (EventSender<T>, EventReceiver<T>) {
+        (self.sender, self.receiver)
+    }
+}
+
+pub struct EventSender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> Clone for EventSender<T> {
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::SeqCst);
+        Self { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for EventSender<T> {
+    fn drop(&mut self) {
+        if self.shared.senders.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.shared.closed.store(true, Ordering::SeqCst);
+            self.shared.available.notify_one();
+        }
+    }
+}
+
+enum Offer<T> {
+    Accepted,
+    Full(T),
+}
+
+impl<T: Serialize + DeserializeOwned + Send + 'static> EventSender<T> {
+    pub fn depth(&self) -> usize {
+        self.shared.counters.depth.load(Ordering::SeqCst)
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.shared.counters.dropped.load(Ordering::SeqCst)
+    }
+
+    fn offer(&self, state: &mut State<T>, item: T) -> std::io::Result<Offer<T>> {
+        let shared = &self.shared;
+        let spilling = state.spill.as_ref().map(|s| s.pending > 0).unwrap_or(false);
+        if state.queue.len() < shared.capacity && !spilling {
+            state.queue.push_back(item);
+        } else {
+            match &shared.policy {
+                OverflowPolicy::Block => return Ok(Offer::Full(item)),
+                OverflowPolicy::DropOldest => {
+                    state.queue.pop_front();
+                    state.queue.push_back(item);
+                    shared.counters.dropped.fetch_add(1, Ordering::SeqCst);
+                }
+                OverflowPolicy::DropNewest => {
+                    shared.counters.dropped.fetch_add(1, Ordering::SeqCst);
+                }
+                OverflowPolicy::SpillToDisk { .. } => {
+                    if let Some(spill) = state.spill.as_mut() {
+                        spill.write(&item)?;
+                    }
+                }
+            }
+        }
+
+        shared.counters.depth.store(state.depth(), Ordering::SeqCst);
+        shared.available.notify_one();
+        Ok(Offer::Accepted)
+    }
+
+    /// Enqueues from a synchronous context such as a notify callback,
+    /// blocking the calling thread under `OverflowPolicy::Block`.
+    pub fn send(&self, item: T) -> std::io::Result<()> {
+        let mut guard = self.shared.lock()?;
+        let mut item = item;
+        loop {
+            match self.offer(&mut guard, item)? {
+                Offer::Accepted => return Ok(()),
+                Offer::Full(rejected) => {
+                    item = rejected;
+                    guard = self.shared.space.wait(guard).map_err(|e| {
+                        std::io::Error::new(
+                            std::io::ErrorKind::Other,
+                            format!("unable to acquire lock on event queue: {e}")
+                        )
+                    })?;
+                }
+            }
+        }
+    }
+
+    /// Enqueues from an async context, waiting without blocking the runtime
+    /// under `OverflowPolicy::Block`.
+    pub async fn send_async(&self, item: T) -> std::io::Result<()> {
+        let mut item = item;
+        loop {
+            let space = self.shared.space_async.notified();
+            {
+                let mut guard = self.shared.lock()?;
+                match self.offer(&mut guard, item)? {
+                    Offer::Accepted => return Ok(()),
+                    Offer::Full(rejected) => item = rejected,
+                }
+            }
+            space.await;
+        }
+    }
+}
+
+pub struct EventReceiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Serialize + DeserializeOwned + Send + 'static> EventReceiver<T> {
+    pub fn depth(&self) -> usize {
+        self.shared.counters.depth.load(Ordering::SeqCst)
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.shared.counters.dropped.load(Ordering::SeqCst)
+    }
+
+    pub fn stats(&self) -> QueueStats {
+        QueueStats { counters: self.shared.counters.clone() }
+    }
+
+    fn try_pop(&self) -> std::io::Result<Option<T>> {
+        let mut guard = self.shared.lock()?;
+        let state = &mut *guard;
+        if state.queue.is_empty() {
+            if let Some(spill) = state.spill.as_mut() {
+                if spill.pending > 0 {
+                    state.queue.extend(spill.read(self.shared.capacity)?);
+                }
+            }
+        }
+
+        let item = state.queue.pop_front();
+        if item.is_some() {
+            self.shared.counters.depth.store(state.depth(), Ordering::SeqCst);
+            self.shared.space.notify_one();
+            self.shared.space_async.notify_one();
+        }
+
+        Ok(item)
+    }
+
+    /// Takes the next event if one is queued, without waiting.
+    pub fn try_recv(&mut self) -> std::io::Result<Option<T>> {
+        self.try_pop()
+    }
+
+    /// Waits for the next event. Returns `Ok(None)` once every sender is gone
+    /// and the queue has been drained.
+    pub async fn recv(&mut self) -> std::io::Result<Option<T>> {
+        loop {
+            let available = self.shared.available.notified();
+            if let Some(item) = self.try_pop()? {
+                return Ok(Some(item))
+            }
+            if self.shared.closed.load(Ordering::SeqCst) {
+                return self.try_pop()
+            }
+            available.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn queue(capacity: usize, overflow: OverflowPolicy) -> (EventSender<u32>, EventReceiver<u32>) {
+        EventQueue::new(QueueConfig { capacity, overflow }).unwrap().split()
+    }
+
+    async fn drain(mut receiver: EventReceiver<u32>) -> Vec<u32> {
+        let mut items = Vec::new();
+        while let Some(item) = receiver.recv().await.unwrap() {
+            items.push(item);
+        }
+        items
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_room() {
+        let (sender, mut receiver) = queue(2, OverflowPolicy::Block);
+        let producer = std::thread::spawn(move || {
+            for i in 0..5 {
+                sender.send(i).unwrap();
+            }
+        });
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(receiver.depth(), 2);
+        assert!(!producer.is_finished());
+
+        let mut items = Vec::new();
+        while let Some(item) = receiver.recv().await.unwrap() {
+            items.push(item);
+        }
+        producer.join().unwrap();
+        assert_eq!(items, vec![0, 1, 2, 3, 4]);
+        assert_eq!(receiver.dropped(), 0);
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_room_in_async_senders() {
+        let (sender, receiver) = queue(1, OverflowPolicy::Block);
+        let producer = tokio::spawn(async move {
+            for i in 0..4 {
+                sender.send_async(i).await.unwrap();
+            }
+        });
+        assert_eq!(drain(receiver).await, vec![0, 1, 2, 3]);
+        producer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_keeps_the_newest_events() {
+        let (sender, receiver) = queue(3, OverflowPolicy::DropOldest);
+        for i in 0..5 {
+            sender.send(i).unwrap();
+        }
+        let stats = receiver.stats();
+        assert_eq!((stats.depth(), stats.dropped()), (3, 2));
+        drop(sender);
+        assert_eq!(drain(receiver).await, vec![2, 3, 4]);
+        assert_eq!(stats.depth(), 0);
+    }
+
+    #[tokio::test]
+    async fn drop_newest_keeps_the_oldest_events() {
+        let (sender, receiver) = queue(3, OverflowPolicy::DropNewest);
+        for i in 0..5 {
+            sender.send(i).unwrap();
+        }
+        assert_eq!((sender.depth(), sender.dropped()), (3, 2));
+        drop(sender);
+        assert_eq!(drain(receiver).await, vec![0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn spilled_events_are_delivered_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("spill").join("events.jsonl");
+        let (sender, mut receiver) = queue(2, OverflowPolicy::SpillToDisk { path: path.clone() });
+        for i in 0..5 {
+            sender.send(i).unwrap();
+        }
+        assert_eq!((receiver.depth(), receiver.dropped()), (5, 0));
+
+        // Events sent while the spill still holds items go behind them.
+        assert_eq!(receiver.recv().await.unwrap(), Some(0));
+        assert_eq!(receiver.recv().await.unwrap(), Some(1));
+        sender.send(5).unwrap();
+        drop(sender);
+        assert_eq!(drain(receiver).await, vec![2, 3, 4, 5]);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn spills_left_by_a_previous_run_are_replayed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.jsonl");
+        std::fs::write(&path, b"10\n11\n12").unwrap();
+
+        let (sender, receiver) = queue(2, OverflowPolicy::SpillToDisk { path: path.clone() });
+        assert_eq!(receiver.depth(), 2);
+        sender.send(1).unwrap();
+        drop(sender);
+        assert_eq!(drain(receiver).await, vec![10, 11, 1]);
+    }
+}
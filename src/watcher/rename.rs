@@ -0,0 +1,291 @@
+use notify::event::{CreateKind, EventKind, ModifyKind, RemoveKind, RenameMode};
+use notify::Event;
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct RenameConfig {
+    /// How long one half of a rename waits for the other before it is
+    /// treated as a move out of (or into) the watched tree.
+    pub timeout_ms: u64,
+}
+
+impl RenameConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+}
+
+impl Default for RenameConfig {
+    fn default() -> Self {
+        Self { timeout_ms: 500 }
+    }
+}
+
+/// Whether the event is a completed rename carrying source then destination.
+pub fn is_move(event: &Event) -> bool {
+    matches!(event.kind, EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+        && event.paths.len() >= 2
+}
+
+fn with_path(event: &Event, kind: EventKind, path: PathBuf) -> Event {
+    let mut event = event.clone();
+    event.kind = kind;
+    event.paths = vec![path];
+    event
+}
+
+/// Breaks a move into a remove of the source and a create of the destination,
+/// for when only one side of it is visible to a root.
+pub fn split(event: &Event) -> (Event, Event) {
+    let from = event.paths.first().cloned().unwrap_or_default();
+    let to = event.paths.get(1).cloned().unwrap_or_default();
+    (
+        with_path(event, EventKind::Remove(RemoveKind::Any), from),
+        with_path(event, EventKind::Create(CreateKind::Any), to),
+    )
+}
+
+struct Half {
+    event: Event,
+    seen: Instant,
+}
+
+/// Pairs the `From` and `To` halves of a rename by their tracker id into a
+/// single `RenameMode::Both` event. Halves that never find a partner become a
+/// remove or a create once the timeout passes.
+pub struct RenameCorrelator {
+    config: RenameConfig,
+    from: HashMap<usize, Half>,
+    to: HashMap<usize, Half>,
+    // Trackers already paired here, so a `Both` the backend emits on its own
+    // afterwards is not published twice.
+    completed: HashMap<usize, Instant>,
+}
+
+impl RenameCorrelator {
+    pub fn new(config: RenameConfig) -> Self {
+        Self {
+            config,
+            from: HashMap::new(),
+            to: HashMap::new(),
+            completed: HashMap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.from.is_empty() && self.to.is_empty()
+    }
+
+    /// Returns whatever can go on now. Events that are not rename halves, or
+    /// that carry no tracker, come straight back.
+    pub fn push(&mut self, event: Event, now: Instant) -> Vec<Event> {
+        let (EventKind::Modify(ModifyKind::Name(mode)), Some(tracker)) = (event.kind, event.tracker()) else {
+            return vec![event]
+        };
+
+        match mode {
+            RenameMode::Both => {
+                self.from.remove(&tracker);
+                self.to.remove(&tracker);
+                if self.completed.remove(&tracker).is_some() {
+                    return Vec::new()
+                }
+                vec![event]
+            }
+            RenameMode::From => {
+                if self.completed.contains_key(&tracker) {
+                    return Vec::new()
+                }
+                match self.to.remove(&tracker) {
+                    Some(to) => vec![self.complete(tracker, event, to.event, now)],
+                    None => {
+                        self.from.insert(tracker, Half { event, seen: now });
+                        Vec::new()
+                    }
+                }
+            }
+            RenameMode::To => {
+                if self.completed.contains_key(&tracker) {
+                    return Vec::new()
+                }
+                match self.from.remove(&tracker) {
+                    Some(from) => vec![self.complete(tracker, from.event, event, now)],
+                    None => {
+                        self.to.insert(tracker, Half { event, seen: now });
+                        Vec::new()
+                    }
+                }
+            }
+            RenameMode::Any | RenameMode::Other => vec![event],
+        }
+    }
+
+    fn complete(&mut self, tracker: usize, from: Event, to: Event, now: Instant) -> Event {
+        self.completed.insert(tracker, now);
+        let mut event = from;
+        event.kind = EventKind::Modify(ModifyKind::Name(RenameMode::Both));
+        event.paths.truncate(1);
+        event.paths.extend(to.paths.into_iter().take(1));
+        event
+    }
+
+    /// Gives up on halves older than the timeout. A lone `From` means the
+    /// path left the watched tree and a lone `To` means one arrived.
+    pub fn expire(&mut self, now: Instant) -> Vec<Event> {
+        let timeout = self.config.timeout();
+        self.completed.retain(|_, seen| now.duration_since(*seen) < timeout);
+
+        let mut expired: Vec<(Instant, Event)> = Vec::new();
+        self.from.retain(|_, half| {
+            if now.duration_since(half.seen) < timeout {
+                return true
+            }
+            let path = half.event.paths.first().cloned().unwrap_or_default();
+            expired.push((half.seen, with_path(&half.event, EventKind::Remove(RemoveKind::Any), path)));
+            false
+        });
+        self.to.retain(|_, half| {
+            if now.duration_since(half.seen) < timeout {
+                return true
+            }
+            let path = half.event.paths.first().cloned().unwrap_or_default();
+            expired.push((half.seen, with_path(&half.event, EventKind::Create(CreateKind::Any), path)));
+            false
+        });
+
+        expired.sort_by_key(|(seen, _)| *seen);
+        expired.into_iter().map(|(_, event)| event).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::DataChange;
+
+    fn half(mode: RenameMode, path: &str, tracker: Option<usize>) -> Event {
+        let event = Event::new(EventKind::Modify(ModifyKind::Name(mode))).add_path(PathBuf::from(path));
+        match tracker {
+            Some(tracker) => event.set_tracker(tracker),
+            None => event,
+        }
+    }
+
+    fn both(from: &str, to: &str, tracker: usize) -> Event {
+        Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+            .add_path(PathBuf::from(from))
+            .add_path(PathBuf::from(to))
+            .set_tracker(tracker)
+    }
+
+    fn summary(events: &[Event]) -> Vec<(EventKind, Vec<PathBuf>)> {
+        events.iter().map(|e| (e.kind, e.paths.clone())).collect()
+    }
+
+    fn moved(from: &str, to: &str) -> (EventKind, Vec<PathBuf>) {
+        (EventKind::Modify(ModifyKind::Name(RenameMode::Both)), vec![PathBuf::from(from), PathBuf::from(to)])
+    }
+
+    fn correlator() -> RenameCorrelator {
+        RenameCorrelator::new(RenameConfig { timeout_ms: 100 })
+    }
+
+    #[test]
+    fn halves_pair_by_tracker_in_either_order() {
+        let mut renames = correlator();
+        let now = Instant::now();
+        assert!(renames.push(half(RenameMode::From, "/a", Some(1)), now).is_empty());
+        assert!(renames.push(half(RenameMode::To, "/y", Some(2)), now).is_empty());
+        assert!(!renames.is_empty());
+
+        let paired = renames.push(half(RenameMode::From, "/x", Some(2)), now);
+        assert_eq!(summary(&paired), vec![moved("/x", "/y")]);
+        let paired = renames.push(half(RenameMode::To, "/b", Some(1)), now);
+        assert_eq!(summary(&paired), vec![moved("/a", "/b")]);
+        assert_eq!(paired[0].tracker(), Some(1));
+        assert!(renames.is_empty());
+    }
+
+    #[test]
+    fn the_backends_own_both_event_is_dropped_once_paired() {
+        let mut renames = correlator();
+        let now = Instant::now();
+        renames.push(half(RenameMode::From, "/a", Some(1)), now);
+        assert_eq!(renames.push(half(RenameMode::To, "/b", Some(1)), now).len(), 1);
+        assert!(renames.push(both("/a", "/b", 1), now).is_empty());
+
+        // A `Both` for a rename that was never paired here goes through.
+        assert_eq!(summary(&renames.push(both("/c", "/d", 2), now)), vec![moved("/c", "/d")]);
+    }
+
+    #[test]
+    fn a_both_event_supersedes_waiting_halves() {
+        let mut renames = correlator();
+        let now = Instant::now();
+        renames.push(half(RenameMode::From, "/a", Some(1)), now);
+        assert_eq!(renames.push(both("/a", "/b", 1), now).len(), 1);
+        assert!(renames.is_empty());
+        assert!(renames.expire(now + Duration::from_secs(1)).is_empty());
+    }
+
+    #[test]
+    fn lone_halves_expire_into_removes_and_creates() {
+        let mut renames = correlator();
+        let start = Instant::now();
+        renames.push(half(RenameMode::To, "/in", Some(2)), start + Duration::from_millis(10));
+        renames.push(half(RenameMode::From, "/out", Some(1)), start);
+
+        assert!(renames.expire(start + Duration::from_millis(99)).is_empty());
+        let expired = renames.expire(start + Duration::from_millis(110));
+        assert_eq!(summary(&expired), vec![
+            (EventKind::Remove(RemoveKind::Any), vec![PathBuf::from("/out")]),
+            (EventKind::Create(CreateKind::Any), vec![PathBuf::from("/in")]),
+        ]);
+        assert!(renames.is_empty());
+
+        // The partner turning up late no longer pairs.
+        assert!(renames.push(half(RenameMode::To, "/late", Some(1)), start + Duration::from_millis(120)).is_empty());
+    }
+
+    #[test]
+    fn completed_trackers_are_forgotten_after_the_timeout() {
+        let mut renames = correlator();
+        let start = Instant::now();
+        renames.push(half(RenameMode::From, "/a", Some(1)), start);
+        renames.push(half(RenameMode::To, "/b", Some(1)), start);
+        assert!(renames.push(half(RenameMode::To, "/b", Some(1)), start).is_empty());
+
+        renames.expire(start + Duration::from_millis(100));
+        assert_eq!(renames.push(both("/a", "/b", 1), start + Duration::from_millis(100)).len(), 1);
+    }
+
+    #[test]
+    fn events_without_a_tracker_pass_through() {
+        let mut renames = correlator();
+        let now = Instant::now();
+        let lone = renames.push(half(RenameMode::From, "/a", None), now);
+        assert_eq!(summary(&lone), vec![(EventKind::Modify(ModifyKind::Name(RenameMode::From)), vec![PathBuf::from("/a")])]);
+
+        let write = Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Any)))
+            .add_path(PathBuf::from("/a"))
+            .set_tracker(3);
+        assert_eq!(renames.push(write, now).len(), 1);
+        assert_eq!(renames.push(half(RenameMode::Any, "/a", Some(4)), now).len(), 1);
+        assert!(renames.is_empty());
+    }
+
+    #[test]
+    fn moves_split_into_a_remove_and_a_create() {
+        let event = both("/a", "/b", 1);
+        assert!(is_move(&event));
+        assert!(!is_move(&half(RenameMode::From, "/a", Some(1))));
+        let (remove, create) = split(&event);
+        assert_eq!(summary(&[remove, create]), vec![
+            (EventKind::Remove(RemoveKind::Any), vec![PathBuf::from("/a")]),
+            (EventKind::Create(CreateKind::Any), vec![PathBuf::from("/b")]),
+        ]);
+    }
+}
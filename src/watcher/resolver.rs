@@ -0,0 +1,201 @@
+use serde::{Serialize, Deserialize};
+use std::path::{Component, Path, PathBuf};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InstanceKind {
+    Container,
+    VirtualMachine,
+}
+
+/// The instance a host path belongs to, and where that path lives inside it.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct InstancePath {
+    pub name: String,
+    pub project: Option<String>,
+    pub kind: InstanceKind,
+    pub snapshot: Option<String>,
+    /// Absolute path as seen from inside the instance. For containers this is
+    /// relative to `rootfs`, for virtual machines relative to the instance
+    /// directory.
+    pub path: PathBuf,
+    /// False for container files that live next to `rootfs`, such as
+    /// `backup.yaml` or `metadata.yaml`.
+    pub in_rootfs: bool,
+}
+
+impl InstancePath {
+    /// The name LXD knows the instance by, including the project prefix used
+    /// on disk for non-default projects.
+    pub fn qualified_name(&self) -> String {
+        match &self.project {
+            Some(project) => format!("{}_{}", project, self.name),
+            None => self.name.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InstanceLayout {
+    /// The watch root is (or is inside) an LXD storage pool, with
+    /// `containers/`, `virtual-machines/` and their `-snapshots` siblings.
+    #[default]
+    StoragePool,
+    /// The watch root is a plain directory tree with no instances in it.
+    Plain,
+}
+
+#[derive(Clone, Debug)]
+pub struct InstanceResolver {
+    root: PathBuf,
+    layout: InstanceLayout,
+}
+
+impl InstanceResolver {
+    pub fn new(root: impl AsRef<Path>, layout: InstanceLayout) -> Self {
+        Self { root: root.as_ref().to_path_buf(), layout }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn layout(&self) -> InstanceLayout {
+        self.layout
+    }
+
+    /// The path relative to the watch root, rendered with a leading `/`.
+    pub fn relative(&self, path: &Path) -> PathBuf {
+        let rel = path.strip_prefix(&self.root).unwrap_or(path);
+        Path::new("/").join(rel)
+    }
+
+    /// The path filter rules should be evaluated against: the in-instance
+    /// path when the event belongs to an instance rootfs, and the
+    /// root-relative path otherwise.
+    pub fn filter_path(&self, path: &Path, instance: Option<&InstancePath>) -> String {
+        match instance {
+            Some(instance) if instance.in_rootfs => instance.path.display().to_string(),
+            _ => self.relative(path).display().to_string(),
+        }
+    }
+
+    pub fn resolve(&self, path: &Path) -> Option<InstancePath> {
+        if self.layout == InstanceLayout::Plain {
+            return None
+        }
+
+        let components: Vec<&str> = path.components().filter_map(|c| {
+            match c {
+                Component::Normal(part) => part.to_str(),
+                _ => None,
+            }
+        }).collect();
+        let root_depth = self.root.components().filter(|c| {
+            matches!(c, Component::Normal(_))
+        }).count();
+
+        let candidates = components.iter().enumerate().filter_map(|(idx, part)| {
+            match *part {
+                "containers" => Some((idx, InstanceKind::Container, false)),
+                "containers-snapshots" => Some((idx, InstanceKind::Container, true)),
+                "virtual-machines" => Some((idx, InstanceKind::VirtualMachine, false)),
+                "virtual-machines-snapshots" => Some((idx, InstanceKind::VirtualMachine, true)),
+                _ => None,
+            }
+        });
+
+        // The root may point at the pool itself or somewhere below one of its
+        // type directories. Take the first type directory below the root, so
+        // a root that happens to have one in its own path (such as
+        // `/srv/containers/pool`) still resolves, and fall back to the
+        // deepest one in the root's path only when nothing below matches.
+        let (idx, kind, is_snapshot) = candidates.clone()
+            .find(|(idx, _, _)| *idx >= root_depth)
+            .or_else(|| candidates.rev().find(|(idx, _, _)| *idx < root_depth))?;
+
+        let mut rest = components[idx + 1..].iter();
+        let (project, name) = split_project(rest.next()?);
+        let snapshot = if is_snapshot {
+            Some(rest.next()?.to_string())
+        } else {
+            None
+        };
+
+        let remainder: Vec<&str> = rest.copied().collect();
+        let (in_rootfs, inner) = match kind {
+            InstanceKind::Container => match remainder.split_first() {
+                Some((&"rootfs", inner)) => (true, inner),
+                _ => (false, remainder.as_slice()),
+            },
+            InstanceKind::VirtualMachine => (false, remainder.as_slice()),
+        };
+
+        let mut instance_path = PathBuf::from("/");
+        instance_path.extend(inner);
+
+        Some(InstancePath {
+            name,
+            project,
+            kind,
+            snapshot,
+            path: instance_path,
+            in_rootfs,
+        })
+    }
+}
+
+fn split_project(dir_name: &str) -> (Option<String>, String) {
+    // LXD instance names cannot contain underscores, so the first one always
+    // separates the project from the instance name.
+    match dir_name.split_once('_') {
+        Some((project, name)) => (Some(project.to_string()), name.to_string()),
+        None => (None, dir_name.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(root: &str, path: &str) -> Option<InstancePath> {
+        InstanceResolver::new(root, InstanceLayout::StoragePool).resolve(Path::new(path))
+    }
+
+    #[test]
+    fn instances_below_a_pool_root_are_resolved() {
+        let instance = resolve("/var/lib/lxd/storage-pools/default", "/var/lib/lxd/storage-pools/default/containers/web_c1/rootfs/etc/hosts").unwrap();
+        assert_eq!(instance.name, "c1");
+        assert_eq!(instance.project.as_deref(), Some("web"));
+        assert_eq!(instance.kind, InstanceKind::Container);
+        assert_eq!(instance.path, PathBuf::from("/etc/hosts"));
+        assert!(instance.in_rootfs);
+
+        let snapshot = resolve("/pool", "/pool/virtual-machines-snapshots/vm1/snap0/root.img").unwrap();
+        assert_eq!(snapshot.kind, InstanceKind::VirtualMachine);
+        assert_eq!(snapshot.snapshot.as_deref(), Some("snap0"));
+        assert_eq!(snapshot.path, PathBuf::from("/root.img"));
+    }
+
+    #[test]
+    fn type_directories_in_the_root_path_are_skipped() {
+        let instance = resolve("/srv/containers/pool/", "/srv/containers/pool/containers/c1/rootfs/etc/hosts").unwrap();
+        assert_eq!(instance.name, "c1");
+        assert_eq!(instance.path, PathBuf::from("/etc/hosts"));
+    }
+
+    #[test]
+    fn roots_inside_a_type_directory_use_it() {
+        let instance = resolve("/pool/containers", "/pool/containers/c1/backup.yaml").unwrap();
+        assert_eq!(instance.name, "c1");
+        assert!(!instance.in_rootfs);
+        assert_eq!(instance.path, PathBuf::from("/backup.yaml"));
+    }
+
+    #[test]
+    fn plain_layouts_and_pool_metadata_have_no_instance() {
+        assert_eq!(InstanceResolver::new("/pool", InstanceLayout::Plain).resolve(Path::new("/pool/containers/c1/x")), None);
+        assert_eq!(resolve("/pool", "/pool/images/abc"), None);
+    }
+}
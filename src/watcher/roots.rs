@@ -0,0 +1,387 @@
+use notify::RecursiveMode;
+use serde::{Serialize, Deserialize};
+use std::path::{Path, PathBuf};
+
+use crate::journal::JournalConfig;
+use crate::pubsub::{FilesystemEvent, FilesystemTopic};
+use super::backend::{BackendConfig, PollConfig};
+use super::debounce::DebounceConfig;
+use super::queue::QueueConfig;
+use super::rename::{self, RenameConfig};
+use super::resolver::{InstanceLayout, InstancePath, InstanceResolver};
+use super::rules::{FilterConfig, PathFilter};
+use super::scan::BaselineConfig;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RootConfig {
+    pub path: PathBuf,
+    #[serde(default = "RootConfig::default_recursive")]
+    pub recursive: bool,
+    #[serde(default)]
+    pub layout: InstanceLayout,
+    /// Inline filter rules for this root.
+    #[serde(default)]
+    pub filter: Option<FilterConfig>,
+    /// Filter rules loaded from, and reloadable from, a separate file. Takes
+    /// precedence over `filter`.
+    #[serde(default)]
+    pub filter_file: Option<PathBuf>,
+    /// Topic events from this root are published on. Defaults to
+    /// `FilesystemTopic`.
+    #[serde(default)]
+    pub topic: Option<String>,
+    #[serde(default)]
+    pub backend: BackendConfig,
+    /// Polling settings used if the native watch cannot be registered.
+    /// Defaults to `PollConfig::default()`.
+    #[serde(default)]
+    pub poll_fallback: Option<PollConfig>,
+}
+
+impl RootConfig {
+    fn default_recursive() -> bool {
+        true
+    }
+
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            recursive: true,
+            layout: InstanceLayout::default(),
+            filter: None,
+            filter_file: None,
+            topic: None,
+            backend: BackendConfig::default(),
+            poll_fallback: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct WatchConfig {
+    pub roots: Vec<RootConfig>,
+    #[serde(default)]
+    pub debounce: DebounceConfig,
+    #[serde(default)]
+    pub queue: QueueConfig,
+    #[serde(default)]
+    pub baseline: BaselineConfig,
+    #[serde(default)]
+    pub journal: JournalConfig,
+    #[serde(default)]
+    pub rename: RenameConfig,
+}
+
+impl WatchConfig {
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let contents = std::fs::read(path.as_ref())?;
+        serde_json::from_slice(&contents).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unable to parse watch config {}: {e}", path.as_ref().display())
+            )
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct WatchRoot {
+    path: PathBuf,
+    recursive: RecursiveMode,
+    resolver: InstanceResolver,
+    filter: PathFilter,
+    topic: String,
+    backend: BackendConfig,
+    poll_fallback: Option<PollConfig>,
+}
+
+impl WatchRoot {
+    pub fn new(
+        path: impl AsRef<Path>,
+        recursive: RecursiveMode,
+        layout: InstanceLayout,
+        filter: PathFilter,
+        topic: Option<String>,
+    ) -> Self {
+        let path = path.as_ref().to_path_buf();
+        Self {
+            resolver: InstanceResolver::new(&path, layout),
+            path,
+            recursive,
+            filter,
+            topic: topic.unwrap_or_else(|| FilesystemTopic.to_string()),
+            backend: BackendConfig::default(),
+            poll_fallback: None,
+        }
+    }
+
+    pub fn with_backend(mut self, backend: BackendConfig) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    pub fn with_poll_fallback(mut self, config: Option<PollConfig>) -> Self {
+        self.poll_fallback = config;
+        self
+    }
+
+    pub fn from_config(config: &RootConfig) -> std::io::Result<Self> {
+        let filter = match (&config.filter_file, &config.filter) {
+            (Some(file), _) => PathFilter::from_file(file)?,
+            (None, Some(filter)) => PathFilter::new(filter)?,
+            (None, None) => PathFilter::default(),
+        };
+        let recursive = if config.recursive {
+            RecursiveMode::Recursive
+        } else {
+            RecursiveMode::NonRecursive
+        };
+
+        Ok(
+            Self::new(&config.path, recursive, config.layout, filter, config.topic.clone())
+                .with_backend(config.backend)
+                .with_poll_fallback(config.poll_fallback)
+        )
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn recursive(&self) -> RecursiveMode {
+        self.recursive
+    }
+
+    pub fn filter(&self) -> &PathFilter {
+        &self.filter
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    pub fn backend(&self) -> BackendConfig {
+        self.backend
+    }
+
+    /// How the root is polled when the native backend cannot watch it: its
+    /// own poll settings if it is configured to poll, else its fallback.
+    pub fn poll_config(&self) -> PollConfig {
+        match self.backend {
+            BackendConfig::Poll(config) => config,
+            BackendConfig::Native => self.poll_fallback.unwrap_or_default(),
+        }
+    }
+
+    pub fn contains(&self, path: &Path) -> bool {
+        path.starts_with(&self.path)
+    }
+
+    fn resolve(&self, path: &Path) -> (Option<InstancePath>, bool) {
+        let instance = self.resolver.resolve(path);
+        let filter_path = self.resolver.filter_path(path, instance.as_ref());
+        log::info!("Change detected in {:?} at {}", instance.as_ref().map(|i| &i.name), &filter_path);
+
+        let allowed = self.filter.allows(&filter_path);
+        (instance, allowed)
+    }
+
+    /// Resolves the instance a path belongs to and applies this root's
+    /// filter rules, returning `None` for excluded paths.
+    pub fn accept(&self, event: notify::Event) -> Option<FilesystemEvent> {
+        if rename::is_move(&event) {
+            return self.accept_move(event)
+        }
+
+        let path = event.paths.first()?;
+        let (instance, allowed) = self.resolve(path);
+        if !allowed {
+            return None
+        }
+
+        Some(FilesystemEvent::new(event, instance))
+    }
+
+    /// A move whose destination is excluded, or outside this root, is a
+    /// remove as far as the root is concerned, and one whose source is
+    /// excluded is a create.
+    fn accept_move(&self, event: notify::Event) -> Option<FilesystemEvent> {
+        let (from, to) = (event.paths.first()?, event.paths.get(1)?);
+        let (source, source_allowed) = self.resolve(from);
+        let (destination, destination_allowed) = if self.contains(to) {
+            self.resolve(to)
+        } else {
+            (None, false)
+        };
+
+        let (removed, created) = rename::split(&event);
+        match (source_allowed, destination_allowed) {
+            (true, true) => Some(FilesystemEvent::new(event, source).with_destination(destination)),
+            (true, false) => Some(FilesystemEvent::new(removed, source)),
+            (false, true) => Some(FilesystemEvent::new(created, destination)),
+            (false, false) => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct WatchRoots {
+    roots: Vec<WatchRoot>,
+}
+
+impl WatchRoots {
+    pub fn new(mut roots: Vec<WatchRoot>) -> Self {
+        // Longest paths first, so nested roots win over their parents.
+        roots.sort_by_key(|root| std::cmp::Reverse(root.path.components().count()));
+        Self { roots }
+    }
+
+    pub fn from_config(config: &WatchConfig) -> std::io::Result<Self> {
+        let roots = config.roots.iter()
+            .map(WatchRoot::from_config)
+            .collect::<std::io::Result<Vec<_>>>()?;
+        Ok(Self::new(roots))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &WatchRoot> {
+        self.roots.iter()
+    }
+
+    pub fn route(&self, path: &Path) -> Option<&WatchRoot> {
+        self.roots.iter().find(|root| root.contains(path))
+    }
+
+    /// Hands the event to the root it belongs to. A move between two roots
+    /// becomes a remove in one and a create in the other.
+    pub fn accept(&self, event: notify::Event) -> Vec<FilesystemEvent> {
+        let Some(root) = event.paths.first().and_then(|p| self.route(p)) else {
+            return Vec::new()
+        };
+
+        if rename::is_move(&event) {
+            let other = event.paths.get(1)
+                .and_then(|p| self.route(p))
+                .filter(|other| other.path() != root.path());
+            if let Some(other) = other {
+                let (removed, created) = rename::split(&event);
+                return root.accept(removed).into_iter().chain(other.accept(created)).collect()
+            }
+        }
+
+        root.accept(event).into_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{CreateKind, DataChange, EventKind, ModifyKind, RemoveKind, RenameMode};
+    use notify::Event;
+
+    fn root(path: &str, topic: &str, layout: InstanceLayout, exclude: &str) -> WatchRoot {
+        let filter: FilterConfig = serde_json::from_value(serde_json::json!({
+            "profile": "empty",
+            "rules": [{"action": "exclude", "glob": exclude}],
+        })).unwrap();
+        WatchRoot::new(path, RecursiveMode::Recursive, layout, PathFilter::new(&filter).unwrap(), Some(topic.to_string()))
+    }
+
+    fn roots() -> WatchRoots {
+        WatchRoots::new(vec![
+            root("/pool", "pool", InstanceLayout::StoragePool, "*.swp"),
+            root("/pool/containers/c1/rootfs/srv", "srv", InstanceLayout::Plain, "*.log"),
+            root("/data", "data", InstanceLayout::Plain, "/cache/**"),
+        ])
+    }
+
+    fn write(path: &str) -> Event {
+        Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Content))).add_path(PathBuf::from(path))
+    }
+
+    fn moved(from: &str, to: &str) -> Event {
+        Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+            .add_path(PathBuf::from(from))
+            .add_path(PathBuf::from(to))
+    }
+
+    fn summary(events: &[FilesystemEvent]) -> Vec<(EventKind, PathBuf, Option<String>)> {
+        events.iter().map(|e| (
+            e.event().kind,
+            e.event().paths[0].clone(),
+            e.instance().as_ref().map(|i| i.name.clone()),
+        )).collect()
+    }
+
+    #[test]
+    fn nested_roots_win_over_their_parents() {
+        let roots = roots();
+        let topic = |path: &str| roots.route(Path::new(path)).map(|root| root.topic().to_string());
+        assert_eq!(topic("/pool/containers/c1/rootfs/srv/www/index.html").as_deref(), Some("srv"));
+        assert_eq!(topic("/pool/containers/c1/rootfs/etc/hosts").as_deref(), Some("pool"));
+        assert_eq!(topic("/data/db"), Some("data".to_string()));
+        assert_eq!(topic("/database/db"), None);
+    }
+
+    #[test]
+    fn each_root_applies_its_own_resolver_and_filter() {
+        let roots = roots();
+        let accepted = roots.accept(write("/pool/containers/c1/rootfs/etc/hosts"));
+        assert_eq!(accepted[0].instance().as_ref().map(|i| i.path.clone()), Some(PathBuf::from("/etc/hosts")));
+
+        // The nested root is plain, so it has no instance, and only its own
+        // rules apply.
+        assert_eq!(summary(&roots.accept(write("/pool/containers/c1/rootfs/srv/notes.swp"))), vec![
+            (EventKind::Modify(ModifyKind::Data(DataChange::Content)), PathBuf::from("/pool/containers/c1/rootfs/srv/notes.swp"), None),
+        ]);
+        assert!(roots.accept(write("/pool/containers/c1/rootfs/srv/access.log")).is_empty());
+        assert!(roots.accept(write("/pool/containers/c1/rootfs/etc/.hosts.swp")).is_empty());
+        assert!(roots.accept(write("/data/cache/a")).is_empty());
+        assert!(roots.accept(write("/elsewhere/a")).is_empty());
+    }
+
+    #[test]
+    fn moves_within_a_root_keep_both_ends() {
+        let roots = roots();
+        let accepted = roots.accept(moved("/pool/containers/c1/rootfs/etc/a", "/pool/containers/c2/rootfs/etc/b"));
+        assert_eq!(accepted.len(), 1);
+        assert!(rename::is_move(accepted[0].event()));
+        assert_eq!(accepted[0].instance().as_ref().map(|i| i.name.as_str()), Some("c1"));
+        assert_eq!(accepted[0].destination().as_ref().map(|i| i.name.as_str()), Some("c2"));
+    }
+
+    #[test]
+    fn moves_with_an_excluded_end_become_a_remove_or_a_create() {
+        let roots = roots();
+        assert_eq!(summary(&roots.accept(moved("/data/a", "/data/cache/a"))), vec![
+            (EventKind::Remove(RemoveKind::Any), PathBuf::from("/data/a"), None),
+        ]);
+        assert_eq!(summary(&roots.accept(moved("/data/cache/a", "/data/a"))), vec![
+            (EventKind::Create(CreateKind::Any), PathBuf::from("/data/a"), None),
+        ]);
+        assert!(roots.accept(moved("/data/cache/a", "/data/cache/b")).is_empty());
+        assert_eq!(summary(&roots.accept(moved("/data/a", "/elsewhere/a"))), vec![
+            (EventKind::Remove(RemoveKind::Any), PathBuf::from("/data/a"), None),
+        ]);
+    }
+
+    #[test]
+    fn moves_across_roots_split_between_them() {
+        let roots = roots();
+        let accepted = roots.accept(moved("/pool/containers/c1/rootfs/etc/motd", "/pool/containers/c1/rootfs/srv/motd"));
+        assert_eq!(summary(&accepted), vec![
+            (EventKind::Remove(RemoveKind::Any), PathBuf::from("/pool/containers/c1/rootfs/etc/motd"), Some("c1".to_string())),
+            (EventKind::Create(CreateKind::Any), PathBuf::from("/pool/containers/c1/rootfs/srv/motd"), None),
+        ]);
+
+        // Each half still goes through its own root's rules.
+        let accepted = roots.accept(moved("/data/a", "/pool/containers/c1/rootfs/srv/a.log"));
+        assert_eq!(summary(&accepted), vec![
+            (EventKind::Remove(RemoveKind::Any), PathBuf::from("/data/a"), None),
+        ]);
+        let accepted = roots.accept(moved("/pool/containers/c1/rootfs/srv/www", "/data/www"));
+        let topics: Vec<_> = accepted.iter()
+            .map(|e| roots.route(&e.event().paths[0]).unwrap().topic().to_string())
+            .collect();
+        assert_eq!(topics, ["srv", "data"]);
+    }
+}
@@ -0,0 +1,338 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Serialize, Deserialize};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+lazy_static! {
+    pub static ref SYSTEM_PATHS: Vec<&'static str> = vec![
+        "/var/lib/snapd", "/snap/", "/var/log/", "/var/run/utmp", "/var/run/wtmp", "/var/run/btmp",
+        "/tmp/", "/var/tmp/", "/var/cache/", "/var/lib/apt/", "/var/lib/dpkg/", "/var/lib/systemd/",
+        "/var/lib/dbus/", "/var/lib/NetworkManager/", "/var/lib/ucf/", "/var/lib/apt/lists/",
+        "/var/lock/", "/var/lib/lock/", "/var/lib/rpm/", "/var/lib/pacman/", "/var/run/",
+        "/run/", "/usr/bin/", "/usr/sbin/", "/usr/lib/", "/lib/", "/lib64/", "/sbin/", "/bin/",
+        "/tmp/.X11-unix/", "/var/lib/lightdm/", "/var/lib/gdm3/", "/var/lib/sddm/", "/var/crash/",
+        "/var/lib/AccountsService/", "/var/lib/alsa/", "/var/lib/bluetooth/", "/var/lib/colord/",
+        "/var/lib/connman/", "/var/lib/console-setup/", "/var/lib/dhcp/", "/var/lib/dovecot/",
+        "/var/lib/flatpak/", "/var/lib/fwupd/", "/var/lib/gdm3/", "/var/lib/hwclock/",
+        "/var/lib/iio-sensor-proxy/", "/var/lib/initramfs-tools/", "/var/lib/initscripts/",
+        "/var/lib/insserv/", "/var/lib/ipsec/", "/var/lib/iscsi/", "/var/lib/kubelet/",
+        "/var/lib/libvirt/", "/var/lib/logrotate/", "/var/lib/machines/", "/var/lib/mdadm/",
+        "/var/lib/misc/", "/var/lib/mlocate/", "/var/lib/NetworkManager/", "/var/lib/nginx/",
+        "/var/lib/nodm/", "/var/lib/nss/", "/var/lib/nut/", "/var/lib/openvpn/", "/var/lib/pam/",
+        "/var/lib/pciutils/", "/var/lib/plymouth/", "/var/lib/polkit-1/", "/var/lib/postgresql/",
+        "/var/lib/pulse/", "/var/lib/rsyslog/", "/var/lib/samba/", "/var/lib/sddm/",
+        "/var/lib/snapd/", "/var/lib/snmp/", "/var/lib/sssd/", "/var/lib/stratisd/", "/var/lib/sudo/",
+        "/var/lib/systemd/", "/var/lib/tor/", "/var/lib/ucf/", "/var/lib/udisks2/",
+        "/var/lib/unattended-upgrades/", "/var/lib/upower/", "/var/lib/usbutils/", "/var/lib/vmware/",
+        "/var/lib/xdm/", "/var/lib/xkb/", "/etc/", "/boot/", "/proc/", "/sys/", "/dev/"
+    ];
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleAction {
+    Include,
+    Exclude,
+}
+
+/// A single path pattern as written in a config file, e.g. `{"glob": "/var/lib/*/cache/**"}`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PatternSpec {
+    Prefix(String),
+    Glob(String),
+    Regex(String),
+}
+
+#[derive(Clone, Debug)]
+pub enum PathPattern {
+    Prefix(String),
+    Glob(glob::Pattern),
+    Regex(Regex),
+}
+
+impl PathPattern {
+    pub fn matches(&self, path: &str) -> bool {
+        match self {
+            PathPattern::Prefix(prefix) => path.starts_with(prefix.as_str()),
+            PathPattern::Glob(pattern) => pattern.matches(path),
+            PathPattern::Regex(re) => re.is_match(path),
+        }
+    }
+}
+
+impl TryFrom<&PatternSpec> for PathPattern {
+    type Error = std::io::Error;
+
+    fn try_from(spec: &PatternSpec) -> std::io::Result<Self> {
+        let pattern = match spec {
+            PatternSpec::Prefix(prefix) => PathPattern::Prefix(prefix.clone()),
+            PatternSpec::Glob(glob) => PathPattern::Glob(
+                glob::Pattern::new(glob).map_err(|e| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("invalid glob pattern {glob}: {e}")
+                    )
+                })?
+            ),
+            PatternSpec::Regex(re) => PathPattern::Regex(
+                Regex::new(re).map_err(|e| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("invalid regex pattern {re}: {e}")
+                    )
+                })?
+            ),
+        };
+
+        Ok(pattern)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RuleSpec {
+    pub action: RuleAction,
+    #[serde(flatten)]
+    pub pattern: PatternSpec,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Profile {
+    /// The historical `SYSTEM_PATHS` list, applied as prefix excludes.
+    #[default]
+    Default,
+    Empty,
+}
+
+/// On-disk filter configuration. User rules are evaluated in order before the
+/// rules of the selected profile, and the first matching rule wins.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FilterConfig {
+    #[serde(default)]
+    pub profile: Profile,
+    #[serde(default = "FilterConfig::default_action")]
+    pub default_action: RuleAction,
+    #[serde(default)]
+    pub rules: Vec<RuleSpec>,
+}
+
+impl FilterConfig {
+    fn default_action() -> RuleAction {
+        RuleAction::Include
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let contents = std::fs::read(path.as_ref())?;
+        serde_json::from_slice(&contents).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unable to parse filter config {}: {e}", path.as_ref().display())
+            )
+        })
+    }
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        Self {
+            profile: Profile::Default,
+            default_action: RuleAction::Include,
+            rules: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PathRule {
+    pub action: RuleAction,
+    pub pattern: PathPattern,
+}
+
+#[derive(Clone, Debug)]
+pub struct RuleSet {
+    rules: Vec<PathRule>,
+    default_action: RuleAction,
+}
+
+impl RuleSet {
+    pub fn compile(config: &FilterConfig) -> std::io::Result<Self> {
+        let mut rules = config.rules.iter().map(|spec| {
+            Ok(PathRule {
+                action: spec.action,
+                pattern: PathPattern::try_from(&spec.pattern)?,
+            })
+        }).collect::<std::io::Result<Vec<_>>>()?;
+
+        if config.profile == Profile::Default {
+            rules.extend(SYSTEM_PATHS.iter().map(|sp| {
+                PathRule {
+                    action: RuleAction::Exclude,
+                    pattern: PathPattern::Prefix(sp.to_string()),
+                }
+            }));
+        }
+
+        Ok(Self { rules, default_action: config.default_action })
+    }
+
+    pub fn evaluate(&self, path: &str) -> RuleAction {
+        self.rules.iter()
+            .find(|rule| rule.pattern.matches(path))
+            .map(|rule| rule.action)
+            .unwrap_or(self.default_action)
+    }
+}
+
+/// Shared, reloadable handle to the active `RuleSet`.
+#[derive(Clone, Debug)]
+pub struct PathFilter {
+    rules: Arc<RwLock<RuleSet>>,
+    source: Option<PathBuf>,
+}
+
+impl PathFilter {
+    pub fn new(config: &FilterConfig) -> std::io::Result<Self> {
+        let rules = RuleSet::compile(config)?;
+        Ok(Self { rules: Arc::new(RwLock::new(rules)), source: None })
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let config = FilterConfig::load(path.as_ref())?;
+        let mut filter = Self::new(&config)?;
+        filter.source = Some(path.as_ref().to_path_buf());
+        Ok(filter)
+    }
+
+    pub fn source(&self) -> Option<&Path> {
+        self.source.as_deref()
+    }
+
+    /// Re-reads the config file this filter was loaded from. On error the
+    /// previously active rules are kept.
+    pub fn reload(&self) -> std::io::Result<()> {
+        let Some(source) = &self.source else {
+            return Ok(())
+        };
+        let rules = RuleSet::compile(&FilterConfig::load(source)?)?;
+        let mut guard = self.rules.write().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("unable to acquire lock on path filter: {e}")
+            )
+        })?;
+        *guard = rules;
+        log::info!("reloaded path filter rules from {}", source.display());
+        Ok(())
+    }
+
+    pub fn allows(&self, path: &str) -> bool {
+        match self.rules.read() {
+            Ok(guard) => guard.evaluate(path) == RuleAction::Include,
+            Err(e) => {
+                log::error!("unable to acquire lock on path filter: {e}");
+                true
+            }
+        }
+    }
+}
+
+impl Default for PathFilter {
+    fn default() -> Self {
+        Self::new(&FilterConfig::default()).expect("default filter profile is valid")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(action: RuleAction, pattern: PatternSpec) -> RuleSpec {
+        RuleSpec { action, pattern }
+    }
+
+    #[test]
+    fn patterns_match_by_kind() {
+        let prefix = PathPattern::try_from(&PatternSpec::Prefix("/var/lib/".to_string())).unwrap();
+        assert!(prefix.matches("/var/lib/app/data"));
+        assert!(!prefix.matches("/var/library"));
+
+        let glob = PathPattern::try_from(&PatternSpec::Glob("/var/lib/*/cache/**".to_string())).unwrap();
+        assert!(glob.matches("/var/lib/app/cache/a/b"));
+        assert!(!glob.matches("/var/lib/app/data/cache"));
+
+        let regex = PathPattern::try_from(&PatternSpec::Regex(r"\.sw[op]$".to_string())).unwrap();
+        assert!(regex.matches("/home/user/.notes.swp"));
+        assert!(!regex.matches("/home/user/notes.swp.bak"));
+    }
+
+    #[test]
+    fn invalid_patterns_are_rejected() {
+        for spec in [PatternSpec::Glob("/var/[".to_string()), PatternSpec::Regex("(".to_string())] {
+            let e = PathPattern::try_from(&spec).unwrap_err();
+            assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn user_rules_come_before_the_profile() {
+        let config = FilterConfig {
+            profile: Profile::Default,
+            default_action: RuleAction::Include,
+            rules: vec![
+                rule(RuleAction::Exclude, PatternSpec::Glob("/etc/ssl/private/**".to_string())),
+                rule(RuleAction::Include, PatternSpec::Prefix("/etc/".to_string())),
+            ],
+        };
+        let rules = RuleSet::compile(&config).unwrap();
+        assert_eq!(rules.evaluate("/etc/ssl/private/key.pem"), RuleAction::Exclude);
+        assert_eq!(rules.evaluate("/etc/hosts"), RuleAction::Include);
+        assert_eq!(rules.evaluate("/var/log/syslog"), RuleAction::Exclude);
+        assert_eq!(rules.evaluate("/home/user/notes"), RuleAction::Include);
+    }
+
+    #[test]
+    fn the_empty_profile_only_applies_user_rules() {
+        let config = FilterConfig {
+            profile: Profile::Empty,
+            default_action: RuleAction::Exclude,
+            rules: vec![rule(RuleAction::Include, PatternSpec::Regex("^/srv/".to_string()))],
+        };
+        let rules = RuleSet::compile(&config).unwrap();
+        assert_eq!(rules.evaluate("/srv/www/index.html"), RuleAction::Include);
+        assert_eq!(rules.evaluate("/home/user/notes"), RuleAction::Exclude);
+        assert_eq!(rules.evaluate("/var/log/syslog"), RuleAction::Exclude);
+    }
+
+    #[test]
+    fn reload_swaps_rules_for_every_clone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("filter.json");
+        std::fs::write(&path, br#"{"profile": "empty", "rules": [{"action": "exclude", "glob": "*.log"}]}"#).unwrap();
+        let filter = PathFilter::from_file(&path).unwrap();
+        let shared = filter.clone();
+        assert!(!shared.allows("/srv/app.log"));
+        assert!(shared.allows("/srv/app.db"));
+
+        std::fs::write(&path, br#"{"profile": "empty", "rules": [{"action": "exclude", "prefix": "/srv/"}]}"#).unwrap();
+        filter.reload().unwrap();
+        assert!(!shared.allows("/srv/app.db"));
+        assert!(shared.allows("/home/app.log"));
+
+        // A broken config keeps the rules in use.
+        std::fs::write(&path, br#"{"rules": [{"action": "exclude", "regex": "("}]}"#).unwrap();
+        assert!(filter.reload().is_err());
+        assert!(!shared.allows("/srv/app.db"));
+        assert!(shared.allows("/home/app.log"));
+    }
+
+    #[test]
+    fn filters_without_a_file_ignore_reloads() {
+        let filter = PathFilter::default();
+        assert!(filter.source().is_none());
+        filter.reload().unwrap();
+        assert!(!filter.allows("/proc/1/status"));
+        assert!(filter.allows("/home/user/notes"));
+    }
+}
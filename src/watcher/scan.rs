@@ -0,0 +1,417 @@
+use notify::event::{CreateKind, DataChange, EventKind, ModifyKind, RemoveKind};
+use notify::{Event, RecursiveMode};
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+
+use crate::statics::STORAGE_PATH;
+use super::roots::{WatchRoot, WatchRoots};
+
+/// `Event::info` attached to events produced by a scan rather than by a
+/// notify backend.
+pub const BASELINE_INFO: &str = "libretto:baseline";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BaselineConfig {
+    #[serde(default = "BaselineConfig::default_enabled")]
+    pub enabled: bool,
+    /// Also compare a content hash, catching changes that preserve size and
+    /// mtime. Every file is read on startup when enabled.
+    #[serde(default)]
+    pub hash: bool,
+    /// Where manifests are kept. Defaults to `$STORAGE_PATH/manifests`.
+    #[serde(default)]
+    pub manifest_dir: Option<PathBuf>,
+}
+
+impl BaselineConfig {
+    fn default_enabled() -> bool {
+        true
+    }
+
+    pub fn manifest_dir(&self) -> PathBuf {
+        self.manifest_dir.clone().unwrap_or_else(|| {
+            Path::new(STORAGE_PATH.as_str()).join("manifests")
+        })
+    }
+
+    pub fn manifest_path(&self, root: &Path) -> PathBuf {
+        let name = root.to_string_lossy().trim_matches('/').replace('/', "_");
+        self.manifest_dir().join(format!("{name}.json"))
+    }
+}
+
+impl Default for BaselineConfig {
+    fn default() -> Self {
+        Self { enabled: true, hash: false, manifest_dir: None }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub size: u64,
+    pub mtime_ns: u64,
+    pub is_dir: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+impl ManifestEntry {
+    pub fn stat(path: &Path, hash: bool) -> std::io::Result<Self> {
+        let metadata = std::fs::symlink_metadata(path)?;
+        let mtime_ns = metadata.modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        let hash = if hash && metadata.is_file() {
+            Some(hash_file(path)?)
+        } else {
+            None
+        };
+
+        Ok(Self { size: metadata.len(), mtime_ns, is_dir: metadata.is_dir(), hash })
+    }
+
+    fn changed(&self, previous: &ManifestEntry) -> bool {
+        if self.is_dir != previous.is_dir {
+            return true
+        }
+        if self.is_dir {
+            return false
+        }
+        match (&self.hash, &previous.hash) {
+            (Some(current), Some(previous_hash)) => current != previous_hash,
+            _ => self.size != previous.size || self.mtime_ns != previous.mtime_ns,
+        }
+    }
+}
+
+fn hash_file(path: &Path) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = [0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// State of a watch root as of the last time it was recorded.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    pub root: PathBuf,
+    pub entries: BTreeMap<PathBuf, ManifestEntry>,
+}
+
+impl Manifest {
+    pub fn load(path: &Path) -> std::io::Result<Option<Self>> {
+        let contents = match std::fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        serde_json::from_slice(&contents).map(Some).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unable to parse manifest {}: {e}", path.display())
+            )
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let contents = serde_json::to_vec(self).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::Other,
+                e
+            )
+        })?;
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, contents)?;
+        std::fs::rename(tmp, path)
+    }
+
+    /// Walks `root` without following symlinks and records every entry below
+    /// it. Entries that vanish mid-walk are skipped.
+    pub fn scan(root: &Path, recursive: RecursiveMode, hash: bool) -> std::io::Result<Self> {
+        let mut manifest = Manifest { root: root.to_path_buf(), entries: BTreeMap::new() };
+        manifest.scan_subtree(root, recursive, hash)?;
+        Ok(manifest)
+    }
+
+    /// Re-walks `subtree` and replaces every entry below it.
+    pub fn scan_subtree(&mut self, subtree: &Path, recursive: RecursiveMode, hash: bool) -> std::io::Result<()> {
+        self.entries.retain(|path, _| !path.starts_with(subtree) || path == subtree);
+
+        let mut pending = vec![subtree.to_path_buf()];
+        while let Some(dir) = pending.pop() {
+            let entries = match std::fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    log::warn!("unable to scan {}: {e}", dir.display());
+                    continue;
+                }
+            };
+            for entry in entries.flatten() {
+                let path = entry.path();
+                let Ok(stat) = ManifestEntry::stat(&path, hash) else {
+                    continue;
+                };
+                if stat.is_dir && recursive == RecursiveMode::Recursive {
+                    pending.push(path.clone());
+                }
+                self.entries.insert(path, stat);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Synthetic events describing how to get from `self` to `current`,
+    /// limited to paths below `subtree`.
+    pub fn diff(&self, current: &Manifest, subtree: &Path) -> Vec<Event> {
+        let mut events = Vec::new();
+        for (path, entry) in current.entries.range(subtree.to_path_buf()..) {
+            if !path.starts_with(subtree) {
+                break;
+            }
+            let kind = match self.entries.get(path) {
+                None if entry.is_dir => EventKind::Create(CreateKind::Folder),
+                None => EventKind::Create(CreateKind::File),
+                Some(previous) if entry.changed(previous) => {
+                    EventKind::Modify(ModifyKind::Data(DataChange::Any))
+                }
+                Some(_) => continue,
+            };
+            events.push(baseline_event(kind, path));
+        }
+
+        for (path, entry) in self.entries.range(subtree.to_path_buf()..) {
+            if !path.starts_with(subtree) {
+                break;
+            }
+            if current.entries.contains_key(path) {
+                continue;
+            }
+            let kind = if entry.is_dir {
+                EventKind::Remove(RemoveKind::Folder)
+            } else {
+                EventKind::Remove(RemoveKind::File)
+            };
+            events.push(baseline_event(kind, path));
+        }
+
+        events
+    }
+
+    /// Brings the entry for `path` up to date with the filesystem.
+    pub fn record(&mut self, path: &Path, hash: bool) {
+        match ManifestEntry::stat(path, hash) {
+            Ok(entry) => {
+                self.entries.insert(path.to_path_buf(), entry);
+            }
+            Err(_) => {
+                self.entries.remove(path);
+                let children: Vec<PathBuf> = self.entries
+                    .range(path.to_path_buf()..)
+                    .take_while(|(p, _)| p.starts_with(path))
+                    .map(|(p, _)| p.clone())
+                    .collect();
+                for child in children {
+                    self.entries.remove(&child);
+                }
+            }
+        }
+    }
+}
+
+/// Synthetic creates for everything below `subtree`, for roots that keep no
+/// manifest to diff against. Whatever was removed cannot be found this way.
+pub fn walk_events(subtree: &Path, recursive: RecursiveMode) -> std::io::Result<Vec<Event>> {
+    let current = Manifest::scan(subtree, recursive, false)?;
+    Ok(Manifest::default().diff(&current, subtree))
+}
+
+fn baseline_event(kind: EventKind, path: &Path) -> Event {
+    Event::new(kind)
+        .add_path(path.to_path_buf())
+        .set_info(BASELINE_INFO)
+}
+
+/// Synthetic events found by a baseline scan, grouped by the root they
+/// belong to.
+pub type RootChanges = Vec<(WatchRoot, Vec<Event>)>;
+
+struct RootManifest {
+    root: WatchRoot,
+    file: PathBuf,
+    manifest: Manifest,
+}
+
+/// Persisted manifests for every watch root, kept current as events are
+/// published so the next startup only reports what changed while down.
+#[derive(Clone)]
+pub struct Baselines {
+    config: BaselineConfig,
+    manifests: Arc<Mutex<Vec<RootManifest>>>,
+}
+
+impl Baselines {
+    /// Scans every root, compares it against the manifest from the previous
+    /// run and returns the synthetic events for the differences per root.
+    pub fn scan(roots: &WatchRoots, config: BaselineConfig) -> std::io::Result<(Self, RootChanges)> {
+        let mut manifests = Vec::new();
+        let mut changes = Vec::new();
+        for root in roots.iter() {
+            let file = config.manifest_path(root.path());
+            let current = Manifest::scan(root.path(), root.recursive(), config.hash)?;
+            // The previous manifest stays in use until the changes found
+            // have been published and `record`ed, so changes that never make
+            // it out are found again on the next start.
+            let (manifest, events) = match Manifest::load(&file) {
+                Ok(Some(previous)) => {
+                    let events = previous.diff(&current, root.path());
+                    (previous, events)
+                }
+                Ok(None) => {
+                    log::info!("no manifest for {}, recording a fresh baseline", root.path().display());
+                    current.save(&file)?;
+                    (current, Vec::new())
+                }
+                Err(e) => {
+                    log::error!("{e}, recording a fresh baseline");
+                    current.save(&file)?;
+                    (current, Vec::new())
+                }
+            };
+            log::info!("baseline scan of {} found {} changes", root.path().display(), events.len());
+            changes.push((root.clone(), events));
+            manifests.push(RootManifest { root: root.clone(), file, manifest });
+        }
+
+        Ok((Self { config, manifests: Arc::new(Mutex::new(manifests)) }, changes))
+    }
+
+    pub fn record(&self, event: &Event) {
+        let Ok(mut guard) = self.manifests.lock() else {
+            return
+        };
+        for path in &event.paths {
+            // Roots are ordered longest first, matching `WatchRoots::route`.
+            if let Some(entry) = guard.iter_mut().find(|m| m.root.contains(path)) {
+                entry.manifest.record(path, self.config.hash);
+            }
+        }
+    }
+
+    /// Re-walks `subtree` in every root it overlaps, returning synthetic
+    /// events for whatever changed since the manifest was last updated.
+    pub fn rescan(&self, subtree: &Path) -> std::io::Result<Vec<Event>> {
+        let guard = self.manifests.lock().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("unable to acquire lock on manifests: {e}")
+            )
+        })?;
+
+        let mut events = Vec::new();
+        for entry in guard.iter() {
+            let root = entry.root.path();
+            let scan_path = if subtree.starts_with(root) {
+                subtree.to_path_buf()
+            } else if root.starts_with(subtree) {
+                root.to_path_buf()
+            } else {
+                continue;
+            };
+
+            // As with startup scans, the manifest only advances as the
+            // events are published.
+            let current = Manifest::scan(&scan_path, entry.root.recursive(), self.config.hash)?;
+            events.extend(entry.manifest.diff(&current, &scan_path));
+        }
+
+        Ok(events)
+    }
+
+    pub fn flush(&self) -> std::io::Result<()> {
+        let guard = self.manifests.lock().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("unable to acquire lock on manifests: {e}")
+            )
+        })?;
+        for entry in guard.iter() {
+            entry.manifest.save(&entry.file)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::roots::RootConfig;
+
+    fn setup() -> (tempfile::TempDir, WatchRoots, BaselineConfig) {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("root")).unwrap();
+        let roots = WatchRoots::new(vec![WatchRoot::from_config(&RootConfig::new(dir.path().join("root"))).unwrap()]);
+        let config = BaselineConfig { enabled: true, hash: false, manifest_dir: Some(dir.path().join("manifests")) };
+        (dir, roots, config)
+    }
+
+    fn changed_paths(changes: &RootChanges) -> Vec<PathBuf> {
+        changes.iter().flat_map(|(_, events)| events.iter().flat_map(|e| e.paths.clone())).collect()
+    }
+
+    #[test]
+    fn unpublished_changes_are_found_again_on_the_next_scan() {
+        let (dir, roots, config) = setup();
+        let (_, changes) = Baselines::scan(&roots, config.clone()).unwrap();
+        assert!(changed_paths(&changes).is_empty());
+
+        let file = dir.path().join("root/new");
+        std::fs::write(&file, b"data").unwrap();
+        let (_, changes) = Baselines::scan(&roots, config.clone()).unwrap();
+        assert_eq!(changed_paths(&changes), std::slice::from_ref(&file));
+
+        // Nothing was published, so a restart reports the change again.
+        let (baselines, changes) = Baselines::scan(&roots, config.clone()).unwrap();
+        assert_eq!(changed_paths(&changes), std::slice::from_ref(&file));
+
+        for (_, events) in &changes {
+            events.iter().for_each(|event| baselines.record(event));
+        }
+        baselines.flush().unwrap();
+        let (_, changes) = Baselines::scan(&roots, config).unwrap();
+        assert!(changed_paths(&changes).is_empty());
+    }
+
+    #[test]
+    fn rescans_do_not_advance_the_manifest() {
+        let (dir, roots, config) = setup();
+        let (baselines, _) = Baselines::scan(&roots, config).unwrap();
+
+        let file = dir.path().join("root/new");
+        std::fs::write(&file, b"data").unwrap();
+        let root = dir.path().join("root");
+        assert_eq!(baselines.rescan(&root).unwrap().len(), 1);
+        assert_eq!(baselines.rescan(&root).unwrap().len(), 1);
+
+        baselines.record(&Event::new(EventKind::Any).add_path(file));
+        assert!(baselines.rescan(&root).unwrap().is_empty());
+    }
+}